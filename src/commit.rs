@@ -0,0 +1,327 @@
+use crate::{object_id::ObjectId, reader::Reader};
+
+/// Whose identity: who wrote a change, or who recorded it (in a commit or a reflog).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Bytes as written, objects do not have to be UTF-8 (see the `encoding` header).
+    pub name: Vec<u8>,
+    pub email: Vec<u8>,
+    pub timestamp: i64,
+    pub timezone: String,
+    /// Git separates the name from `<email>` with a space, some old tools did not.
+    pub space_before_email: bool,
+}
+
+impl Identity {
+    // <name> <<email>> <timestamp> <timezone>
+    pub fn parse(raw: &[u8]) -> Result<Self, String> {
+        let invalid = || format!("invalid identity: {}", String::from_utf8_lossy(raw));
+
+        let (rest, timezone) = split_last_space(raw).ok_or_else(invalid)?;
+        let (rest, timestamp) = split_last_space(rest).ok_or_else(invalid)?;
+        let email_start = rest.iter().position(|&c| c == b'<').ok_or_else(invalid)?;
+        let name = &rest[..email_start];
+        let email = rest[email_start + 1..]
+            .strip_suffix(b">")
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            name: name.strip_suffix(b" ").unwrap_or(name).to_vec(),
+            email: email.to_vec(),
+            timestamp: str::from_utf8(timestamp)
+                .ok()
+                .and_then(|timestamp| timestamp.parse().ok())
+                .ok_or_else(invalid)?,
+            timezone: String::from_utf8(timezone.to_vec()).map_err(|_| invalid())?,
+            space_before_email: name.ends_with(b" "),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.name.clone();
+        if self.space_before_email {
+            bytes.push(b' ');
+        }
+        bytes.push(b'<');
+        bytes.extend_from_slice(&self.email);
+        bytes.extend_from_slice(format!("> {} {}", self.timestamp, self.timezone).as_bytes());
+        bytes
+    }
+}
+
+fn split_last_space(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let space = bytes.iter().rposition(|&c| c == b' ')?;
+    Some((&bytes[..space], &bytes[space + 1..]))
+}
+
+/// Headers of a commit or tag that have no field of their own, in order of appearance: None for
+/// a key alone on its line, continuation lines joined with `\n`.
+pub type ExtraHeaders = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    pub author: Identity,
+    pub committer: Identity,
+    /// Headers other than tree/parent/author/committer (gpgsig, encoding, mergetag, ...).
+    pub extra_headers: ExtraHeaders,
+    pub message: Vec<u8>,
+}
+
+impl Commit {
+    pub fn parse(content: &[u8]) -> Result<Self, String> {
+        // tree <tree_sha>
+        // parent <parent_sha>
+        // author <name> <<email>> <timestamp> <timezone>
+        // committer <name> <<email>> <timestamp> <timezone>
+        // <extra headers>
+        //
+        // <commit message>
+        let mut reader = Reader::new(content);
+
+        let mut tree = None;
+        let mut parents = vec![];
+        let mut author = None;
+        let mut committer = None;
+
+        let extra_headers = parse_headers(&mut reader, |key, value, line| {
+            let parse_id = || {
+                str::from_utf8(value.unwrap_or_default())
+                    .ok()
+                    .and_then(ObjectId::from_hex)
+                    .ok_or_else(|| format!("invalid {}", String::from_utf8_lossy(line)))
+            };
+            match key {
+                b"tree" => tree = Some(parse_id()?),
+                b"parent" => parents.push(parse_id()?),
+                b"author" => author = Some(Identity::parse(value.unwrap_or_default())?),
+                b"committer" => committer = Some(Identity::parse(value.unwrap_or_default())?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+
+        Ok(Self {
+            tree: tree.ok_or_else(|| "commit is missing a tree".to_string())?,
+            parents,
+            author: author.ok_or_else(|| "commit is missing an author".to_string())?,
+            committer: committer.ok_or_else(|| "commit is missing a committer".to_string())?,
+            extra_headers,
+            message: reader.pop_all().to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        bytes.extend_from_slice(b"tree ");
+        bytes.extend_from_slice(self.tree.to_string().as_bytes());
+        bytes.push(b'\n');
+
+        for parent in &self.parents {
+            bytes.extend_from_slice(b"parent ");
+            bytes.extend_from_slice(parent.to_string().as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes.extend_from_slice(b"author ");
+        bytes.extend_from_slice(&self.author.to_bytes());
+        bytes.push(b'\n');
+
+        bytes.extend_from_slice(b"committer ");
+        bytes.extend_from_slice(&self.committer.to_bytes());
+        bytes.push(b'\n');
+
+        write_extra_headers(&mut bytes, &self.extra_headers);
+
+        bytes.push(b'\n');
+        bytes.extend_from_slice(&self.message);
+
+        bytes
+    }
+}
+
+/// Reads the header lines of a commit or tag, up to the blank line before the message. `known`
+/// gets each line split into key and value, and returns false for a key it does not handle.
+/// Those are returned as extra headers.
+pub(crate) fn parse_headers(
+    reader: &mut Reader<u8>,
+    mut known: impl FnMut(&[u8], Option<&[u8]>, &[u8]) -> Result<bool, String>,
+) -> Result<ExtraHeaders, String> {
+    let mut extra_headers: ExtraHeaders = vec![];
+
+    while !reader.is_empty() {
+        let line = reader.pop_while(|c| c != &b'\n');
+        reader.try_pop(); // \n
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let (_, value) = extra_headers
+                .last_mut()
+                .ok_or_else(|| "continuation line without a header".to_string())?;
+            let value = value.get_or_insert_default();
+            value.push(b'\n');
+            value.extend_from_slice(continuation);
+            continue;
+        }
+
+        let (key, value) = match line.iter().position(|&c| c == b' ') {
+            Some(space) => (&line[..space], Some(&line[space + 1..])),
+            None => (line, None),
+        };
+        if !known(key, value, line)? {
+            extra_headers.push((key.to_vec(), value.map(<[u8]>::to_vec)));
+        }
+    }
+
+    Ok(extra_headers)
+}
+
+/// Writes extra headers back as `parse_headers` read them, continuation lines indented by a
+/// space.
+pub(crate) fn write_extra_headers(
+    bytes: &mut Vec<u8>,
+    extra_headers: &[(Vec<u8>, Option<Vec<u8>>)],
+) {
+    for (key, value) in extra_headers {
+        bytes.extend_from_slice(key);
+        if let Some(value) = value {
+            bytes.push(b' ');
+            for (i, line) in value.split(|&c| c == b'\n').enumerate() {
+                if i > 0 {
+                    bytes.extend_from_slice(b"\n ");
+                }
+                bytes.extend_from_slice(line);
+            }
+        }
+        bytes.push(b'\n');
+    }
+}
+
+/// Cleans up a commit or tag message the way git does: trailing whitespace, leading and
+/// trailing blank lines are removed, runs of blank lines collapsed into one, and every line ends
+/// with a newline. `strip_comments` also drops the lines starting with `#`, as for tag messages.
+/// An empty result means an empty message.
+pub fn cleanup_message(message: &str, strip_comments: bool) -> String {
+    let mut cleaned = String::new();
+    let mut pending_blank = false;
+    for line in message.lines().map(str::trim_end) {
+        if strip_comments && line.starts_with('#') {
+            continue;
+        }
+        if line.is_empty() {
+            pending_blank = !cleaned.is_empty();
+            continue;
+        }
+        if pending_blank {
+            cleaned.push('\n');
+            pending_blank = false;
+        }
+        cleaned.push_str(line);
+        cleaned.push('\n');
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commit::{Commit, Identity, cleanup_message};
+
+    #[test]
+    fn test_parse_identity() {
+        let identity = Identity::parse(b"John Doe <john@example.com> 1234567890 -0130").unwrap();
+        assert_eq!(b"John Doe".to_vec(), identity.name);
+        assert_eq!(b"john@example.com".to_vec(), identity.email);
+        assert_eq!(1234567890, identity.timestamp);
+        assert_eq!("-0130", identity.timezone);
+    }
+
+    #[test]
+    fn test_commit_round_trip() {
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+parent 0314a3aa6df0d0ab9b3e3a2ae1a1e3e3e3e3e3e3\n\
+parent 1111111111111111111111111111111111111111\n\
+author John Doe <john@example.com> 1234567890 +0000\n\
+committer Jane Doe <jane@example.com> 1234567891 +0100\n\
+encoding ISO-8859-1\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n abcdef\n -----END PGP SIGNATURE-----\n\
+\n\
+Merge things\n\nDetails.\n";
+
+        let commit = Commit::parse(raw).unwrap();
+        assert_eq!(
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+            commit.tree.to_string()
+        );
+        assert_eq!(2, commit.parents.len());
+        assert_eq!(b"Jane Doe".to_vec(), commit.committer.name);
+        assert_eq!(2, commit.extra_headers.len());
+        assert_eq!(
+            Some(b"-----BEGIN PGP SIGNATURE-----\n\nabcdef\n-----END PGP SIGNATURE-----".to_vec()),
+            commit.extra_headers[1].1
+        );
+        assert_eq!(b"Merge things\n\nDetails.\n".to_vec(), commit.message);
+        assert_eq!(raw.to_vec(), commit.to_bytes());
+    }
+
+    #[test]
+    fn test_commit_round_trip_unusual_headers() {
+        // A Latin-1 author, an identity without a space before the email, a header without a
+        // value.
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author Jos\xe9 <jose@example.com> 1234567890 +0000\n\
+committer Jane<jane@example.com> 1234567891 +0100\n\
+encoding ISO-8859-1\n\
+flag\n\
+\n\
+Caf\xe9\n";
+
+        let commit = Commit::parse(raw).unwrap();
+        assert_eq!(b"Jos\xe9".to_vec(), commit.author.name);
+        assert!(commit.author.space_before_email);
+        assert_eq!(b"Jane".to_vec(), commit.committer.name);
+        assert!(!commit.committer.space_before_email);
+        assert_eq!(
+            vec![
+                (b"encoding".to_vec(), Some(b"ISO-8859-1".to_vec())),
+                (b"flag".to_vec(), None)
+            ],
+            commit.extra_headers
+        );
+        assert_eq!(raw.to_vec(), commit.to_bytes());
+    }
+
+    #[test]
+    fn test_cleanup_message() {
+        assert_eq!("subject\n", cleanup_message("subject", false));
+        assert_eq!(
+            "subject\n\nbody  line\n",
+            cleanup_message("\n\n  \nsubject  \n\n\n\t\nbody  line\t\n\n", false)
+        );
+        assert_eq!("", cleanup_message(" \n\t\n", false));
+        assert_eq!(
+            "#1\nsubject\n\n  # kept\n",
+            cleanup_message("#1\nsubject\n\n  # kept\n", false)
+        );
+        assert_eq!(
+            "subject\n\n  # kept\n",
+            cleanup_message("#1\nsubject\n#2\n\n  # kept\n", true)
+        );
+    }
+
+    #[test]
+    fn test_commit_missing_tree() {
+        let raw = b"author John Doe <john@example.com> 1234567890 +0000\n\nmessage\n";
+        assert!(Commit::parse(raw).is_err());
+    }
+}
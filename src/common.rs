@@ -1,8 +1,16 @@
-use crate::{pack::PackObjectType, reader::Reader};
-use flate2::read::ZlibDecoder;
+use crate::{
+    commit::Commit,
+    error::{IoResultExt, Result},
+    object_id::{ObjectFormat, ObjectId},
+    pack::PackObjectType,
+    reader::Reader,
+    tag::Tag,
+};
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
+    fmt,
+    fs::{self, Metadata},
+    os::unix::fs::PermissionsExt,
+    path::Path,
 };
 
 pub(crate) fn bytes_to_string(bytes: &[u8]) -> String {
@@ -26,170 +34,290 @@ pub(crate) fn hex_len_prefixed_string(s: &str) -> String {
     format!("{:04x}{}", s.len() + 4, s)
 }
 
-#[derive(Debug)]
-pub(crate) struct Hash {
-    pub(crate) hash: String,
+pub(crate) fn create_object_blob_payload_from_file(
+    file_path: &Path,
+    kind: PackObjectType,
+) -> Result<Vec<u8>> {
+    let content = fs::read(file_path).with_path(file_path)?;
+    Ok(create_object_payload_from_content(&content[..], kind))
 }
 
-impl Hash {
-    pub(crate) fn new(hash: String) -> Self {
-        Self { hash }
-    }
+pub(crate) fn create_object_payload_from_content(content: &[u8], kind: PackObjectType) -> Vec<u8> {
+    let mut payload = format!("{} {}", kind.to_string(), content.len())
+        .as_bytes()
+        .to_vec();
+    payload.push(0);
+    payload.extend_from_slice(content);
+    payload
+}
 
-    #[allow(unused)]
-    pub(crate) fn from_bytes(bytes: [u8; 20]) -> Self {
-        Self::new(bytes_to_string(&bytes))
+/// Mode of a tree or index entry, which also tells the kind of object the entry points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    Tree,
+    Regular,
+    Executable,
+    Symlink,
+    /// A submodule: the entry is the id of a commit in another repository.
+    Gitlink,
+}
+
+impl FileMode {
+    pub fn from_octal(mode: u32) -> Option<Self> {
+        match mode {
+            0o040000 => Some(FileMode::Tree),
+            // Old versions of git recorded group writable files, they are read as regular ones.
+            0o100644 | 0o100664 => Some(FileMode::Regular),
+            0o100755 => Some(FileMode::Executable),
+            0o120000 => Some(FileMode::Symlink),
+            0o160000 => Some(FileMode::Gitlink),
+            _ => None,
+        }
     }
 
-    pub(crate) fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    pub fn parse(mode: &str) -> Option<Self> {
+        u32::from_str_radix(mode, 8).ok().and_then(Self::from_octal)
+    }
 
-        let mut i = 0;
-        while i < self.hash.len() {
-            bytes.push(u8::from_str_radix(&self.hash[i..i + 2], 16).unwrap());
-            i += 2;
+    pub fn as_octal(self) -> u32 {
+        match self {
+            FileMode::Tree => 0o040000,
+            FileMode::Regular => 0o100644,
+            FileMode::Executable => 0o100755,
+            FileMode::Symlink => 0o120000,
+            FileMode::Gitlink => 0o160000,
         }
-
-        bytes
     }
 
-    fn folder_path(&self) -> String {
-        let prefix = &self.hash[0..2];
-        format!(".git/objects/{}", prefix)
+    /// The mode as written in tree objects, where trees have no leading zero.
+    pub fn as_tree_str(self) -> &'static str {
+        match self {
+            FileMode::Tree => "40000",
+            FileMode::Regular => "100644",
+            FileMode::Executable => "100755",
+            FileMode::Symlink => "120000",
+            FileMode::Gitlink => "160000",
+        }
     }
 
-    fn ensure_folder_exist(&self) {
-        std::fs::create_dir_all(&self.folder_path()).unwrap();
+    pub fn kind(self) -> PackObjectType {
+        match self {
+            FileMode::Tree => PackObjectType::Tree,
+            FileMode::Regular | FileMode::Executable | FileMode::Symlink => PackObjectType::Blob,
+            FileMode::Gitlink => PackObjectType::Commit,
+        }
     }
 
-    pub(crate) fn file_path(&self) -> String {
-        let prefix = &self.hash[0..2];
-        let filename = &self.hash[2..];
-        format!(".git/objects/{}/{}", prefix, filename)
+    /// Detects the mode of a worktree file from its `symlink_metadata`. Directories are trees,
+    /// telling submodules apart is up to the caller.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        if metadata.is_dir() {
+            FileMode::Tree
+        } else if metadata.is_symlink() {
+            FileMode::Symlink
+        } else if metadata.permissions().mode() & 0o100 != 0 {
+            FileMode::Executable
+        } else {
+            FileMode::Regular
+        }
     }
+}
 
-    pub(crate) fn write_content(&self, content: &[u8]) {
-        self.ensure_folder_exist();
-        let mut file = File::create(self.file_path()).unwrap();
-        file.write_all(content).unwrap();
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06o}", self.as_octal())
     }
+}
 
-    pub(crate) fn read(&self) -> Entry {
-        let file = File::open(self.file_path())
-            .expect(&format!("Failed opening file: {}", self.file_path()));
-        let mut decoder = ZlibDecoder::new(file);
-        let mut content_buf = vec![];
-        decoder.read_to_end(&mut content_buf).unwrap();
+#[derive(Debug)]
+pub struct TreeEntry {
+    pub mode: FileMode,
+    /// Any bytes but `/` and NUL, git does not require names to be UTF-8.
+    pub filename: Vec<u8>,
+    pub hash: ObjectId,
+}
 
-        let mut reader = Reader::new(&content_buf[..]);
-        let kind = str::from_utf8(reader.pop_while(|c| c != &b' ')).unwrap();
+pub enum Object {
+    Tree { entries: Vec<TreeEntry> },
+    Blob { content: Vec<u8> },
+    Commit { commit: Box<Commit> },
+    Tag { tag: Box<Tag> },
+}
 
-        reader.pop(); // space
-        let _payload_len =
-            usize::from_str_radix(str::from_utf8(reader.pop_while(|c| c != &0)).unwrap(), 10)
-                .unwrap();
-        reader.pop(); // \0
+impl Object {
+    /// Parses the content of an object of a repository using `format`, errors describe why it
+    /// is corrupt.
+    pub fn parse(
+        kind: PackObjectType,
+        content: &[u8],
+        format: ObjectFormat,
+    ) -> std::result::Result<Self, String> {
+        let mut reader = Reader::new(content);
 
-        match kind {
-            "blob" => {
-                let content = str::from_utf8(reader.pop_all()).unwrap().to_string();
-                Entry::File { content }
-            }
-            "tree" => {
+        Ok(match kind {
+            PackObjectType::Blob => Object::Blob {
+                content: reader.pop_all().to_vec(),
+            },
+            PackObjectType::Tree => {
                 let mut entries = vec![];
 
                 while !reader.is_empty() {
                     // tree <size>\0
                     // <mode> <name>\0<20_byte_sha>
                     // <mode> <name>\0<20_byte_sha>
-                    let perm = str::from_utf8(reader.pop_while(|c| c != &b' '))
-                        .unwrap()
-                        .to_string();
-                    reader.pop(); // space
-                    let filename = str::from_utf8(reader.pop_while(|c| c != &0))
-                        .unwrap()
-                        .to_string();
+                    let mode = str::from_utf8(reader.pop_while(|c| c != &b' '))
+                        .ok()
+                        .and_then(FileMode::parse)
+                        .ok_or_else(|| "invalid tree entry mode".to_string())?;
+                    reader.try_pop(); // space
+                    let filename = reader.pop_while(|c| c != &0).to_vec();
+                    if reader.len() < 1 + format.id_len() {
+                        return Err(format!(
+                            "truncated tree entry: {}",
+                            String::from_utf8_lossy(&filename)
+                        ));
+                    }
                     reader.pop(); // \0
-                    let hash_bytes = reader.popn(20);
-                    let hash_str = bytes_to_string(hash_bytes);
-                    let hash = Hash::new(hash_str);
+                    let hash = ObjectId::from_slice(reader.popn(format.id_len())).unwrap();
 
                     entries.push(TreeEntry {
-                        perm,
+                        mode,
                         filename,
                         hash,
                     });
                 }
 
-                Entry::Tree { entries }
-            }
-            other => {
-                error!("Unrecognized entry indicator: {}", other);
-                panic!()
+                Object::Tree { entries }
             }
+            PackObjectType::Commit => Object::Commit {
+                commit: Box::new(Commit::parse(reader.pop_all())?),
+            },
+            PackObjectType::Tag => Object::Tag {
+                tag: Box::new(Tag::parse(reader.pop_all())?),
+            },
+        })
+    }
+
+    pub fn kind(&self) -> PackObjectType {
+        match self {
+            Object::Tree { .. } => PackObjectType::Tree,
+            Object::Blob { .. } => PackObjectType::Blob,
+            Object::Commit { .. } => PackObjectType::Commit,
+            Object::Tag { .. } => PackObjectType::Tag,
         }
     }
 }
 
-pub(crate) fn create_object_blob_payload_from_file(
-    file_path: &str,
-    kind: PackObjectType,
-) -> Vec<u8> {
-    let mut file = fs::File::open(file_path).unwrap();
-    let mut content = vec![];
-    file.read_to_end(&mut content).unwrap();
-    create_object_payload_from_content(&content[..], kind)
-}
+#[cfg(test)]
+mod test {
+    use crate::{
+        common::{FileMode, Object, bytes_to_string, hex_len_prefixed_string},
+        object_id::ObjectFormat,
+        pack::PackObjectType,
+        test_util::TempDir,
+    };
+    use std::{fs, os::unix::fs::PermissionsExt};
 
-pub(crate) fn create_object_payload_from_content(content: &[u8], kind: PackObjectType) -> Vec<u8> {
-    let mut payload = format!("{} {}", kind.to_string(), content.len())
-        .as_bytes()
-        .to_vec();
-    payload.push(0);
-    payload.extend_from_slice(content);
-    payload
-}
+    #[test]
+    fn test_bytes_to_string() {
+        assert_eq!("0314a3", bytes_to_string(&[0x03, 0x14, 0xa3]));
+    }
 
-#[derive(Debug)]
-pub(crate) struct TreeEntry {
-    pub(crate) perm: String,
-    pub(crate) filename: String,
-    pub(crate) hash: Hash,
-}
+    #[test]
+    fn test() {
+        assert_eq!("000a123456", hex_len_prefixed_string("123456"));
+    }
 
-impl TreeEntry {
-    pub(crate) fn perm_to_string(&self) -> &str {
-        match self.perm.as_str() {
-            "100644" => "blob",
-            "040000" => "tree",
-            other => {
-                error!("Perm type not implemented: {}", other);
-                panic!()
-            }
+    #[test]
+    fn test_parse_binary_blob() {
+        let content = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe];
+        match Object::parse(PackObjectType::Blob, &content, ObjectFormat::Sha1).unwrap() {
+            Object::Blob { content: parsed } => assert_eq!(content.to_vec(), parsed),
+            _ => panic!(),
         }
     }
 
-    pub(crate) fn read(&self) -> Entry {
-        self.hash.read()
+    #[test]
+    fn test_file_mode() {
+        assert_eq!(Some(FileMode::Tree), FileMode::parse("40000"));
+        assert_eq!(Some(FileMode::Tree), FileMode::parse("040000"));
+        assert_eq!(Some(FileMode::Regular), FileMode::parse("100664"));
+        assert_eq!(Some(FileMode::Gitlink), FileMode::parse("160000"));
+        assert_eq!(None, FileMode::parse("100600"));
+        assert_eq!(None, FileMode::parse("abc"));
+
+        assert_eq!("040000", FileMode::Tree.to_string());
+        assert_eq!("40000", FileMode::Tree.as_tree_str());
+        assert_eq!("120000", FileMode::Symlink.to_string());
     }
-}
 
-pub(crate) enum Entry {
-    Tree { entries: Vec<TreeEntry> },
-    File { content: String },
-}
+    #[test]
+    fn test_file_mode_from_metadata() {
+        let dir = TempDir::new("file_mode");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, b"").unwrap();
 
-#[cfg(test)]
-mod test {
-    use crate::common::{bytes_to_string, hex_len_prefixed_string};
+        // Like git, only the owner's executable bit counts.
+        for (permissions, expected) in [
+            (0o644, FileMode::Regular),
+            (0o655, FileMode::Regular),
+            (0o744, FileMode::Executable),
+            (0o700, FileMode::Executable),
+        ] {
+            fs::set_permissions(&path, fs::Permissions::from_mode(permissions)).unwrap();
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            assert_eq!(expected, FileMode::from_metadata(&metadata));
+        }
+    }
 
     #[test]
-    fn test_bytes_to_string() {
-        assert_eq!("0314a3", bytes_to_string(&[0x03, 0x14, 0xa3]));
+    fn test_parse_tree_modes() {
+        let mut content = vec![];
+        for (mode, name) in [("100755", "run.sh"), ("120000", "link"), ("40000", "src")] {
+            content.extend_from_slice(format!("{} {}\0", mode, name).as_bytes());
+            content.extend_from_slice(&[0xab; 20]);
+        }
+
+        match Object::parse(PackObjectType::Tree, &content, ObjectFormat::Sha1).unwrap() {
+            Object::Tree { entries } => {
+                assert_eq!(
+                    vec![FileMode::Executable, FileMode::Symlink, FileMode::Tree],
+                    entries.iter().map(|entry| entry.mode).collect::<Vec<_>>()
+                );
+            }
+            _ => panic!(),
+        }
     }
 
     #[test]
-    fn test() {
-        assert_eq!("000a123456", hex_len_prefixed_string("123456"));
+    fn test_parse_tree_non_utf8_name() {
+        let mut content = b"100644 caf\xe9\0".to_vec();
+        content.extend_from_slice(&[0xab; 20]);
+
+        match Object::parse(PackObjectType::Tree, &content, ObjectFormat::Sha1).unwrap() {
+            Object::Tree { entries } => assert_eq!(b"caf\xe9".to_vec(), entries[0].filename),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_sha256_tree() {
+        let mut content = vec![];
+        for name in ["a", "b"] {
+            content.extend_from_slice(format!("100644 {}\0", name).as_bytes());
+            content.extend_from_slice(&[0xcd; 32]);
+        }
+
+        match Object::parse(PackObjectType::Tree, &content, ObjectFormat::Sha256).unwrap() {
+            Object::Tree { entries } => {
+                assert_eq!(2, entries.len());
+                assert_eq!(b"b".to_vec(), entries[1].filename);
+                assert_eq!(ObjectFormat::Sha256, entries[1].hash.format());
+            }
+            _ => panic!(),
+        }
+        // Read with 20 byte ids, the second entry's name runs into the first one's id.
+        assert!(Object::parse(PackObjectType::Tree, &content, ObjectFormat::Sha1).is_err());
     }
 }
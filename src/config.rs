@@ -0,0 +1,238 @@
+use std::{fs, io, path::Path};
+
+use crate::error::{Error, IoResultExt, Result};
+
+/// One `key = value` line. Section and key names are case insensitive and kept lowercase,
+/// subsection names are case sensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ConfigEntry {
+    section: String,
+    subsection: Option<String>,
+    key: String,
+    value: String,
+}
+
+/// The repository configuration (`.git/config`).
+#[derive(Debug, Default)]
+pub struct Config {
+    entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    /// Reads the `config` file of the git directory `git_dir`, a missing file is an empty
+    /// configuration.
+    pub fn read_at(git_dir: &Path) -> Result<Self> {
+        let config_path = git_dir.join("config");
+        match fs::read_to_string(&config_path) {
+            Ok(content) => Self::parse(&content),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_path(config_path),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = vec![];
+        let mut section: Option<(String, Option<String>)> = None;
+
+        let mut lines = content.lines().enumerate();
+        while let Some((line_index, line)) = lines.next() {
+            let bad_line = || Error::BadConfig {
+                line: line_index + 1,
+            };
+            let line = line.trim_start();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let (header, _) = header.split_once(']').ok_or_else(bad_line)?;
+                section = Some(parse_section_header(header).ok_or_else(bad_line)?);
+                continue;
+            }
+
+            let (section_name, subsection) = section.clone().ok_or_else(bad_line)?;
+            let (key, raw_value) = match line.split_once('=') {
+                Some((key, raw_value)) => (key.trim(), Some(raw_value.to_string())),
+                // A key without a value is a true boolean.
+                None => (line.trim_end(), None),
+            };
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(bad_line());
+            }
+
+            let value = match raw_value {
+                Some(mut raw_value) => {
+                    // A trailing backslash continues the value on the next line.
+                    while raw_value.trim_end().ends_with('\\') && !raw_value.ends_with("\\\\") {
+                        raw_value.truncate(raw_value.trim_end().len() - 1);
+                        let (_, next_line) = lines.next().ok_or_else(bad_line)?;
+                        raw_value.push_str(next_line);
+                    }
+                    parse_value(&raw_value).ok_or_else(bad_line)?
+                }
+                None => "true".to_string(),
+            };
+
+            entries.push(ConfigEntry {
+                section: section_name,
+                subsection,
+                key: key.to_lowercase(),
+                value,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// The last value of a boolean key, None if unset or not a boolean.
+    pub fn get_bool(&self, section: &str, subsection: Option<&str>, key: &str) -> Option<bool> {
+        match self
+            .get(section, subsection, key)?
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "true" | "yes" | "on" | "1" => Some(true),
+            "false" | "no" | "off" | "0" | "" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// The last value of a key, like `git config --get`.
+    pub fn get(&self, section: &str, subsection: Option<&str>, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| {
+                entry.section.eq_ignore_ascii_case(section)
+                    && entry.subsection.as_deref() == subsection
+                    && entry.key.eq_ignore_ascii_case(key)
+            })
+            .map(|entry| &entry.value[..])
+    }
+}
+
+/// `section`, `section "subsection"` or the legacy `section.subsection`.
+fn parse_section_header(header: &str) -> Option<(String, Option<String>)> {
+    let header = header.trim();
+
+    if let Some((section, quoted)) = header.split_once(' ') {
+        let quoted = quoted.trim().strip_prefix('"')?.strip_suffix('"')?;
+        let mut subsection = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            subsection.push(if c == '\\' { chars.next()? } else { c });
+        }
+        return Some((section.to_lowercase(), Some(subsection)));
+    }
+
+    match header.split_once('.') {
+        Some((section, subsection)) => {
+            Some((section.to_lowercase(), Some(subsection.to_lowercase())))
+        }
+        None => Some((header.to_lowercase(), None)),
+    }
+}
+
+/// Unquotes a value, handling escapes and dropping comments and surrounding whitespace.
+fn parse_value(raw_value: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    // Whitespace is only kept when something follows it.
+    let mut pending_whitespace = String::new();
+
+    let mut chars = raw_value.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                value.push_str(&pending_whitespace);
+                pending_whitespace.clear();
+                in_quotes = !in_quotes;
+            }
+            '\\' => {
+                value.push_str(&pending_whitespace);
+                pending_whitespace.clear();
+                value.push(match chars.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    escaped @ ('"' | '\\') => escaped,
+                    _ => return None,
+                });
+            }
+            '#' | ';' if !in_quotes => break,
+            c if c.is_whitespace() && !in_quotes => pending_whitespace.push(c),
+            c => {
+                value.push_str(&pending_whitespace);
+                pending_whitespace.clear();
+                value.push(c);
+            }
+        }
+    }
+
+    if in_quotes { None } else { Some(value) }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "# comment\n\
+[core]\n\
+\trepositoryformatversion = 0\n\
+\tbare = false ; trailing comment\n\
+[remote \"origin\"]\n\
+\turl = https://example.com/repo.git\n\
+\tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+[Branch \"Main\"]\n\
+\tremote = origin\n\
+\tmerge = refs/heads/main\n\
+[user]\n\
+\tname = \"John  Doe\" # quoted\n\
+\tsigningKey\n\
+\tsigningKey = \"a\\\"b\\\\c\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("0"),
+            config.get("core", None, "repositoryFormatVersion")
+        );
+        assert_eq!(Some("false"), config.get("core", None, "bare"));
+        assert_eq!(Some(false), config.get_bool("core", None, "bare"));
+        assert_eq!(None, config.get_bool("user", None, "name"));
+        assert_eq!(
+            Some("https://example.com/repo.git"),
+            config.get("remote", Some("origin"), "url")
+        );
+        assert_eq!(Some("origin"), config.get("branch", Some("Main"), "remote"));
+        assert_eq!(None, config.get("branch", Some("main"), "remote"));
+        assert_eq!(Some("John  Doe"), config.get("user", None, "name"));
+        assert_eq!(Some("a\"b\\c"), config.get("user", None, "signingkey"));
+    }
+
+    #[test]
+    fn test_parse_config_continuation() {
+        let config = Config::parse("[alias]\n\tlg = log \\\n --oneline\n").unwrap();
+        assert_eq!(Some("log  --oneline"), config.get("alias", None, "lg"));
+    }
+
+    #[test]
+    fn test_get_bool() {
+        let config = Config::parse("[core]\n\tbare\n\tfilemode = On\n\tsymlinks = 0\n").unwrap();
+        assert_eq!(Some(true), config.get_bool("core", None, "bare"));
+        assert_eq!(Some(true), config.get_bool("core", None, "filemode"));
+        assert_eq!(Some(false), config.get_bool("core", None, "symlinks"));
+        assert_eq!(None, config.get_bool("core", None, "ignorecase"));
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        assert!(Config::parse("key = value\n").is_err());
+        assert!(Config::parse("[core\n").is_err());
+        assert!(Config::parse("[core]\n\tname = \"unterminated\n").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Timestamps as git records them: seconds since the epoch with a `+hhmm` offset from UTC.
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+const UNITS: [(&str, i64); 7] = [
+    ("second", 1),
+    ("minute", 60),
+    ("hour", 60 * 60),
+    ("day", 24 * 60 * 60),
+    ("week", 7 * 24 * 60 * 60),
+    ("month", 30 * 24 * 60 * 60),
+    ("year", 365 * 24 * 60 * 60),
+];
+
+/// The current time and the offset of the local timezone.
+pub fn now() -> (i64, String) {
+    let now = Local::now();
+    (
+        now.timestamp(),
+        format_offset(now.offset().local_minus_utc()),
+    )
+}
+
+/// Parses the dates git accepts in `GIT_AUTHOR_DATE` and `@{<date>}`: its own
+/// `<timestamp> <offset>`, `@<timestamp>`, RFC 2822, ISO 8601 (`2005-04-07 22:13:13 +0200`,
+/// with the current time of day for a date alone), and relative dates (`yesterday`,
+/// `2.weeks.ago`). Dates without an offset are local.
+pub fn parse(date: &str) -> Option<(i64, String)> {
+    let date = date.trim();
+    let (now, local_offset) = now();
+
+    if let Some((timestamp, offset)) = date.split_once(' ')
+        && let Some(timestamp) = parse_timestamp(timestamp)
+        && parse_offset(offset).is_some()
+    {
+        return Some((timestamp, offset.to_string()));
+    }
+    if let Some(timestamp) = parse_timestamp(date) {
+        return Some((timestamp, local_offset));
+    }
+
+    match date {
+        "now" => return Some((now, local_offset)),
+        "yesterday" => return Some((now - UNITS[3].1, local_offset)),
+        _ => {}
+    }
+    if let Some(ago) = parse_ago(date) {
+        return Some((now.checked_sub(ago)?, local_offset));
+    }
+
+    if let Ok(date) = DateTime::parse_from_rfc2822(date) {
+        return Some((
+            date.timestamp(),
+            format_offset(date.offset().local_minus_utc()),
+        ));
+    }
+    parse_iso(date)
+}
+
+/// `%s` of date(1): only digits, after `@` or long enough not to be mistaken for a year or a
+/// day.
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (digits, min_len) = match timestamp.strip_prefix('@') {
+        Some(digits) => (digits, 1),
+        None => (timestamp, 9),
+    };
+    match digits.len() >= min_len && digits.bytes().all(|c| c.is_ascii_digit()) {
+        true => digits.parse().ok(),
+        false => None,
+    }
+}
+
+/// `<n> <unit>[s] ago`, words separated by spaces or dots, in seconds. None if it overflows.
+fn parse_ago(date: &str) -> Option<i64> {
+    let words = date.split([' ', '.']).collect::<Vec<_>>();
+    let [n, unit, "ago"] = words[..] else {
+        return None;
+    };
+    let unit = unit.strip_suffix('s').unwrap_or(unit);
+    let (_, seconds) = UNITS.iter().find(|(name, _)| *name == unit)?;
+    n.parse::<i64>().ok()?.checked_mul(*seconds)
+}
+
+fn parse_iso(date: &str) -> Option<(i64, String)> {
+    let (date, offset) = match date.rsplit_once([' ', 'T']) {
+        Some((rest, offset)) if parse_offset(offset).is_some() => (rest, Some(offset.to_string())),
+        _ => match date.strip_suffix('Z') {
+            Some(rest) => (rest, Some("+0000".to_string())),
+            None => (date, None),
+        },
+    };
+
+    let datetime = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+            Some(day.and_time(Local::now().time()))
+        })?;
+
+    match offset {
+        Some(offset) => {
+            let timezone = FixedOffset::east_opt(parse_offset(&offset)?)?;
+            let timestamp = timezone
+                .from_local_datetime(&datetime)
+                .single()?
+                .timestamp();
+            Some((timestamp, offset))
+        }
+        None => {
+            let local = Local.from_local_datetime(&datetime).earliest()?;
+            Some((
+                local.timestamp(),
+                format_offset(local.offset().local_minus_utc()),
+            ))
+        }
+    }
+}
+
+/// `+0200` to seconds east of UTC.
+fn parse_offset(offset: &str) -> Option<i32> {
+    let (sign, digits) = match offset.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = digits.split_at(2);
+    Some(sign * (hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60))
+}
+
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+/// `Wed, 15 Nov 2023 00:13:21 +0200`, how git shows dates in messages.
+pub fn format_rfc2822(timestamp: i64, offset: &str) -> String {
+    let timezone = parse_offset(offset)
+        .and_then(FixedOffset::east_opt)
+        .unwrap_or(FixedOffset::east_opt(0).unwrap());
+    match timezone.timestamp_opt(timestamp, 0).single() {
+        Some(date) => date.format("%a, %-d %b %Y %H:%M:%S %z").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::date::{format_rfc2822, now, parse};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Some((1700000000, "+0200".to_string())),
+            parse("1700000000 +0200")
+        );
+        assert_eq!(
+            Some((1700000000, "-0130".to_string())),
+            parse("@1700000000 -0130")
+        );
+        assert_eq!(
+            Some((1699948805, "+0200".to_string())),
+            parse("2023-11-14 10:00:05 +0200")
+        );
+        assert_eq!(
+            Some((1699956005, "+0000".to_string())),
+            parse("2023-11-14T10:00:05Z")
+        );
+        assert_eq!(
+            Some((1112904793, "+0200".to_string())),
+            parse("Thu, 07 Apr 2005 22:13:13 +0200")
+        );
+        assert_eq!(Some((0, "+0000".to_string())), parse("@0 +0000"));
+        assert_eq!(Some(86400), parse("@86400").map(|(ts, _)| ts));
+        assert_eq!(None, parse("86400 +0000"));
+        assert_eq!(None, parse("@ +0000"));
+        assert_eq!(Some(1700000000), parse("1700000000").map(|(ts, _)| ts));
+        assert_eq!(None, parse("garbage"));
+        assert_eq!(None, parse("2 fortnights ago"));
+    }
+
+    #[test]
+    fn test_parse_relative() {
+        let (now, _) = now();
+        let (two_days_ago, _) = parse("2 days ago").unwrap();
+        assert!((now - 2 * 86400 - two_days_ago).abs() <= 1);
+        let (two_weeks_ago, _) = parse("2.weeks.ago").unwrap();
+        assert!((now - 14 * 86400 - two_weeks_ago).abs() <= 1);
+        let (yesterday, _) = parse("yesterday").unwrap();
+        assert!((now - 86400 - yesterday).abs() <= 1);
+        assert_eq!(None, parse("99999999999999999 years ago"));
+        assert_eq!(None, parse("-9223372036854775807.seconds.ago"));
+    }
+
+    #[test]
+    fn test_format_rfc2822() {
+        assert_eq!(
+            "Wed, 15 Nov 2023 00:13:21 +0200",
+            format_rfc2822(1700000001, "+0200")
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::{fmt, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    ObjectNotFound(String),
+    CorruptObject { id: String, reason: String },
+    WrongObjectType { id: String, expected: &'static str },
+    CorruptIndex(String),
+    BadPack(String),
+    Protocol(String),
+    Io { path: PathBuf, source: io::Error },
+    UnknownRevision(String),
+    AmbiguousObject(String),
+    InvalidObjectId(String),
+    CannotLockRef { name: String, reason: String },
+    HashCollision(String),
+    BadConfig { line: usize },
+    NotARepository,
+    PathspecNoMatch(String),
+    Unmerged(String),
+    Usage(String),
+    Other(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn corrupt_object(id: impl fmt::Display, reason: impl Into<String>) -> Self {
+        Error::CorruptObject {
+            id: id.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    /// The exit code git uses for this kind of failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 129,
+            _ => 128,
+        }
+    }
+
+    /// Recovers an error that travelled through an `io::Read` implementation (like the protocol
+    /// errors of `SideBandReader`), or describes the stream failure as a bad pack.
+    pub(crate) fn from_stream(err: io::Error, context: &str) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+
+        Error::BadPack(format!("{}: {}", context, err))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ObjectNotFound(id) => write!(f, "object not found: {}", id),
+            Error::CannotLockRef { name, reason } => {
+                write!(f, "cannot lock ref '{}': {}", name, reason)
+            }
+            Error::HashCollision(id) => {
+                write!(f, "SHA-1 appears to be part of a collision attack: {}", id)
+            }
+            Error::CorruptObject { id, reason } => write!(f, "corrupt object {}: {}", id, reason),
+            Error::WrongObjectType { id, expected } => write!(f, "{} is not a {}", id, expected),
+            Error::CorruptIndex(reason) => write!(f, "index file corrupt: {}", reason),
+            Error::BadPack(reason) => write!(f, "bad pack: {}", reason),
+            Error::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Error::Io { path, source } => write!(f, "{}: {}", path.to_string_lossy(), source),
+            Error::UnknownRevision(name) => write!(
+                f,
+                "ambiguous argument '{}': unknown revision or path not in the working tree.",
+                name
+            ),
+            Error::InvalidObjectId(name) => write!(f, "Not a valid object name {}", name),
+            Error::AmbiguousObject(prefix) => write!(f, "short object ID {} is ambiguous", prefix),
+            Error::BadConfig { line } => {
+                write!(f, "bad config line {} in file .git/config", line)
+            }
+            Error::NotARepository => write!(
+                f,
+                "not a git repository (or any of the parent directories): .git"
+            ),
+            Error::PathspecNoMatch(path) => {
+                write!(f, "pathspec '{}' did not match any files", path)
+            }
+            Error::Unmerged(path) => write!(f, "{}: unmerged", path),
+            Error::Usage(reason) | Error::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Protocol(err.to_string())
+    }
+}
+
+/// Attaches the path an IO operation worked on to its error.
+pub(crate) trait IoResultExt<T> {
+    fn with_path(self, path: impl Into<PathBuf>) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn with_path(self, path: impl Into<PathBuf>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.into(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,590 @@
+use std::{
+    fs::{self, Metadata, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use crate::{
+    common::{FileMode, bytes_to_string},
+    error::{Error, IoResultExt, Result},
+    object_id::{ObjectFormat, ObjectId},
+    reader::Reader,
+};
+
+const INDEX_SIGNATURE: &[u8] = b"DIRC";
+
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+const EXTENDED_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+const EXTENDED_FLAG_INTENT_TO_ADD: u16 = 0x2000;
+
+/// One staged file, with the stat data used to tell whether the worktree file changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub ctime_seconds: u32,
+    pub ctime_nanoseconds: u32,
+    pub mtime_seconds: u32,
+    pub mtime_nanoseconds: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: FileMode,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub hash: ObjectId,
+    pub assume_valid: bool,
+    /// 0 for normal entries, 1-3 for the base, ours and theirs sides of a conflict.
+    pub stage: u8,
+    pub skip_worktree: bool,
+    pub intent_to_add: bool,
+    /// Slash separated, relative to the top of the worktree.
+    pub path: String,
+}
+
+impl IndexEntry {
+    /// An entry for a worktree file, `metadata` comes from `symlink_metadata`.
+    pub fn from_metadata(path: &str, mode: FileMode, hash: ObjectId, metadata: &Metadata) -> Self {
+        // The index keeps the low 32 bits of the stat data, like git.
+        Self {
+            ctime_seconds: metadata.ctime() as u32,
+            ctime_nanoseconds: metadata.ctime_nsec() as u32,
+            mtime_seconds: metadata.mtime() as u32,
+            mtime_nanoseconds: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            hash,
+            assume_valid: false,
+            stage: 0,
+            skip_worktree: false,
+            intent_to_add: false,
+            path: path.to_string(),
+        }
+    }
+
+    fn is_extended(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+}
+
+/// The staging area (`.git/index`), versions 2 to 4.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub version: u32,
+    /// Object format of the repository, which sets the length of ids and of the checksum.
+    pub format: ObjectFormat,
+    /// Sorted by path, then stage.
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new(ObjectFormat::default())
+    }
+}
+
+impl Index {
+    /// An empty version 2 index.
+    pub fn new(format: ObjectFormat) -> Self {
+        Self {
+            version: 2,
+            format,
+            entries: vec![],
+        }
+    }
+
+    /// Reads the `index` file of the git directory `git_dir`, a missing file is an empty index.
+    pub fn read(git_dir: &Path, format: ObjectFormat) -> Result<Self> {
+        let index_path = git_dir.join("index");
+        match fs::read(&index_path) {
+            Ok(bytes) => Self::parse(&bytes[..], format),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new(format)),
+            Err(err) => Err(err).with_path(index_path),
+        }
+    }
+
+    /// Replaces the `index` file of `git_dir` through a lock file, failing if another process
+    /// holds the lock.
+    pub fn write(&self, git_dir: &Path) -> Result<()> {
+        let (index_path, lock_path) = (git_dir.join("index"), git_dir.join("index.lock"));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .with_path(&lock_path)?;
+
+        if let Err(err) = file.write_all(&self.to_bytes()) {
+            let _ = fs::remove_file(&lock_path);
+            return Err(err).with_path(lock_path);
+        }
+        drop(file);
+
+        fs::rename(&lock_path, &index_path).with_path(index_path)
+    }
+
+    pub fn parse(bytes: &[u8], format: ObjectFormat) -> Result<Self> {
+        if bytes.len() < 12 + format.id_len() {
+            return Err(Error::CorruptIndex("file too short".to_string()));
+        }
+
+        let (content, checksum) = bytes.split_at(bytes.len() - format.id_len());
+        let expected_checksum = format.hash(content);
+        if checksum != expected_checksum.as_bytes() {
+            return Err(Error::CorruptIndex(format!(
+                "checksum mismatch: expected {}, got {}",
+                expected_checksum,
+                bytes_to_string(checksum)
+            )));
+        }
+
+        let mut reader = Reader::new(content);
+        if reader.popn(4) != INDEX_SIGNATURE {
+            return Err(Error::CorruptIndex("bad signature".to_string()));
+        }
+
+        let version = pop_u32(&mut reader)?;
+        if !(2..=4).contains(&version) {
+            return Err(Error::CorruptIndex(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+
+        let entry_count = pop_u32(&mut reader)?;
+        // The count is untrusted, a truncated file must not allocate for it.
+        let mut entries: Vec<IndexEntry> = vec![];
+        for _ in 0..entry_count {
+            let previous_path = entries.last().map(|entry| &entry.path[..]).unwrap_or("");
+            let entry = parse_entry(&mut reader, version, previous_path, format)?;
+            entries.push(entry);
+        }
+
+        while !reader.is_empty() {
+            let signature = pop_bytes(&mut reader, 4)?;
+            let size = pop_u32(&mut reader)?;
+            pop_bytes(&mut reader, size as usize)?;
+
+            // Extensions starting with an uppercase letter are optional caches, anything else
+            // changes how the index has to be read.
+            if !signature[0].is_ascii_uppercase() {
+                return Err(Error::CorruptIndex(format!(
+                    "unsupported extension {}",
+                    String::from_utf8_lossy(signature)
+                )));
+            }
+            debug!(
+                "Ignoring index extension {}",
+                String::from_utf8_lossy(signature)
+            );
+        }
+
+        Ok(Self {
+            version,
+            format,
+            entries,
+        })
+    }
+
+    /// Serializes the entries. Optional extensions are not kept: their caches would be stale.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let version = if self.version == 2 && self.entries.iter().any(IndexEntry::is_extended) {
+            3
+        } else {
+            self.version
+        };
+
+        let mut bytes = INDEX_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+
+        let mut previous_path = "";
+        for entry in &self.entries {
+            let entry_start = bytes.len();
+
+            for field in [
+                entry.ctime_seconds,
+                entry.ctime_nanoseconds,
+                entry.mtime_seconds,
+                entry.mtime_nanoseconds,
+                entry.dev,
+                entry.ino,
+                entry.mode.as_octal(),
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                bytes.extend_from_slice(&field.to_be_bytes());
+            }
+            bytes.extend_from_slice(entry.hash.as_bytes());
+
+            let mut flags = (entry.path.len().min(FLAG_NAME_MASK as usize)) as u16;
+            flags |= ((entry.stage as u16) << 12) & FLAG_STAGE_MASK;
+            if entry.assume_valid {
+                flags |= FLAG_ASSUME_VALID;
+            }
+            if entry.is_extended() {
+                flags |= FLAG_EXTENDED;
+            }
+            bytes.extend_from_slice(&flags.to_be_bytes());
+
+            if entry.is_extended() {
+                let mut extended_flags = 0u16;
+                if entry.skip_worktree {
+                    extended_flags |= EXTENDED_FLAG_SKIP_WORKTREE;
+                }
+                if entry.intent_to_add {
+                    extended_flags |= EXTENDED_FLAG_INTENT_TO_ADD;
+                }
+                bytes.extend_from_slice(&extended_flags.to_be_bytes());
+            }
+
+            if version == 4 {
+                let common_len = common_prefix_len(previous_path, &entry.path);
+                bytes.extend_from_slice(&encode_offset_varint(
+                    (previous_path.len() - common_len) as u64,
+                ));
+                bytes.extend_from_slice(&entry.path.as_bytes()[common_len..]);
+                bytes.push(0);
+            } else {
+                bytes.extend_from_slice(entry.path.as_bytes());
+                // 1 to 8 NULs, padding the entry to a multiple of 8 bytes.
+                let entry_len = bytes.len() - entry_start;
+                bytes.resize(entry_start + (entry_len + 8) / 8 * 8, 0);
+            }
+
+            previous_path = &entry.path;
+        }
+
+        let checksum = self.format.hash(&bytes);
+        bytes.extend_from_slice(checksum.as_bytes());
+        bytes
+    }
+
+    /// Adds or replaces the stage 0 entry of a path, dropping its conflict stages. A file
+    /// replaces the entries under a directory of the same name, and the other way round.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let is_parent = |dir: &str, path: &str| {
+            path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+        };
+        self.entries.retain(|existing| {
+            existing.path != entry.path
+                && !is_parent(&entry.path, &existing.path)
+                && !is_parent(&existing.path, &entry.path)
+        });
+        let position = self
+            .entries
+            .partition_point(|existing| existing.path.as_bytes() < entry.path.as_bytes());
+        self.entries.insert(position, entry);
+    }
+
+    /// Removes every stage of a path, returns whether it was in the index.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.path != path);
+        self.entries.len() != len
+    }
+}
+
+fn parse_entry(
+    reader: &mut Reader<u8>,
+    version: u32,
+    previous_path: &str,
+    format: ObjectFormat,
+) -> Result<IndexEntry> {
+    let entry_start_len = reader.len();
+
+    let mut stat = [0u32; 10];
+    for field in stat.iter_mut() {
+        *field = pop_u32(reader)?;
+    }
+    let hash = ObjectId::from_slice(pop_bytes(reader, format.id_len())?).unwrap();
+    let flags = pop_u16(reader)?;
+
+    let extended_flags = if flags & FLAG_EXTENDED != 0 {
+        if version < 3 {
+            return Err(Error::CorruptIndex(
+                "extended flags in a version 2 index".to_string(),
+            ));
+        }
+        pop_u16(reader)?
+    } else {
+        0
+    };
+
+    let path_bytes = if version == 4 {
+        let strip_len = reader.pop_offset_varint().ok_or_else(|| {
+            Error::CorruptIndex("truncated entry or invalid path prefix length".to_string())
+        })? as usize;
+        if strip_len > previous_path.len() {
+            return Err(Error::CorruptIndex(format!(
+                "invalid path prefix length {}",
+                strip_len
+            )));
+        }
+
+        let mut path_bytes = previous_path.as_bytes()[..previous_path.len() - strip_len].to_vec();
+        path_bytes.extend_from_slice(reader.pop_while(|c| c != &0));
+        pop_bytes(reader, 1)?; // \0
+        path_bytes
+    } else {
+        let path_bytes = reader.pop_while(|c| c != &0).to_vec();
+        let entry_len = entry_start_len - reader.len();
+        pop_bytes(reader, (entry_len + 8) / 8 * 8 - entry_len)?; // \0 padding
+        path_bytes
+    };
+
+    let path = String::from_utf8(path_bytes)
+        .map_err(|_| Error::CorruptIndex("path is not valid UTF-8".to_string()))?;
+
+    Ok(IndexEntry {
+        ctime_seconds: stat[0],
+        ctime_nanoseconds: stat[1],
+        mtime_seconds: stat[2],
+        mtime_nanoseconds: stat[3],
+        dev: stat[4],
+        ino: stat[5],
+        mode: FileMode::from_octal(stat[6])
+            .filter(|mode| *mode != FileMode::Tree)
+            .ok_or_else(|| Error::CorruptIndex(format!("invalid mode {:o}", stat[6])))?,
+        uid: stat[7],
+        gid: stat[8],
+        size: stat[9],
+        hash,
+        assume_valid: flags & FLAG_ASSUME_VALID != 0,
+        stage: ((flags & FLAG_STAGE_MASK) >> 12) as u8,
+        skip_worktree: extended_flags & EXTENDED_FLAG_SKIP_WORKTREE != 0,
+        intent_to_add: extended_flags & EXTENDED_FLAG_INTENT_TO_ADD != 0,
+        path,
+    })
+}
+
+fn pop_bytes<'a>(reader: &mut Reader<'a, u8>, n: usize) -> Result<&'a [u8]> {
+    reader
+        .try_popn(n)
+        .ok_or_else(|| Error::CorruptIndex("truncated entry".to_string()))
+}
+
+fn pop_u32(reader: &mut Reader<u8>) -> Result<u32> {
+    Ok(u32::from_be_bytes(
+        pop_bytes(reader, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn pop_u16(reader: &mut Reader<u8>) -> Result<u16> {
+    Ok(u16::from_be_bytes(
+        pop_bytes(reader, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count()
+}
+
+/// Inverse of `Reader::pop_offset_varint`.
+pub(crate) fn encode_offset_varint(mut value: u64) -> Vec<u8> {
+    let mut bytes = vec![(value & 0b0111_1111) as u8];
+    value >>= 7;
+
+    while value > 0 {
+        value -= 1;
+        bytes.push(0b1000_0000 | (value & 0b0111_1111) as u8);
+        value >>= 7;
+    }
+
+    bytes.reverse();
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        common::FileMode,
+        error::Error,
+        index::{Index, IndexEntry, encode_offset_varint},
+        object_id::{ObjectFormat, ObjectId},
+        reader::Reader,
+    };
+
+    const SHA1: ObjectFormat = ObjectFormat::Sha1;
+
+    fn entry(path: &str) -> IndexEntry {
+        IndexEntry {
+            ctime_seconds: 1,
+            ctime_nanoseconds: 2,
+            mtime_seconds: 3,
+            mtime_nanoseconds: 4,
+            dev: 5,
+            ino: 6,
+            mode: FileMode::Regular,
+            uid: 7,
+            gid: 8,
+            size: 9,
+            hash: "ce013625030ba8dba906f756967f9e9ca394464a".parse().unwrap(),
+            assume_valid: false,
+            stage: 0,
+            skip_worktree: false,
+            intent_to_add: false,
+            path: path.to_string(),
+        }
+    }
+
+    fn sample_index(version: u32) -> Index {
+        let mut index = Index {
+            version,
+            ..Index::default()
+        };
+        index.add(entry("src/main.rs"));
+        index.add(entry("README.md"));
+        index.add(entry("src/lib.rs"));
+        index
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        for version in 2..=4 {
+            let index = sample_index(version);
+            assert_eq!(index, Index::parse(&index.to_bytes(), SHA1).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_sha256_index_round_trip() {
+        let mut index = Index::new(ObjectFormat::Sha256);
+        let mut sha256_entry = entry("README.md");
+        sha256_entry.hash = ObjectId::Sha256([0xab; 32]);
+        index.add(sha256_entry);
+
+        let bytes = index.to_bytes();
+        // 74 bytes of fixed fields plus "README.md" padded to 88, then a 32 byte checksum.
+        assert_eq!(12 + 88 + 32, bytes.len());
+        assert_eq!(index, Index::parse(&bytes, ObjectFormat::Sha256).unwrap());
+        assert!(Index::parse(&bytes, SHA1).is_err());
+    }
+
+    #[test]
+    fn test_index_v2_layout() {
+        let index = sample_index(2);
+        let bytes = index.to_bytes();
+
+        // 62 bytes of fixed fields plus "README.md" padded to 72.
+        assert_eq!(b"README.md\0", &bytes[12 + 62..12 + 72]);
+        assert_eq!(b"src/lib.rs", &bytes[12 + 72 + 62..12 + 72 + 72]);
+        assert_eq!(
+            vec!["README.md", "src/lib.rs", "src/main.rs"],
+            index
+                .entries
+                .iter()
+                .map(|entry| &entry.path[..])
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_index_extended_flags() {
+        let mut index = sample_index(2);
+        index.entries[1].skip_worktree = true;
+
+        let bytes = index.to_bytes();
+        assert_eq!(3u32.to_be_bytes(), bytes[4..8]);
+
+        let parsed = Index::parse(&bytes, SHA1).unwrap();
+        assert_eq!(3, parsed.version);
+        assert!(parsed.entries[1].skip_worktree);
+        assert!(!parsed.entries[1].intent_to_add);
+    }
+
+    #[test]
+    fn test_index_v4_prefix_compression() {
+        let bytes = sample_index(4).to_bytes();
+        // "src/main.rs" after "src/lib.rs": strip 6 bytes, then "main.rs".
+        let tail = b"\x06main.rs\0";
+        assert!(bytes.windows(tail.len()).any(|window| window == tail));
+    }
+
+    #[test]
+    fn test_index_bad_checksum() {
+        let mut bytes = sample_index(2).to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(Index::parse(&bytes, SHA1).is_err());
+    }
+
+    #[test]
+    fn test_index_untrusted_sizes() {
+        let with_checksum = |mut bytes: Vec<u8>| {
+            let checksum = SHA1.hash(&bytes);
+            bytes.extend_from_slice(checksum.as_bytes());
+            bytes
+        };
+        let mut index = Index {
+            version: 4,
+            ..Index::default()
+        };
+        index.add(entry("README.md"));
+        let bytes = index.to_bytes();
+        let content = &bytes[..bytes.len() - 20];
+
+        // A count of entries the file cannot hold.
+        let mut huge_count = content.to_vec();
+        huge_count[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        // A path prefix length wider than 64 bits.
+        let mut overlong = content[..12 + 62].to_vec();
+        overlong.extend_from_slice(&[0xff; 10]);
+        overlong.push(0x7f);
+        overlong.extend_from_slice(&content[12 + 62 + 1..]);
+
+        for bytes in [huge_count, overlong] {
+            assert!(matches!(
+                Index::parse(&with_checksum(bytes), SHA1),
+                Err(Error::CorruptIndex(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_index_remove() {
+        let mut index = sample_index(2);
+        assert!(index.remove("src/lib.rs"));
+        assert!(!index.remove("src/lib.rs"));
+        assert_eq!(2, index.entries.len());
+    }
+
+    #[test]
+    fn test_index_add_replaces_directory_and_file() {
+        let mut index = sample_index(2);
+        index.add(entry("src"));
+        assert_eq!(
+            vec!["README.md", "src"],
+            index
+                .entries
+                .iter()
+                .map(|entry| entry.path.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        index.add(entry("src/main.rs"));
+        index.add(entry("src.rs"));
+        assert_eq!(
+            vec!["README.md", "src.rs", "src/main.rs"],
+            index
+                .entries
+                .iter()
+                .map(|entry| entry.path.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_encode_offset_varint() {
+        for value in [0, 1, 127, 128, 300, 16511, 16512, 1 << 40] {
+            let bytes = encode_offset_varint(value);
+            assert_eq!(Some(value), Reader::new(&bytes[..]).pop_offset_varint());
+        }
+    }
+}
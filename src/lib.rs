@@ -0,0 +1,34 @@
+#[macro_use]
+extern crate log;
+
+mod commit;
+mod common;
+mod config;
+pub mod date;
+mod error;
+mod index;
+mod object_id;
+mod odb;
+mod pack;
+mod pack_index;
+mod reader;
+pub mod reflog;
+pub mod refs;
+mod repository;
+mod revision;
+mod tag;
+#[cfg(test)]
+mod test_util;
+pub mod transport;
+
+pub use crate::{
+    commit::{Commit, ExtraHeaders, Identity, Role, cleanup_message},
+    common::{FileMode, Object, TreeEntry},
+    config::Config,
+    error::{Error, Result},
+    index::{Index, IndexEntry},
+    object_id::{Hasher, ObjectFormat, ObjectId},
+    pack::{PackObjectType, PackReader},
+    repository::{CommitOptions, InitOptions, Initialized, RefUpdate, Repository, ShowRefOptions},
+    tag::Tag,
+};
@@ -1,58 +1,337 @@
-#[macro_use]
-extern crate log;
-
-use clap::{Parser, Subcommand};
-use flate2::{Compression, write::ZlibEncoder};
-use sha1::{Digest, Sha1};
+use clap::{ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use std::{
-    collections::BTreeMap,
-    fs,
-    io::{Read, Write},
+    io::{BufRead, Read, Write},
+    path::{Path, PathBuf},
 };
 
-use crate::{
-    common::{
-        Entry, Hash, bytes_to_string, create_object_blob_payload_from_file,
-        create_object_payload_from_content, hex_len_prefixed_string,
-    },
-    pack::{PackObject, PackObjectType, PackReader},
+use codecrafters_git::{
+    CommitOptions, Error, FileMode, InitOptions, Object, ObjectFormat, ObjectId, PackObjectType,
+    Repository, Result, ShowRefOptions, TreeEntry, reflog,
+    refs::{self, Ref, SortKey},
 };
 
-mod common;
-mod pack;
-mod reader;
-
 #[derive(Subcommand)]
 enum CliCommand {
-    Init,
+    Init {
+        /// Only print errors and warnings
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Create a bare repository, without a work tree
+        #[arg(long)]
+        bare: bool,
+
+        /// The hash function naming objects, sha1 or sha256
+        #[arg(long = "object-format")]
+        object_format: Option<String>,
+
+        /// The branch HEAD points at in the new repository
+        #[arg(short = 'b', long = "initial-branch")]
+        initial_branch: Option<String>,
+
+        /// Directory whose files are copied into the new git directory
+        #[arg(long)]
+        template: Option<PathBuf>,
+
+        /// Put the git directory there, with a `.git` file pointing at it in the work tree
+        #[arg(long = "separate-git-dir")]
+        separate_git_dir: Option<PathBuf>,
+
+        /// Where to create the repository, the current directory by default
+        directory: Option<PathBuf>,
+    },
+    #[command(group(
+        ArgGroup::new("mode")
+            .required(true)
+            .args(["kind", "size", "exists", "pretty", "batch", "batch_check"])
+    ))]
     CatFile {
-        #[arg(short = 'p', long)]
-        parent_hash: String,
+        /// Show the object type
+        #[arg(short = 't')]
+        kind: bool,
+
+        /// Show the object size
+        #[arg(short = 's')]
+        size: bool,
+
+        /// Exit with zero status if the object exists and is valid
+        #[arg(short = 'e')]
+        exists: bool,
+
+        /// Pretty-print the object content
+        #[arg(short = 'p')]
+        pretty: bool,
+
+        /// Print info and content of the objects named on stdin
+        #[arg(long)]
+        batch: bool,
+
+        /// Print info of the objects named on stdin
+        #[arg(long = "batch-check")]
+        batch_check: bool,
+
+        #[arg(required_unless_present_any = ["batch", "batch_check"], conflicts_with_all = ["batch", "batch_check"])]
+        object: Option<String>,
     },
     HashObject {
         #[arg(short = 'w', long)]
         file_path: String,
     },
     LsTree {
-        object_hash: String,
+        /// Recurse into subtrees
+        #[arg(short = 'r')]
+        recursive: bool,
 
-        #[arg(long = "name-only")]
+        /// Show trees even when recursing into them
+        #[arg(short = 't')]
+        show_trees: bool,
+
+        /// Show only trees
+        #[arg(short = 'd')]
+        trees_only: bool,
+
+        /// Show the size of blobs
+        #[arg(short = 'l', long = "long")]
+        long: bool,
+
+        /// Terminate entries with NUL instead of newline, without quoting paths
+        #[arg(short = 'z')]
+        nul_terminated: bool,
+
+        #[arg(long = "name-only", alias = "name-status")]
         name_only: bool,
+
+        /// A revision naming a tree, or a commit or tag leading to one
+        tree_ish: String,
+
+        /// Only show these paths
+        paths: Vec<String>,
+    },
+    RevParse {
+        /// Fail unless exactly one argument names an existing object
+        #[arg(long)]
+        verify: bool,
+
+        /// With --verify, exit with status 1 instead of an error message
+        #[arg(short = 'q', long)]
+        quiet: bool,
+
+        /// Print the shortest unique prefix of at least this many characters
+        #[arg(long, value_name = "N", num_args = 0..=1, require_equals = true, default_missing_value = "7")]
+        short: Option<usize>,
+
+        #[arg(required = true)]
+        revs: Vec<String>,
     },
     WriteTree,
-    CommitTree {
-        tree_hash: String,
+    Add {
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    Rm {
+        #[arg(long)]
+        cached: bool,
 
-        #[arg(short = 'p', long)]
-        parent_hash: String,
+        #[arg(short = 'r')]
+        recursive: bool,
 
+        /// Remove files even if they have changes that are not committed
         #[arg(short, long)]
-        message: String,
+        force: bool,
+
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    Commit {
+        /// The commit message, several are joined as paragraphs
+        #[arg(short, long)]
+        message: Vec<String>,
+
+        /// Take the commit message from a file, `-` for the standard input
+        #[arg(short = 'F', long, conflicts_with = "message")]
+        file: Option<PathBuf>,
+
+        /// Replace the commit at HEAD instead of adding one on top of it
+        #[arg(long)]
+        amend: bool,
+
+        /// Commit even if nothing changed since the parent commit
+        #[arg(long = "allow-empty")]
+        allow_empty: bool,
+
+        /// Do not print the summary of the commit
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    CommitTree {
+        tree: String,
+
+        /// A parent commit, repeated for merges; none makes a root commit
+        #[arg(short = 'p')]
+        parents: Vec<String>,
+
+        /// A paragraph of the message
+        #[arg(short = 'm')]
+        message: Vec<String>,
+
+        /// Read a part of the message from a file, or from the standard input for `-`
+        #[arg(short = 'F')]
+        file: Vec<PathBuf>,
+
+        /// `-m` and `-F` in command line order, which clap keeps apart
+        #[arg(skip)]
+        message_sources: Vec<MessageSource>,
     },
     Clone {
         url: String,
         dir: String,
     },
+    Tag {
+        #[arg(short = 'a', long)]
+        annotate: bool,
+
+        /// Replace an existing tag
+        #[arg(short, long)]
+        force: bool,
+
+        name: String,
+
+        object_hash: Option<String>,
+
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    UpdateRef {
+        /// Delete the ref, after checking its old value if given
+        #[arg(short = 'd')]
+        delete: bool,
+
+        /// Update the ref itself rather than the ref it points to
+        #[arg(long = "no-deref")]
+        no_deref: bool,
+
+        /// Reason of the update, recorded in the reflog
+        #[arg(short = 'm', default_value = "")]
+        message: String,
+
+        name: String,
+
+        /// The new value then the expected old value, only the old value with -d
+        values: Vec<String>,
+    },
+    SymbolicRef {
+        /// Exit with status 1 instead of an error when the ref is not symbolic
+        #[arg(short = 'q', long)]
+        quiet: bool,
+
+        /// Delete the symbolic ref
+        #[arg(short = 'd', long)]
+        delete: bool,
+
+        /// Print the target as a short name, `main` for `refs/heads/main`
+        #[arg(long)]
+        short: bool,
+
+        name: String,
+
+        /// Point the symbolic ref at this ref instead of printing its target
+        target: Option<String>,
+    },
+    ShowRef {
+        /// Show HEAD too
+        #[arg(long)]
+        head: bool,
+
+        /// Also show the objects annotated tags point at, as `<name>^{}`
+        #[arg(short = 'd', long)]
+        dereference: bool,
+
+        /// Only show the object ids
+        #[arg(short = 's', long)]
+        hash: bool,
+
+        /// Only show tags
+        #[arg(long)]
+        tags: bool,
+
+        /// Only show branches
+        #[arg(long)]
+        heads: bool,
+
+        /// Only accept full ref names, failing on the first one that does not exist
+        #[arg(long)]
+        verify: bool,
+
+        /// Do not print anything, only set the exit status
+        #[arg(short = 'q', long)]
+        quiet: bool,
+
+        /// Only show refs named so, or ending with `/<pattern>`
+        patterns: Vec<String>,
+    },
+    ForEachRef {
+        /// Format of each line, with `%(<field>)` placeholders
+        #[arg(long, default_value = refs::DEFAULT_FORMAT)]
+        format: String,
+
+        /// Field to sort on, descending with a `-` prefix; the last key is the primary one
+        #[arg(long, allow_hyphen_values = true)]
+        sort: Vec<String>,
+
+        /// Stop after this many refs
+        #[arg(long)]
+        count: Option<usize>,
+
+        /// Only show refs under these prefixes or matching these globs
+        patterns: Vec<String>,
+    },
+    #[command(args_conflicts_with_subcommands = true)]
+    Reflog {
+        #[command(subcommand)]
+        command: Option<ReflogCommand>,
+
+        /// The ref whose reflog to show, HEAD by default
+        reference: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReflogCommand {
+    /// Show the entries of a reflog, newest first
+    Show {
+        /// The ref whose reflog to show, HEAD by default
+        reference: Option<String>,
+    },
+    /// Prune entries older than a date
+    Expire {
+        /// Prune entries older than this date, `now` for all of them and `never` for none
+        #[arg(long, default_value = "90 days ago")]
+        expire: String,
+
+        /// Prune the reflogs of every ref
+        #[arg(long)]
+        all: bool,
+
+        refs: Vec<String>,
+    },
+    /// Delete single entries, named like `main@{2}`
+    Delete {
+        /// Make the entry after a deleted one start where the deleted one started
+        #[arg(long)]
+        rewrite: bool,
+
+        #[arg(required = true)]
+        entries: Vec<String>,
+    },
+    /// Exit with status 0 if the ref has a reflog, 1 otherwise
+    Exists { reference: String },
+}
+
+/// Where a part of a `commit-tree` message comes from.
+#[derive(Clone)]
+enum MessageSource {
+    Message(String),
+    File(PathBuf),
 }
 
 #[derive(Parser)]
@@ -67,288 +346,1054 @@ fn main() {
     // unsafe { std::env::set_var("RUST_LOG", "debug") };
     pretty_env_logger::init();
 
-    let args = Args::parse();
+    let args = match Args::command()
+        .try_get_matches()
+        .and_then(|matches| parse_args(&matches))
+    {
+        Ok(args) => args,
+        Err(err) => {
+            let _ = err.print();
+            std::process::exit(if err.use_stderr() { 129 } else { 0 });
+        }
+    };
+
+    if let Err(err) = run(args) {
+        eprintln!("fatal: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn parse_args(matches: &ArgMatches) -> std::result::Result<Args, clap::Error> {
+    let mut args = Args::from_arg_matches(matches)?;
+    if let CliCommand::CommitTree {
+        message_sources, ..
+    } = &mut args.command
+        && let Some(matches) = matches.subcommand_matches("commit-tree")
+    {
+        let messages = matches
+            .indices_of("message")
+            .into_iter()
+            .flatten()
+            .zip(matches.get_many::<String>("message").into_iter().flatten())
+            .map(|(index, message)| (index, MessageSource::Message(message.clone())));
+        let files = matches
+            .indices_of("file")
+            .into_iter()
+            .flatten()
+            .zip(matches.get_many::<PathBuf>("file").into_iter().flatten())
+            .map(|(index, path)| (index, MessageSource::File(path.clone())));
+
+        let mut sources = messages.chain(files).collect::<Vec<_>>();
+        sources.sort_by_key(|(index, _)| *index);
+        *message_sources = sources.into_iter().map(|(_, source)| source).collect();
+    }
+    Ok(args)
+}
 
+fn run(args: Args) -> Result<()> {
     match args.command {
-        CliCommand::Init => git_init(),
+        CliCommand::Init {
+            quiet,
+            bare,
+            object_format,
+            initial_branch,
+            template,
+            separate_git_dir,
+            directory,
+        } => {
+            let object_format = object_format
+                .map(|name| {
+                    ObjectFormat::from_name(&name)
+                        .ok_or_else(|| Error::Usage(format!("unknown hash algorithm '{}'", name)))
+                })
+                .transpose()?;
+            let options = InitOptions {
+                bare,
+                object_format,
+                initial_branch,
+                template,
+                separate_git_dir,
+            };
+            let initialized =
+                Repository::init(directory.as_deref().unwrap_or(Path::new(".")), &options)?;
+
+            if initialized.reinitialized
+                && let Some(branch) = &options.initial_branch
+            {
+                eprintln!("warning: re-init: ignored --initial-branch={}", branch);
+            }
+            if !quiet {
+                println!(
+                    "{} Git repository in {}/",
+                    match initialized.reinitialized {
+                        true => "Reinitialized existing",
+                        false => "Initialized empty",
+                    },
+                    initialized.git_dir.display()
+                );
+            }
+        }
 
-        CliCommand::CatFile { parent_hash } => match Hash::new(parent_hash).read() {
-            Entry::File { content } => print!("{}", content),
-            Entry::Tree { .. } => unimplemented!(),
-        },
+        CliCommand::Clone { url, dir } => {
+            Repository::clone(&url, &dir)?;
+        }
+
+        command => run_in_repository(&Repository::discover()?, command)?,
+    }
+
+    Ok(())
+}
+
+fn run_in_repository(repo: &Repository, command: CliCommand) -> Result<()> {
+    match command {
+        CliCommand::Init { .. } | CliCommand::Clone { .. } => unreachable!(),
+
+        CliCommand::CatFile {
+            kind,
+            size,
+            exists,
+            pretty,
+            batch,
+            batch_check,
+            object,
+        } => {
+            if batch || batch_check {
+                return cat_file_batch(
+                    repo,
+                    std::io::stdin().lock(),
+                    &mut std::io::stdout().lock(),
+                    batch,
+                );
+            }
+
+            let hash = repo.rev_parse(&object.unwrap())?;
+            if exists {
+                match repo.read_object(&hash) {
+                    Ok(_) => {}
+                    Err(Error::ObjectNotFound(_)) => std::process::exit(1),
+                    Err(err) => return Err(err),
+                }
+            } else if pretty {
+                match repo.read_object(&hash)? {
+                    Object::Blob { content } => write_stdout(&content)?,
+                    Object::Tree { entries } => {
+                        for entry in entries {
+                            println!(
+                                "{} {} {}\t{}",
+                                entry.mode,
+                                entry.mode.kind().to_string(),
+                                entry.hash,
+                                quote_path(&entry.filename)
+                            );
+                        }
+                    }
+                    // Commits and tags are printed as stored, whatever their headers.
+                    Object::Commit { .. } | Object::Tag { .. } => {
+                        let (_, content) = repo
+                            .read_raw_object(&hash)?
+                            .ok_or_else(|| Error::ObjectNotFound(hash.to_string()))?;
+                        write_stdout(&content)?
+                    }
+                }
+            } else {
+                let (object_kind, content) = repo
+                    .read_raw_object(&hash)?
+                    .ok_or_else(|| Error::ObjectNotFound(hash.to_string()))?;
+                if kind {
+                    println!("{}", object_kind.to_string());
+                } else if size {
+                    println!("{}", content.len());
+                }
+            }
+        }
 
         CliCommand::HashObject { file_path } => {
-            let hash = write_object_file_from_file(&file_path);
-            println!("{}", hash.hash);
+            let hash = repo.write_blob_from_file(Path::new(&file_path))?;
+            println!("{}", hash);
         }
 
         CliCommand::LsTree {
-            object_hash,
+            recursive,
+            show_trees,
+            trees_only,
+            long,
+            nul_terminated,
             name_only,
-        } => match Hash::new(object_hash).read() {
-            Entry::File { .. } => unimplemented!(),
-            Entry::Tree { entries } => {
-                for entry in entries {
-                    if name_only {
-                        println!("{}", entry.filename);
-                    } else {
-                        println!(
-                            "{} {} {}\t{}",
-                            entry.perm,
-                            entry.perm_to_string(),
-                            entry.hash.hash,
-                            entry.filename
-                        );
-                    }
+            tree_ish,
+            paths,
+        } => {
+            let tree = repo.peel(&repo.rev_parse(&tree_ish)?, PackObjectType::Tree)?;
+            // Paths are relative to the current directory, which is listed by default.
+            let paths = match paths.is_empty() {
+                true if !repo.prefix().is_empty() => vec![repo.prefix().to_string()],
+                true => vec![],
+                false => paths
+                    .iter()
+                    .map(|path| {
+                        let mut normalized = repo.normalize_pathspec(path)?;
+                        let is_dir = path.ends_with('/')
+                            || path
+                                .rsplit('/')
+                                .next()
+                                .is_some_and(|last| last == "." || last == "..");
+                        if is_dir && !normalized.is_empty() {
+                            normalized.push('/');
+                        }
+                        Ok(normalized)
+                    })
+                    .collect::<Result<_>>()?,
+            };
+            let options = LsTreeOptions {
+                recursive,
+                show_trees,
+                trees_only,
+                long,
+                nul_terminated,
+                name_only,
+                paths,
+                cwd_prefix: repo.prefix().to_string(),
+            };
+            ls_tree(repo, &tree, b"", &options)?;
+        }
+
+        CliCommand::RevParse {
+            verify,
+            quiet,
+            short,
+            revs,
+        } => {
+            let hashes = if verify {
+                let hash = match &revs[..] {
+                    [rev] => repo
+                        .rev_parse(rev)
+                        .ok()
+                        .filter(|hash| matches!(repo.read_raw_object(hash), Ok(Some(_)))),
+                    _ => None,
+                };
+                match hash {
+                    Some(hash) => vec![hash],
+                    None if quiet => std::process::exit(1),
+                    None => return Err(Error::Other("Needed a single revision".to_string())),
+                }
+            } else {
+                revs.iter()
+                    .map(|rev| repo.rev_parse(rev))
+                    .collect::<Result<Vec<_>>>()?
+            };
+
+            for hash in hashes {
+                match short {
+                    Some(min_len) => println!("{}", repo.abbreviate(&hash, min_len)?),
+                    None => println!("{}", hash),
                 }
             }
-        },
+        }
 
         CliCommand::WriteTree => {
-            let hash = write_tree("./");
-            println!("{}", hash.hash);
+            let hash = repo.write_tree()?;
+            println!("{}", hash);
         }
 
-        CliCommand::CommitTree {
-            parent_hash,
-            message,
-            ..
+        CliCommand::Add { paths } => repo.add(&paths)?,
+
+        CliCommand::Rm {
+            cached,
+            recursive,
+            force,
+            paths,
         } => {
-            // commit <size>\0tree <tree_sha>
-            // parent <parent_sha>
-            // author <name> <<email>> <timestamp> <timezone>
-            // committer <name> <<email>> <timestamp> <timezone>
-            //
-            // <commit message>
+            for path in repo.remove(&paths, cached, recursive, force)? {
+                println!("rm '{}'", path);
+            }
+        }
 
-            let mut suffix = vec![];
+        CliCommand::Commit {
+            message,
+            file,
+            amend,
+            allow_empty,
+            quiet,
+        } => {
+            let message = match (&file, message.is_empty()) {
+                (Some(file), _) => {
+                    Some(String::from_utf8_lossy(&read_message_file(file)?).to_string())
+                }
+                (None, false) => Some(message.join("\n\n")),
+                (None, true) if amend => None,
+                (None, true) => {
+                    return Err(Error::Usage(
+                        "a commit message is required, use -m or -F".to_string(),
+                    ));
+                }
+            };
 
-            let tree_hash = write_tree("./");
-            suffix.extend_from_slice(b"tree ");
-            suffix.extend_from_slice(tree_hash.hash.as_bytes());
-            suffix.push(b'\n');
+            let branch = refs::read_symbolic(repo.git_dir(), "HEAD")?;
+            let options = CommitOptions { amend, allow_empty };
+            let Some(id) = repo.commit(message.as_deref(), &options)? else {
+                println!("nothing to commit, working tree clean");
+                std::process::exit(1);
+            };
 
-            suffix.extend_from_slice(b"parent ");
-            suffix.extend_from_slice(parent_hash.as_bytes());
-            suffix.push(b'\n');
+            if !quiet {
+                let Object::Commit { commit } = repo.read_object(&id)? else {
+                    unreachable!("just committed");
+                };
+                let subject = String::from_utf8_lossy(&commit.message);
+                println!(
+                    "[{}{} {}] {}",
+                    branch.as_deref().map_or("detached HEAD", refs::shorten),
+                    match commit.parents.is_empty() {
+                        true => " (root-commit)",
+                        false => "",
+                    },
+                    repo.abbreviate(&id, 7)?,
+                    subject.lines().next().unwrap_or_default()
+                );
+            }
+        }
 
-            suffix.extend_from_slice(b"author John Doe <john@example.com> 1234567890 +0000\n");
-            suffix.extend_from_slice(b"committer John Doe <john@example.com> 1234567890 +0000\n\n");
+        CliCommand::CommitTree {
+            tree,
+            parents,
+            message_sources,
+            ..
+        } => {
+            println!("{}", commit_tree(repo, &tree, &parents, &message_sources)?);
+        }
 
-            suffix.extend_from_slice(message.as_bytes());
-            suffix.push(b'\n');
+        CliCommand::Tag {
+            annotate,
+            force,
+            name,
+            object_hash,
+            message,
+        } => {
+            if annotate && message.is_none() {
+                return Err(Error::Usage("annotated tags require a message".to_string()));
+            }
 
-            let mut content = format!("commit {}\0", suffix.len()).as_bytes().to_vec();
+            let object_hash = object_hash
+                .map(|object| repo.rev_parse(&object))
+                .transpose()?;
+            let old = refs::resolve(repo.git_dir(), &format!("refs/tags/{}", name))?;
+            let hash = repo.create_tag(&name, object_hash, message.as_deref(), force)?;
+            if let Some(old) = old.filter(|old| *old != hash) {
+                println!("Updated tag '{}' (was {})", name, repo.abbreviate(&old, 7)?);
+            }
+        }
 
-            content.append(&mut suffix);
+        CliCommand::UpdateRef {
+            delete,
+            no_deref,
+            message,
+            name,
+            values,
+        } => {
+            let (new, old) = match (delete, values.as_slice()) {
+                (true, []) => (None, None),
+                (true, [old]) => (None, Some(old)),
+                (false, [new]) => (Some(new), None),
+                (false, [new, old]) => (Some(new), Some(old)),
+                _ => {
+                    return Err(Error::Usage(
+                        "usage: git update-ref [<options>] -d <refname> [<old-val>]\n   \
+                         or: git update-ref [<options>]    <refname> <new-val> [<old-val>]"
+                            .to_string(),
+                    ));
+                }
+            };
 
-            let hash = write_object_payload_to_file(&content[..]);
-            println!("{}", hash.hash);
+            let update = repo.resolve_ref_update(
+                &name,
+                new.map(String::as_str),
+                old.map(String::as_str),
+                no_deref,
+            )?;
+            let applied = repo.apply_ref_update(&update, &message);
+            if let Err(err) = &applied
+                && delete
+            {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+            applied?;
         }
 
-        CliCommand::Clone { url, dir } => {
-            let client = reqwest::blocking::Client::new();
-            let get_head_sha_url = format!(
-                "{}{}",
-                url.trim_end_matches('/'),
-                "/info/refs?service=git-upload-pack"
-            );
-            let response = client.get(get_head_sha_url).send().unwrap();
-            let response_body = response.text().unwrap();
-            let lines = response_body.lines().collect::<Vec<_>>();
-            let sha1_head_str = lines[1][8..48].to_string();
-            let want_content = format!(
-                "want {} multi_ack_detailed thin-pack side-band-64k ofs-delta\n",
-                sha1_head_str
-            );
-            let want_payload = format!("{}00000009done\n", hex_len_prefixed_string(&want_content));
-            let want_url = format!("{}{}", url.trim_end_matches('/'), "/git-upload-pack");
-
-            let mut response = client
-                .post(&want_url)
-                .header("Content-Type", "application/x-git-upload-pack-request")
-                .header("Accept", "application/x-git-upload-pack-result")
-                .body(want_payload)
-                .send()
-                .unwrap();
+        CliCommand::SymbolicRef {
+            quiet,
+            delete,
+            short,
+            name,
+            target,
+        } => {
+            let git_dir = repo.git_dir();
+            if let Some(target) = target {
+                if name == "HEAD" && !target.starts_with("refs/") {
+                    return Err(Error::Other(
+                        "Refusing to point HEAD outside of refs/".to_string(),
+                    ));
+                }
+                if !refs::is_valid_name(&target) {
+                    return Err(Error::Other(format!(
+                        "Refusing to set '{}' to invalid ref '{}'",
+                        name, target
+                    )));
+                }
+                return refs::write_symbolic(git_dir, &name, &target);
+            }
 
-            let mut buf = Vec::new();
-            response.read_to_end(&mut buf).unwrap();
+            if delete && name == "HEAD" {
+                return Err(Error::Other("deleting 'HEAD' is not allowed".to_string()));
+            }
+            if refs::read_symbolic(git_dir, &name)?.is_none() {
+                match (quiet, delete) {
+                    (true, _) => std::process::exit(1),
+                    (false, true) => {
+                        return Err(Error::Other(format!(
+                            "Cannot delete {}, not a symbolic ref",
+                            name
+                        )));
+                    }
+                    (false, false) => {
+                        return Err(Error::Other(format!("ref {} is not a symbolic ref", name)));
+                    }
+                }
+            }
 
-            let pack = parse_git_upload_pack_response(buf);
-            let objects = PackReader::new(&pack[..]).read();
-            clone_repo(&dir, objects);
+            if delete {
+                repo.delete_ref(&name, None, "")?;
+            } else {
+                let target = refs::resolve_symbolic(git_dir, &name)?;
+                match short {
+                    true => println!("{}", refs::shorten(&target)),
+                    false => println!("{}", target),
+                }
+            }
         }
-    }
-}
 
-fn git_init() {
-    fs::create_dir(".git").unwrap();
-    fs::create_dir(".git/objects").unwrap();
-    fs::create_dir(".git/refs").unwrap();
-    fs::write(".git/HEAD", "ref: refs/heads/main\n").unwrap();
-
-    info!("Initialized git directory")
-}
-
-fn parse_git_upload_pack_response(buf: Vec<u8>) -> Vec<u8> {
-    let mut lines: Vec<Vec<u8>> = vec![];
-    let mut slice = &buf[..];
-    let mut pack = Vec::new();
+        CliCommand::ShowRef {
+            head,
+            dereference,
+            hash,
+            tags,
+            heads,
+            verify,
+            quiet,
+            patterns,
+        } => {
+            if verify {
+                for pattern in &patterns {
+                    match repo.exact_ref(pattern)? {
+                        Some(found) => show_ref(repo, &found, dereference, hash, quiet)?,
+                        None if quiet => std::process::exit(1),
+                        None => {
+                            return Err(Error::Other(format!("'{}' - not a valid ref", pattern)));
+                        }
+                    }
+                }
+                return Ok(());
+            }
 
-    loop {
-        if slice.is_empty() {
-            break;
+            let shown = repo.show_refs(&ShowRefOptions {
+                head,
+                heads,
+                tags,
+                patterns,
+            })?;
+            if shown.is_empty() {
+                std::process::exit(1);
+            }
+            for found in &shown {
+                show_ref(repo, found, dereference, hash, quiet)?;
+            }
         }
 
-        let len_str = str::from_utf8(&slice[..4]).unwrap();
-        let len = usize::from_str_radix(len_str, 16).unwrap();
-        if len == 0 {
-            break;
+        CliCommand::Reflog { command, reference } => {
+            match command.unwrap_or(ReflogCommand::Show { reference }) {
+                ReflogCommand::Show { reference } => {
+                    reflog_show(repo, reference.as_deref().unwrap_or("HEAD"))?
+                }
+                ReflogCommand::Expire { expire, all, refs } => {
+                    reflog::expire(repo.git_dir(), &expire, all, &refs)?
+                }
+                ReflogCommand::Delete { rewrite, entries } => {
+                    reflog::delete_entries(repo.git_dir(), &entries, rewrite)?
+                }
+                ReflogCommand::Exists { reference } => {
+                    if !reflog::exists(repo.git_dir(), &reference) {
+                        std::process::exit(1);
+                    }
+                }
+            }
         }
 
-        let line = slice[4..len].to_vec();
+        CliCommand::ForEachRef {
+            format,
+            sort,
+            count,
+            patterns,
+        } => {
+            let format = refs::parse_format(&format)?;
+            let sort = match sort.is_empty() {
+                true => vec!["refname".to_string()],
+                false => sort,
+            };
+            // Keys are parsed up front so that an unknown one fails even without refs.
+            let sort_keys = sort
+                .iter()
+                .map(|key| SortKey::parse(key))
+                .collect::<Result<Vec<_>>>()?;
 
-        match line[0] {
-            1 => {
-                // Data.
-                pack.extend_from_slice(&line[1..]);
+            for found in repo
+                .for_each_ref(&patterns, &sort_keys)?
+                .iter()
+                .take(count.unwrap_or(usize::MAX))
+            {
+                let mut line = repo.format_ref(&format, found)?;
+                line.push(b'\n');
+                write_stdout(&line)?;
             }
-            2 => {
-                // Progress messages.
-                let _progress_msg = String::from_utf8(line[1..].to_vec()).unwrap();
-            }
-            3 => {
-                unimplemented!("Error line not implemented");
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers one object name per `input` line with `<id> <type> <size>`, followed by the content
+/// and a newline when `with_content`. Unknown names get `<name> missing`, short ids matching
+/// several objects `<name> ambiguous`.
+fn cat_file_batch(
+    repo: &Repository,
+    input: impl BufRead,
+    stdout: &mut impl Write,
+    with_content: bool,
+) -> Result<()> {
+    let stdout_error = |source| Error::Io {
+        path: "<stdout>".into(),
+        source,
+    };
+
+    for line in input.lines() {
+        let line = line.map_err(|source| Error::Io {
+            path: "<stdin>".into(),
+            source,
+        })?;
+        let name = line.trim();
+        let object = match repo.rev_parse(name) {
+            Ok(hash) => repo
+                .read_raw_object(&hash)?
+                .map(|(kind, content)| (hash, kind, content)),
+            Err(Error::AmbiguousObject(_)) => {
+                writeln!(stdout, "{} ambiguous", name).map_err(stdout_error)?;
+                stdout.flush().map_err(stdout_error)?;
+                continue;
             }
-            other => {
-                let msg = String::from_utf8(line.clone()).unwrap();
-                warn!("Unknown line type {} -> message = {}", other, msg);
+            Err(err @ Error::Io { .. }) => return Err(err),
+            Err(_) => None,
+        };
+
+        match object {
+            Some((hash, kind, content)) => {
+                writeln!(stdout, "{} {} {}", hash, kind.to_string(), content.len())
+                    .map_err(stdout_error)?;
+                if with_content {
+                    stdout.write_all(&content).map_err(stdout_error)?;
+                    stdout.write_all(b"\n").map_err(stdout_error)?;
+                }
             }
+            None => writeln!(stdout, "{} missing", name).map_err(stdout_error)?,
         }
 
-        lines.push(line);
-        slice = &slice[len..];
+        // Callers usually wait for each answer before sending the next name.
+        stdout.flush().map_err(stdout_error)?;
     }
 
-    // dbg!(lines);
-    pack
+    Ok(())
+}
+
+fn write_stdout(bytes: &[u8]) -> Result<()> {
+    std::io::stdout()
+        .write_all(bytes)
+        .map_err(|source| Error::Io {
+            path: "<stdout>".into(),
+            source,
+        })
+}
+
+struct LsTreeOptions {
+    recursive: bool,
+    show_trees: bool,
+    trees_only: bool,
+    long: bool,
+    nul_terminated: bool,
+    name_only: bool,
+    /// Paths from the top of the work tree, a trailing `/` selecting only what is below.
+    paths: Vec<String>,
+    /// Output paths are relative to this directory, see `Repository::prefix`.
+    cwd_prefix: String,
 }
 
-fn write_object_payload_to_file(payload: &[u8]) -> Hash {
-    let mut hasher = Sha1::new();
-    hasher.update(&payload);
-    let hash = Hash::new(bytes_to_string(&hasher.finalize()));
+/// Lists the entries of `tree`, whose path is `prefix` (empty or ending with `/`).
+fn ls_tree(
+    repo: &Repository,
+    tree: &ObjectId,
+    prefix: &[u8],
+    options: &LsTreeOptions,
+) -> Result<()> {
+    let Object::Tree { entries } = repo.read_object(tree)? else {
+        return Err(Error::WrongObjectType {
+            id: tree.to_string(),
+            expected: "tree",
+        });
+    };
 
-    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(&payload).unwrap();
-    let content_encoded = encoder.finish().unwrap();
+    for entry in entries {
+        let path = [prefix, &entry.filename].concat();
+        let dir_prefix = [&path[..], b"/"].concat();
 
-    hash.write_content(&content_encoded[..]);
+        // A path filter selects itself and everything under it, and leads into the trees above.
+        let matched = options.paths.is_empty()
+            || options.paths.iter().any(|filter| {
+                filter.is_empty()
+                    || filter.as_bytes() == path
+                    || path.starts_with(format!("{}/", filter.trim_end_matches('/')).as_bytes())
+            });
+        let leads_below = options
+            .paths
+            .iter()
+            .any(|filter| filter.as_bytes().starts_with(&dir_prefix));
 
-    hash
+        let is_tree = entry.mode == FileMode::Tree;
+        let descend = is_tree && ((options.recursive && matched) || leads_below);
+        let show = if descend {
+            options.show_trees || (options.trees_only && options.recursive)
+        } else {
+            matched && (entry.mode.kind() != PackObjectType::Blob || !options.trees_only)
+        };
+
+        if show {
+            print_ls_tree_entry(repo, &entry, &path, options)?;
+        }
+        if descend {
+            ls_tree(repo, &entry.hash, &dir_prefix, options)?;
+        }
+    }
+
+    Ok(())
 }
 
-fn write_object_file_from_file(file_path: &str) -> Hash {
-    write_object_payload_to_file(
-        &create_object_blob_payload_from_file(file_path, PackObjectType::Blob)[..],
-    )
+/// `path` from the top of the work tree as seen from the directory `prefix` (empty or ending with
+/// `/`), `./` for `prefix` itself.
+fn relative_path(path: &[u8], prefix: &str) -> Vec<u8> {
+    let mut path_components = path.split(|&c| c == b'/').peekable();
+    let mut prefix_components = prefix.split_terminator('/').peekable();
+    while let (Some(a), Some(b)) = (path_components.peek(), prefix_components.peek())
+        && *a == b.as_bytes()
+    {
+        path_components.next();
+        prefix_components.next();
+    }
+
+    let mut relative = b"../".repeat(prefix_components.count());
+    for component in path_components.filter(|component| !component.is_empty()) {
+        relative.extend_from_slice(component);
+        relative.push(b'/');
+    }
+    match relative.is_empty() {
+        true => b"./".to_vec(),
+        false if relative.ends_with(b"../") => relative,
+        false => relative[..relative.len() - 1].to_vec(),
+    }
 }
 
-fn write_tree(dir: &str) -> Hash {
-    let mut folder_entries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+fn print_ls_tree_entry(
+    repo: &Repository,
+    entry: &TreeEntry,
+    path: &[u8],
+    options: &LsTreeOptions,
+) -> Result<()> {
+    let path = relative_path(path, &options.cwd_prefix);
+    let path = if options.nul_terminated {
+        path
+    } else {
+        quote_path(&path).into_bytes()
+    };
+
+    let mut line = if options.name_only {
+        vec![]
+    } else if options.long {
+        let size = match entry.mode.kind() {
+            PackObjectType::Blob => repo
+                .read_raw_object(&entry.hash)?
+                .ok_or_else(|| Error::ObjectNotFound(entry.hash.to_string()))?
+                .1
+                .len()
+                .to_string(),
+            _ => "-".to_string(),
+        };
+        format!(
+            "{} {} {} {:>7}\t",
+            entry.mode,
+            entry.mode.kind().to_string(),
+            entry.hash,
+            size,
+        )
+        .into_bytes()
+    } else {
+        format!(
+            "{} {} {}\t",
+            entry.mode,
+            entry.mode.kind().to_string(),
+            entry.hash,
+        )
+        .into_bytes()
+    };
+    line.extend_from_slice(&path);
+    line.push(if options.nul_terminated { b'\0' } else { b'\n' });
+
+    write_stdout(&line)
+}
 
-    for entry in fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.file_name().unwrap().to_string_lossy() == ".git" {
+/// Writes the commit of `git commit-tree`: duplicated parents are reported and skipped, and the
+/// message is made of `message_sources` in order, or read from the standard input without any.
+fn commit_tree(
+    repo: &Repository,
+    tree: &str,
+    parents: &[String],
+    message_sources: &[MessageSource],
+) -> Result<ObjectId> {
+    let tree = repo
+        .rev_parse(tree)
+        .map_err(|_| Error::Other(format!("not a valid object name {}", tree)))?;
+    let mut parent_ids: Vec<ObjectId> = vec![];
+    for parent in parents {
+        let id = repo
+            .rev_parse(parent)
+            .map_err(|_| Error::Other(format!("not a valid object name {}", parent)))?;
+        if parent_ids.contains(&id) {
+            eprintln!("error: duplicate parent {} ignored", id);
             continue;
         }
+        parent_ids.push(id);
+    }
 
-        let metadata = fs::metadata(&path).unwrap();
-
-        let mut bytes = vec![];
+    // Like git, `-m` completes its line and paragraphs are separated by a blank line, while
+    // files and the standard input are taken as they are.
+    let message = match message_sources.is_empty() {
+        true => read_message_file(Path::new("-"))?,
+        false => {
+            let mut message = vec![];
+            for source in message_sources {
+                if !message.is_empty() {
+                    message.push(b'\n');
+                }
+                match source {
+                    MessageSource::Message(text) => {
+                        message.extend_from_slice(text.as_bytes());
+                        if !message.is_empty() && !message.ends_with(b"\n") {
+                            message.push(b'\n');
+                        }
+                    }
+                    MessageSource::File(path) => message.extend(read_message_file(path)?),
+                }
+            }
+            message
+        }
+    };
 
-        let bytes = if metadata.is_dir() {
-            let hash = write_tree(&path.to_string_lossy());
+    repo.commit_tree(&tree, &parent_ids, &message)
+}
 
-            bytes.extend_from_slice(b"40000 ");
-            bytes.extend_from_slice(path.file_name().unwrap().to_string_lossy().as_bytes());
-            bytes.push(0);
-            bytes.extend_from_slice(&hash.as_bytes());
-            bytes
-        } else {
-            let hash = write_object_file_from_file(&path.to_string_lossy());
+/// Reads a commit message from a file, or from the standard input for `-`.
+fn read_message_file(path: &Path) -> Result<Vec<u8>> {
+    let mut message = vec![];
+    let read = match path == Path::new("-") {
+        true => std::io::stdin().lock().read_to_end(&mut message),
+        false => std::fs::File::open(path).and_then(|mut file| file.read_to_end(&mut message)),
+    };
+    read.map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(message)
+}
 
-            bytes.extend_from_slice(b"100644 ");
-            bytes.extend_from_slice(path.file_name().unwrap().to_string_lossy().as_bytes());
-            bytes.push(0);
-            bytes.extend_from_slice(&hash.as_bytes());
-            bytes
-        };
+/// Prints the reflog of a ref newest first, as `<id> <name>@{<n>}: <message>`.
+fn reflog_show(repo: &Repository, name: &str) -> Result<()> {
+    let full_name = reflog::ref_name(repo.git_dir(), name)?;
+    let entries = reflog::read(repo.git_dir(), &full_name)?.unwrap_or_default();
 
-        folder_entries.insert(
-            path.file_name().unwrap().to_string_lossy().to_string(),
-            bytes,
+    for (i, entry) in entries.iter().rev().enumerate() {
+        println!(
+            "{} {}@{{{}}}: {}",
+            repo.abbreviate(&entry.new, 7)?,
+            name,
+            i,
+            entry.message
         );
     }
+    Ok(())
+}
 
-    let mut entries = folder_entries
-        .into_values()
-        .flat_map(|e| e)
-        .collect::<Vec<_>>();
+/// Prints a `show-ref` line, followed by the object an annotated tag points at when
+/// `dereference`.
+fn show_ref(
+    repo: &Repository,
+    found: &Ref,
+    dereference: bool,
+    hash_only: bool,
+    quiet: bool,
+) -> Result<()> {
+    if quiet {
+        return Ok(());
+    }
 
-    let mut bytes = format!("tree {}\0", entries.len()).as_bytes().to_vec();
-    bytes.append(&mut entries);
+    let print = |id: &ObjectId, name: &str| match hash_only {
+        true => println!("{}", id),
+        false => println!("{} {}", id, name),
+    };
+    print(&found.id, &found.name);
 
-    write_object_payload_to_file(&bytes[..])
+    if dereference && let Some(peeled) = repo.peeled_tag(found)? {
+        print(&peeled, &format!("{}^{{}}", found.name));
+    }
+    Ok(())
 }
 
-fn materialize_entity(entity: Entry, path: &str) {
-    match entity {
-        Entry::File { content } => {
-            let folder_path = std::path::Path::new(&path)
-                .parent()
-                .unwrap()
-                .to_string_lossy();
-            std::fs::create_dir_all(folder_path.as_ref()).unwrap();
-            std::fs::write(&path, content).expect(&format!(
-                "Failed writing file to: {} in folder: {}",
-                path, folder_path
-            ));
+/// Quotes a path the way git does by default (`core.quotePath`): paths with control characters,
+/// quotes, backslashes or non-ASCII bytes are C-quoted, with octal escapes for the bytes.
+fn quote_path(path: &[u8]) -> String {
+    let needs_quoting = path
+        .iter()
+        .any(|&byte| !(0x20..0x7f).contains(&byte) || byte == b'"' || byte == b'\\');
+    if !needs_quoting {
+        return String::from_utf8_lossy(path).to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for &byte in path {
+        match byte {
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            0x0b => quoted.push_str("\\v"),
+            0x0c => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            byte if !(0x20..0x7f).contains(&byte) => quoted.push_str(&format!("\\{:03o}", byte)),
+            byte => quoted.push(byte as char),
         }
-        Entry::Tree {
-            entries: subtree_entries,
-        } => {
-            for tree_entry in subtree_entries {
-                let tree_entry_path = format!("{}/{}", path, tree_entry.filename);
-                materialize_entity(tree_entry.read(), &tree_entry_path);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test_util;
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        Args, CliCommand, cat_file_batch, commit_tree, parse_args, quote_path, relative_path,
+        test_util::TempDir,
+    };
+    use clap::CommandFactory;
+    use codecrafters_git::{InitOptions, PackObjectType, Repository};
+    use std::{
+        collections::HashMap,
+        env,
+        ffi::OsString,
+        fs,
+        sync::{Mutex, MutexGuard, PoisonError},
+    };
+
+    /// Held by the tests that read or change the environment, which all tests share.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Environment variables set until it is dropped, when their previous values come back.
+    struct EnvGuard {
+        saved: Vec<(&'static str, Option<OsString>)>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl EnvGuard {
+        fn set(vars: &[(&'static str, &str)]) -> Self {
+            let lock = lock_env();
+            let saved = vars
+                .iter()
+                .map(|(key, _)| (*key, env::var_os(key)))
+                .collect();
+            for (key, value) in vars {
+                // SAFETY: the tests of this binary only touch the environment under ENV_LOCK.
+                unsafe { env::set_var(key, value) };
+            }
+            Self { saved, _lock: lock }
+        }
+    }
+
+    impl Drop for EnvGuard {
+        fn drop(&mut self) {
+            for (key, value) in &self.saved {
+                // SAFETY: ENV_LOCK is still held, see `EnvGuard::set`.
+                unsafe {
+                    match value {
+                        Some(value) => env::set_var(key, value),
+                        None => env::remove_var(key),
+                    }
+                }
             }
         }
     }
-}
 
-fn clone_repo(dir: &str, objects: Vec<PackObject>) {
-    std::fs::create_dir_all(dir).unwrap();
-    std::env::set_current_dir(dir).unwrap();
+    #[test]
+    fn test_commit_tree_matches_git() {
+        let _env = EnvGuard::set(&[
+            ("GIT_AUTHOR_NAME", "A"),
+            ("GIT_AUTHOR_EMAIL", "a@x"),
+            ("GIT_AUTHOR_DATE", "1700000000 +0200"),
+            ("GIT_COMMITTER_NAME", "C"),
+            ("GIT_COMMITTER_EMAIL", "c@x"),
+            ("GIT_COMMITTER_DATE", "1700000001 -0130"),
+        ]);
+        let dir = TempDir::new("commit_tree");
+        let initialized = Repository::init(&dir, &InitOptions::default()).unwrap();
+        let repo = Repository::open(&initialized.git_dir, Some(&dir)).unwrap();
+        let message_file = dir.join("message");
+        fs::write(&message_file, "from file").unwrap();
+        let message_file = message_file.to_str().unwrap();
 
-    git_init();
+        let commit_tree_with = |args: &[&str]| {
+            let matches = Args::command()
+                .try_get_matches_from(["git", "commit-tree"].iter().chain(args))
+                .unwrap();
+            let CliCommand::CommitTree {
+                tree,
+                parents,
+                message_sources,
+                ..
+            } = parse_args(&matches).unwrap().command
+            else {
+                unreachable!();
+            };
+            commit_tree(&repo, &tree, &parents, &message_sources)
+                .unwrap()
+                .to_string()
+        };
 
-    let mut tree_hashes = vec![];
+        let empty_tree = repo
+            .write_object(PackObjectType::Tree, b"")
+            .unwrap()
+            .to_string();
+        let empty_tree = empty_tree.as_str();
 
-    // Create objects.
-    for object in &objects {
-        let hash = write_object_payload_to_file(
-            &create_object_payload_from_content(&object.decompressed_payload[..], object.kind)[..],
+        // The ids stock git gives with the same identities and dates.
+        let root = commit_tree_with(&[empty_tree, "-m", "root"]);
+        assert_eq!("11cfd30a967b17a6b47ee22149a7b753c64539ab", root);
+        assert_eq!(
+            "660385957a408aa0eb2dff5d84d9c6da7d225c9f",
+            commit_tree_with(&[
+                empty_tree,
+                "-p",
+                &root,
+                "-p",
+                &root[..7],
+                "-m",
+                "first",
+                "-F",
+                message_file,
+                "-m",
+                "third",
+            ])
         );
+        assert_eq!(
+            "721781dfe9d5f8f8bf7c7ebb990ae50e012939c0",
+            commit_tree_with(&[
+                empty_tree,
+                "-p",
+                &root,
+                "-F",
+                message_file,
+                "-m",
+                "second",
+                "-F",
+                message_file,
+            ])
+        );
+    }
 
-        if object.kind == PackObjectType::Tree && tree_hashes.is_empty() {
-            tree_hashes.push(hash);
-        }
+    #[test]
+    fn test_cat_file_batch() {
+        // `Repository::init` reads GIT_TEMPLATE_DIR.
+        let _env = lock_env();
+        let dir = TempDir::new("cat_file");
+        let initialized = Repository::init(&dir, &InitOptions::default()).unwrap();
+        let repo = Repository::open(&initialized.git_dir, Some(&dir)).unwrap();
+
+        let hello = repo.write_object(PackObjectType::Blob, b"hello\n").unwrap();
+        // Blobs until two of them share their first four hex digits.
+        let mut by_prefix = HashMap::new();
+        let ambiguous_prefix = (0..)
+            .find_map(|i| {
+                let id = repo
+                    .write_object(PackObjectType::Blob, format!("blob {}", i).as_bytes())
+                    .unwrap()
+                    .to_string();
+                by_prefix.insert(id[..4].to_string(), id.clone())?;
+                Some(id[..4].to_string())
+            })
+            .unwrap();
+
+        let input = format!(
+            "{}\n{}\n{}\n{}\n",
+            hello,
+            &hello.to_string()[..7],
+            ambiguous_prefix,
+            "does-not-exist"
+        );
+
+        let mut output = vec![];
+        cat_file_batch(&repo, input.as_bytes(), &mut output, false).unwrap();
+        assert_eq!(
+            format!(
+                "{hello} blob 6\n{hello} blob 6\n{ambiguous_prefix} ambiguous\n\
+                 does-not-exist missing\n"
+            ),
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut output = vec![];
+        cat_file_batch(&repo, input.as_bytes(), &mut output, true).unwrap();
+        assert_eq!(
+            format!(
+                "{hello} blob 6\nhello\n\n{hello} blob 6\nhello\n\n\
+                 {ambiguous_prefix} ambiguous\ndoes-not-exist missing\n"
+            ),
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!("src/main.rs", quote_path(b"src/main.rs"));
+        assert_eq!("\"tab\\there\"", quote_path(b"tab\there"));
+        assert_eq!("\"caf\\303\\251\"", quote_path("café".as_bytes()));
+        assert_eq!("\"caf\\351\"", quote_path(b"caf\xe9"));
+        assert_eq!("\"a\\\"b\\\\c\"", quote_path(b"a\"b\\c"));
     }
 
-    // Expand tree.
-    if let Some(tree_hash) = tree_hashes.first() {
-        materialize_entity(tree_hash.read(), ".");
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(b"src/main.rs".to_vec(), relative_path(b"src/main.rs", ""));
+        assert_eq!(b"main.rs".to_vec(), relative_path(b"src/main.rs", "src/"));
+        assert_eq!(b"./".to_vec(), relative_path(b"src", "src/"));
+        assert_eq!(b"../README".to_vec(), relative_path(b"README", "src/"));
+        assert_eq!(b"../../".to_vec(), relative_path(b"src", "src/a/b/"));
+        assert_eq!(b"../b/c".to_vec(), relative_path(b"a/b/c", "a/d/"));
     }
 }
@@ -0,0 +1,324 @@
+use sha1::Sha1;
+use sha1collisiondetection::Sha1CD;
+use sha2::{Digest, Sha256};
+use std::{fmt, io, str::FromStr};
+
+use crate::error::{Error, Result};
+
+/// The hash function naming the objects of a repository, `extensions.objectformat` in its
+/// config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObjectFormat {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectFormat::Sha1 => "sha1",
+            ObjectFormat::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha1" => Some(ObjectFormat::Sha1),
+            "sha256" => Some(ObjectFormat::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Length of ids in bytes, which is also the length of pack and index checksums.
+    pub fn id_len(self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 20,
+            ObjectFormat::Sha256 => 32,
+        }
+    }
+
+    pub fn hex_len(self) -> usize {
+        self.id_len() * 2
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            ObjectFormat::Sha1 => Hasher::Sha1(Sha1::new()),
+            ObjectFormat::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    /// Hashes a checksum (of a pack or an index file), these are not checked for collisions.
+    pub fn hash(self, data: &[u8]) -> ObjectId {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Hashes an object payload (header included) into its id. SHA-1 input showing the
+    /// patterns of a known collision attack, like SHAttered, is refused as upstream git does.
+    pub fn hash_object(self, payload: &[u8]) -> Result<ObjectId> {
+        match self {
+            ObjectFormat::Sha1 => {
+                let mut hasher = Sha1CD::default();
+                hasher.update(payload);
+                let mut digest = Default::default();
+                match hasher.finalize_into_dirty_cd(&mut digest) {
+                    Ok(()) => Ok(ObjectId::Sha1(digest.into())),
+                    Err(_) => Err(Error::HashCollision(
+                        ObjectId::Sha1(digest.into()).to_string(),
+                    )),
+                }
+            }
+            ObjectFormat::Sha256 => Ok(self.hash(payload)),
+        }
+    }
+}
+
+/// Incremental hashing with the hash function of an object format.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> ObjectId {
+        match self {
+            Hasher::Sha1(hasher) => ObjectId::Sha1(hasher.finalize().into()),
+            Hasher::Sha256(hasher) => ObjectId::Sha256(hasher.finalize().into()),
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The hash naming an object, in the format of its repository.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ObjectId {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+
+impl ObjectId {
+    /// None unless `bytes` is as long as the ids of one of the formats.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() {
+            20 => Some(ObjectId::Sha1(bytes.try_into().unwrap())),
+            32 => Some(ObjectId::Sha256(bytes.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    /// Parses a full hex id of any format, upper or lower case.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.as_bytes();
+        let mut bytes = [0u8; 32];
+        if !hex.len().is_multiple_of(2) || hex.len() > bytes.len() * 2 {
+            return None;
+        }
+
+        for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+            *byte = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+        }
+        Self::from_slice(&bytes[..hex.len() / 2])
+    }
+
+    /// The id made of zeros, used where git means "no object".
+    pub fn null(format: ObjectFormat) -> Self {
+        match format {
+            ObjectFormat::Sha1 => ObjectId::Sha1([0; 20]),
+            ObjectFormat::Sha256 => ObjectId::Sha256([0; 32]),
+        }
+    }
+
+    /// Whether this is the id made of zeros.
+    pub fn is_null(&self) -> bool {
+        self.as_bytes().iter().all(|&byte| byte == 0)
+    }
+
+    pub fn format(&self) -> ObjectFormat {
+        match self {
+            ObjectId::Sha1(_) => ObjectFormat::Sha1,
+            ObjectId::Sha256(_) => ObjectFormat::Sha256,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            ObjectId::Sha1(bytes) => bytes,
+            ObjectId::Sha256(bytes) => bytes,
+        }
+    }
+
+    /// Whether the lowercase hex form of the id starts with `prefix`.
+    pub fn starts_with_hex(&self, prefix: &str) -> bool {
+        prefix.len() <= self.format().hex_len()
+            && prefix.bytes().enumerate().all(|(i, digit)| {
+                hex_digit(digit) == Some(self.nibble(i)) && !digit.is_ascii_uppercase()
+            })
+    }
+
+    fn nibble(&self, i: usize) -> u8 {
+        let byte = self.as_bytes()[i / 2];
+        match i % 2 {
+            0 => byte >> 4,
+            _ => byte & 0xf,
+        }
+    }
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+impl fmt::Display for ObjectId {
+    /// Lowercase hex, a precision abbreviates it: `format!("{:.7}", id)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+        let mut hex = [0u8; 64];
+        let hex_len = self.format().hex_len();
+        for (i, digit) in hex[..hex_len].iter_mut().enumerate() {
+            *digit = DIGITS[self.nibble(i) as usize];
+        }
+        f.pad(str::from_utf8(&hex[..hex_len]).unwrap())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({})", self)
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = Error;
+
+    fn from_str(hex: &str) -> Result<Self> {
+        Self::from_hex(hex).ok_or_else(|| Error::InvalidObjectId(hex.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::Error,
+        object_id::{ObjectFormat, ObjectId},
+    };
+
+    const HEX: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+
+    /// The start of shattered-1.pdf (https://shattered.io), up to the end of its two near-collision
+    /// blocks.
+    const SHATTERED_PREFIX: &str = "\
+         255044462d312e330a25e2e3cfd30a0a0a312030206f626a0a3c3c2f57696474682032203020522f\
+         4865696768742033203020522f547970652034203020522f537562747970652035203020522f4669\
+         6c7465722036203020522f436f6c6f7253706163652037203020522f4c656e677468203820302052\
+         2f42697473506572436f6d706f6e656e7420383e3e0a73747265616d0affd8fffe00245348412d31\
+         20697320646561642121212121852fec092339759c39b1a1c63c4c97e1fffe017346dc9166b67e11\
+         8f029ab621b2560ff9ca67cca8c7f85ba84c79030c2b3de218f86db3a90901d5df45c14f26fedfb3\
+         dc38e96ac22fe7bd728f0e45bce046d23c570feb141398bb552ef5a0a82be331fea48037b8b5d71f\
+         0e332edf93ac3500eb4ddc0decc1a864790c782c76215660dd309791d06bd0af3f98cda4bc4629b1";
+
+    #[test]
+    fn test_hex_round_trip() {
+        let id: ObjectId = HEX.parse().unwrap();
+        assert_eq!(ObjectFormat::Sha1, id.format());
+        assert_eq!(0xce, id.as_bytes()[0]);
+        assert_eq!(0x4a, id.as_bytes()[19]);
+        assert_eq!(HEX, id.to_string());
+        assert_eq!("ce01362", format!("{:.7}", id));
+        assert_eq!(Some(id), ObjectId::from_hex(&HEX.to_uppercase()));
+        assert_eq!(Some(id), ObjectId::from_slice(id.as_bytes()));
+    }
+
+    #[test]
+    fn test_invalid_hex() {
+        assert!("".parse::<ObjectId>().is_err());
+        assert!(HEX[1..].parse::<ObjectId>().is_err());
+        assert!(HEX[2..].parse::<ObjectId>().is_err());
+        assert!(format!("{}0", HEX).parse::<ObjectId>().is_err());
+        assert!(HEX.replace('c', "g").parse::<ObjectId>().is_err());
+        assert_eq!(None, ObjectId::from_slice(&[0u8; 19]));
+    }
+
+    #[test]
+    fn test_starts_with_hex() {
+        let id: ObjectId = HEX.parse().unwrap();
+        assert!(id.starts_with_hex(""));
+        assert!(id.starts_with_hex("ce0"));
+        assert!(id.starts_with_hex(HEX));
+        assert!(!id.starts_with_hex("ce1"));
+        assert!(!id.starts_with_hex("CE0"));
+        assert!(!id.starts_with_hex(&format!("{}0", HEX)));
+    }
+
+    #[test]
+    fn test_ordering() {
+        let low = ObjectId::null(ObjectFormat::Sha1);
+        let high = ObjectId::Sha1([0xff; 20]);
+        assert!(low < high);
+        assert_eq!(
+            vec![low, high],
+            std::collections::BTreeSet::from([high, low])
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_sha256() {
+        // `printf 'blob 0\0' | sha256sum`, the empty blob of a sha256 repository.
+        let empty_blob = ObjectFormat::Sha256.hash(b"blob 0\0");
+        assert_eq!(
+            "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813",
+            empty_blob.to_string()
+        );
+        assert_eq!(
+            Some(empty_blob),
+            ObjectId::from_hex(&empty_blob.to_string())
+        );
+        assert_eq!(32, empty_blob.as_bytes().len());
+        assert_eq!(
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+            ObjectFormat::Sha1.hash(b"blob 0\0").to_string()
+        );
+    }
+
+    #[test]
+    fn test_hash_object_detects_collisions() {
+        let shattered = (0..SHATTERED_PREFIX.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&SHATTERED_PREFIX[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            ObjectFormat::Sha1.hash_object(&shattered),
+            Err(Error::HashCollision(_))
+        ));
+        assert!(ObjectFormat::Sha256.hash_object(&shattered).is_ok());
+
+        // Unaffected input hashes to the plain SHA-1.
+        assert_eq!(
+            ObjectFormat::Sha1.hash(b"blob 0\0"),
+            ObjectFormat::Sha1.hash_object(b"blob 0\0").unwrap()
+        );
+    }
+}
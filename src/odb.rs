@@ -0,0 +1,178 @@
+use flate2::read::ZlibDecoder;
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    common::Object,
+    error::{Error, IoResultExt, Result},
+    object_id::{ObjectFormat, ObjectId},
+    pack::{PackEntry, PackObjectType, apply_delta, read_pack_entry},
+    pack_index::PackIndex,
+    reader::Reader,
+};
+
+struct PackFile {
+    index: PackIndex,
+    pack: File,
+}
+
+impl PackFile {
+    fn read_at(&self, odb: &ObjectDatabase, offset: u64) -> Result<(PackObjectType, Vec<u8>)> {
+        match read_pack_entry(&self.pack, offset, odb.format)? {
+            PackEntry::Object { kind, payload } => Ok((kind, payload)),
+            PackEntry::OfsDelta { base_offset, delta } => {
+                let (kind, base) = self.read_at(odb, base_offset)?;
+                Ok((kind, apply_delta(&base[..], &delta[..])?))
+            }
+            PackEntry::RefDelta { base_id, delta } => {
+                let (kind, base) = odb
+                    .read_raw(&base_id)?
+                    .ok_or_else(|| Error::ObjectNotFound(base_id.to_string()))?;
+                Ok((kind, apply_delta(&base[..], &delta[..])?))
+            }
+        }
+    }
+}
+
+/// Every object of the repository, whether stored loose or in one of the packs.
+pub(crate) struct ObjectDatabase {
+    objects_dir: PathBuf,
+    packs: Vec<PackFile>,
+    format: ObjectFormat,
+}
+
+impl ObjectDatabase {
+    /// Opens the object store of a repository, `objects_dir` being its `objects` directory.
+    pub(crate) fn open(objects_dir: &Path, format: ObjectFormat) -> Result<Self> {
+        let mut packs = vec![];
+        let pack_dir = objects_dir.join("pack");
+
+        if let Ok(dir_entries) = fs::read_dir(&pack_dir) {
+            for dir_entry in dir_entries {
+                let idx_path = dir_entry.with_path(&pack_dir)?.path();
+                if idx_path
+                    .extension()
+                    .is_none_or(|extension| extension != "idx")
+                {
+                    continue;
+                }
+
+                let pack_path = idx_path.with_extension("pack");
+                if !pack_path.exists() {
+                    warn!("Pack index without pack: {}", idx_path.to_string_lossy());
+                    continue;
+                }
+
+                packs.push(PackFile {
+                    index: PackIndex::parse(fs::read(&idx_path).with_path(&idx_path)?, format)?,
+                    pack: File::open(&pack_path).with_path(&pack_path)?,
+                });
+            }
+        }
+
+        Ok(Self {
+            objects_dir: objects_dir.to_path_buf(),
+            packs,
+            format,
+        })
+    }
+
+    pub(crate) fn format(&self) -> ObjectFormat {
+        self.format
+    }
+
+    pub(crate) fn read_raw(&self, id: &ObjectId) -> Result<Option<(PackObjectType, Vec<u8>)>> {
+        let loose_path = self.loose_object_path(id);
+        if loose_path.exists() {
+            return read_loose(id, &loose_path).map(Some);
+        }
+
+        for pack in &self.packs {
+            if let Some(offset) = pack.index.find(id) {
+                return pack.read_at(self, offset).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Ids of all objects, loose or packed, starting with a hex prefix of at least two
+    /// characters.
+    pub(crate) fn ids_with_prefix(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let mut ids = vec![];
+
+        let folder_path = self.objects_dir.join(&prefix[..2]);
+        if let Ok(dir_entries) = fs::read_dir(&folder_path) {
+            for dir_entry in dir_entries {
+                let filename = dir_entry.with_path(&folder_path)?.file_name();
+                let id =
+                    ObjectId::from_hex(&format!("{}{}", &prefix[..2], filename.to_string_lossy()));
+                if let Some(id) = id.filter(|id| id.starts_with_hex(prefix)) {
+                    ids.push(id);
+                }
+            }
+        }
+
+        for pack in &self.packs {
+            ids.extend(pack.index.ids_with_prefix(prefix));
+        }
+
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    pub(crate) fn read(&self, id: &ObjectId) -> Result<Object> {
+        let (kind, content) = self
+            .read_raw(id)?
+            .ok_or_else(|| Error::ObjectNotFound(id.to_string()))?;
+        Object::parse(kind, &content[..], self.format)
+            .map_err(|reason| Error::corrupt_object(id, reason))
+    }
+
+    /// Whether the object is stored, loose or in a pack.
+    pub(crate) fn contains(&self, id: &ObjectId) -> bool {
+        self.loose_object_path(id).exists()
+            || self.packs.iter().any(|pack| pack.index.find(id).is_some())
+    }
+
+    /// Where the object is stored when it is loose: `objects/<2 hex digits>/<38 hex digits>`.
+    pub(crate) fn loose_object_path(&self, id: &ObjectId) -> PathBuf {
+        let hex = id.to_string();
+        self.objects_dir.join(&hex[..2]).join(&hex[2..])
+    }
+}
+
+fn read_loose(id: &ObjectId, loose_path: &Path) -> Result<(PackObjectType, Vec<u8>)> {
+    let file = File::open(loose_path).with_path(loose_path)?;
+    let mut decoder = ZlibDecoder::new(file);
+    let mut content_buf = vec![];
+    decoder
+        .read_to_end(&mut content_buf)
+        .map_err(|err| Error::corrupt_object(id, err.to_string()))?;
+
+    let mut reader = Reader::new(&content_buf[..]);
+    let kind = String::from_utf8_lossy(reader.pop_while(|c| c != &b' '));
+    let kind = PackObjectType::from_name(&kind)
+        .ok_or_else(|| Error::corrupt_object(id, format!("unrecognized object type: {}", kind)))?;
+
+    reader.try_pop(); // space
+    let payload_len = str::from_utf8(reader.pop_while(|c| c != &0))
+        .ok()
+        .and_then(|payload_len| payload_len.parse::<usize>().ok())
+        .ok_or_else(|| Error::corrupt_object(id, "invalid object size"))?;
+    reader.try_pop(); // \0
+
+    let content = reader.pop_all().to_vec();
+    if content.len() != payload_len {
+        return Err(Error::corrupt_object(
+            id,
+            format!("declared size {}, actual {}", payload_len, content.len()),
+        ));
+    }
+
+    Ok((kind, content))
+}
@@ -1,207 +1,1171 @@
-use flate2::read::ZlibDecoder;
-use std::{collections::BTreeMap, io::Read};
+use flate2::{Compression, bufread, write::ZlibEncoder};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
 
-use crate::reader::Reader;
+use crate::{
+    common::{bytes_to_string, create_object_payload_from_content},
+    error::{Error, IoResultExt, Result},
+    object_id::{Hasher, ObjectFormat, ObjectId},
+    odb::ObjectDatabase,
+    pack_index::{PackIndexEntry, build_index},
+    reader::Reader,
+};
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub(crate) enum PackObjectType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackObjectType {
     Commit,
     Tree,
     Blob,
+    Tag,
 }
 
 impl PackObjectType {
-    pub(crate) fn to_string(&self) -> &'static str {
+    pub fn to_string(self) -> &'static str {
         match self {
             PackObjectType::Commit => "commit",
             PackObjectType::Tree => "tree",
             PackObjectType::Blob => "blob",
+            PackObjectType::Tag => "tag",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "commit" => Some(PackObjectType::Commit),
+            "tree" => Some(PackObjectType::Tree),
+            "blob" => Some(PackObjectType::Blob),
+            "tag" => Some(PackObjectType::Tag),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn type_id(self) -> u8 {
+        match self {
+            PackObjectType::Commit => 1,
+            PackObjectType::Tree => 2,
+            PackObjectType::Blob => 3,
+            PackObjectType::Tag => 4,
+        }
+    }
+
+    pub(crate) fn from_type_id(type_id: u8) -> Option<Self> {
+        match type_id {
+            1 => Some(PackObjectType::Commit),
+            2 => Some(PackObjectType::Tree),
+            3 => Some(PackObjectType::Blob),
+            4 => Some(PackObjectType::Tag),
+            _ => None,
         }
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct PackObject {
     pub(crate) kind: PackObjectType,
     pub(crate) decompressed_payload: Vec<u8>,
 }
 
-pub(crate) struct PackReader<'a> {
-    slice: &'a [u8],
-    slice_ptr: usize,
+impl PackObject {
+    pub(crate) fn id(&self, format: ObjectFormat) -> Result<ObjectId> {
+        format.hash_object(&create_object_payload_from_content(
+            &self.decompressed_payload[..],
+            self.kind,
+        ))
+    }
+}
+
+/// Rebuilds `base` from a git delta: a header with base and result sizes followed by copy and
+/// insert instructions.
+pub(crate) fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let truncated = || Error::BadPack("truncated or invalid delta header".to_string());
+
+    let mut decoded_reader = Reader::new(delta);
+    let base_size = decoded_reader.pop_varint().ok_or_else(truncated)? as usize;
+    let result_size = decoded_reader.pop_varint().ok_or_else(truncated)? as usize;
+
+    if base_size != base.len() {
+        return Err(Error::BadPack(format!(
+            "delta base size mismatch: expected {}, got {}",
+            base_size,
+            base.len()
+        )));
+    }
+
+    // The sizes come from the pack, so they only bound the pre-allocation.
+    let mut payload = Vec::with_capacity(result_size.min(1 << 20));
+
+    while !decoded_reader.is_empty() {
+        let byte = decoded_reader.pop();
+
+        match byte >> 7 {
+            0 => {
+                // Insert
+                let size = (byte & 0b0111_1111) as usize;
+                if size == 0 || size > decoded_reader.len() {
+                    return Err(Error::BadPack(format!(
+                        "invalid delta insert instruction of size {}",
+                        size
+                    )));
+                }
+
+                payload.extend_from_slice(decoded_reader.popn(size));
+            }
+            1 => {
+                // Copy
+                let offset_bits = byte & 0b1111;
+                let size_bits = (byte >> 4) & 0b111;
+                if ((offset_bits.count_ones() + size_bits.count_ones()) as usize)
+                    > decoded_reader.len()
+                {
+                    return Err(truncated());
+                }
+
+                let offset = decoded_reader.pop_bit_masked_int(offset_bits);
+                let mut size = decoded_reader.pop_bit_masked_int(size_bits);
+
+                if size == 0 {
+                    size = 0x10000;
+                }
+
+                if offset + size > base.len() {
+                    return Err(Error::BadPack(format!(
+                        "delta copy out of base bounds: {}..{} of {}",
+                        offset,
+                        offset + size,
+                        base.len()
+                    )));
+                }
+
+                payload.extend_from_slice(&base[offset..offset + size]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if payload.len() != result_size {
+        return Err(Error::BadPack(format!(
+            "delta result size mismatch: expected {}, got {}",
+            result_size,
+            payload.len()
+        )));
+    }
+
+    Ok(payload)
+}
+
+/// Type and size header of a pack entry: 3 type bits and 4 size bits in the first byte, then
+/// the rest of the size in 7 bit little-endian groups.
+fn encode_object_header(type_id: u8, size: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    let mut byte = (type_id << 4) | (size & 0b1111) as u8;
+    let mut size = size >> 4;
+
+    while size > 0 {
+        bytes.push(byte | 0b1000_0000);
+        byte = (size & 0b0111_1111) as u8;
+        size >>= 7;
+    }
+    bytes.push(byte);
+
+    bytes
+}
+
+/// A single entry of a pack file on disk, with deltas not yet applied.
+pub(crate) enum PackEntry {
+    Object {
+        kind: PackObjectType,
+        payload: Vec<u8>,
+    },
+    OfsDelta {
+        base_offset: u64,
+        delta: Vec<u8>,
+    },
+    RefDelta {
+        base_id: ObjectId,
+        delta: Vec<u8>,
+    },
+}
+
+/// Reads the entry starting at `offset` of an open pack file.
+pub(crate) fn read_pack_entry(
+    mut file: &File,
+    offset: u64,
+    format: ObjectFormat,
+) -> Result<PackEntry> {
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|err| Error::from_stream(err, "seeking pack entry"))?;
+    let mut reader = BufReader::new(file);
+
+    let (object_type, size) = read_entry_header(&mut reader)?;
+
+    Ok(match object_type {
+        1..=4 => PackEntry::Object {
+            kind: PackObjectType::from_type_id(object_type).unwrap(),
+            payload: inflate(&mut reader, size)?,
+        },
+        6 => {
+            let distance = read_offset_varint(&mut reader)?;
+            if distance == 0 || distance > offset {
+                return Err(Error::BadPack(format!(
+                    "invalid OFS_DELTA base distance {} at {}",
+                    distance, offset
+                )));
+            }
+
+            PackEntry::OfsDelta {
+                base_offset: offset - distance,
+                delta: inflate(&mut reader, size)?,
+            }
+        }
+        7 => {
+            let mut base_id = vec![0u8; format.id_len()];
+            reader
+                .read_exact(&mut base_id)
+                .map_err(|err| Error::from_stream(err, "truncated pack entry"))?;
+
+            PackEntry::RefDelta {
+                base_id: ObjectId::from_slice(&base_id).unwrap(),
+                delta: inflate(&mut reader, size)?,
+            }
+        }
+        other => {
+            return Err(Error::BadPack(format!("unknown object type: {}", other)));
+        }
+    })
+}
+
+fn read_entry_header(reader: &mut impl Read) -> Result<(u8, usize)> {
+    let mut byte = [0u8; 1];
+    reader
+        .read_exact(&mut byte)
+        .map_err(|err| Error::from_stream(err, "truncated pack entry"))?;
+
+    let object_type = (byte[0] >> 4) & 0b111;
+    let mut size = (byte[0] & 0b1111) as usize;
+    let mut shift = 4;
+
+    while byte[0] & 0b1000_0000 > 0 {
+        reader
+            .read_exact(&mut byte)
+            .map_err(|err| Error::from_stream(err, "truncated pack entry"))?;
+        let group = (byte[0] & 0b0111_1111) as usize;
+        let bits = group
+            .checked_shl(shift)
+            .filter(|bits| bits >> shift == group)
+            .ok_or_else(|| Error::BadPack("pack entry size overflows".to_string()))?;
+        size |= bits;
+        shift += 7;
+    }
+
+    Ok((object_type, size))
+}
+
+fn read_offset_varint(reader: &mut impl Read) -> Result<u64> {
+    let mut byte = [0u8; 1];
+    reader
+        .read_exact(&mut byte)
+        .map_err(|err| Error::from_stream(err, "truncated pack entry"))?;
+    let mut out = (byte[0] & 0b0111_1111) as u64;
+
+    while byte[0] & 0b1000_0000 > 0 {
+        reader
+            .read_exact(&mut byte)
+            .map_err(|err| Error::from_stream(err, "truncated pack entry"))?;
+        out = out
+            .checked_add(1)
+            .and_then(|out| out.checked_mul(1 << 7))
+            .ok_or_else(|| Error::BadPack("pack delta offset overflows".to_string()))?;
+        out |= (byte[0] & 0b0111_1111) as u64;
+    }
+
+    Ok(out)
 }
 
-impl<'a> PackReader<'a> {
-    pub(crate) fn new(slice: &'a [u8]) -> Self {
-        Self {
-            slice,
-            slice_ptr: 0,
+/// Inflates one zlib stream, consuming exactly its compressed bytes from `reader`, and checks it
+/// against the size declared in the entry header. The declared size is untrusted: it only bounds
+/// the pre-allocation, and inflating stops one byte past it.
+fn inflate(reader: &mut impl BufRead, expected_size: usize) -> Result<Vec<u8>> {
+    let mut decoder = bufread::ZlibDecoder::new(reader);
+    let mut content_buf = Vec::with_capacity(expected_size.min(1 << 20));
+    (&mut decoder)
+        .take(expected_size as u64 + 1)
+        .read_to_end(&mut content_buf)
+        .map_err(|err| Error::from_stream(err, "corrupt or truncated pack entry"))?;
+
+    if content_buf.len() > expected_size {
+        return Err(Error::BadPack(format!(
+            "pack entry inflates past its declared size {}",
+            expected_size
+        )));
+    }
+    if content_buf.len() != expected_size {
+        return Err(Error::BadPack(format!(
+            "pack entry size mismatch: declared {}, inflated {}",
+            expected_size,
+            content_buf.len()
+        )));
+    }
+
+    Ok(content_buf)
+}
+
+/// Inflates one zlib stream without keeping the result, see `inflate`.
+fn inflate_to_sink(reader: &mut impl BufRead, expected_size: usize) -> Result<()> {
+    let inflated_size = io::copy(&mut bufread::ZlibDecoder::new(reader), &mut io::sink())
+        .map_err(|err| Error::from_stream(err, "corrupt or truncated pack entry"))?;
+
+    if inflated_size != expected_size as u64 {
+        return Err(Error::BadPack(format!(
+            "pack entry size mismatch: declared {}, inflated {}",
+            expected_size, inflated_size
+        )));
+    }
+
+    Ok(())
+}
+
+/// Copies everything consumed from the incoming stream into the pack file, keeping track of the
+/// offset and the checksums on the way.
+struct PackStream<R: BufRead> {
+    inner: R,
+    file: BufWriter<File>,
+    /// `consume` cannot fail, the first error writing the pack file is kept for later.
+    file_error: Option<io::Error>,
+    offset: u64,
+    crc32: crc32fast::Hasher,
+    checksum: Hasher,
+}
+
+impl<R: BufRead> Read for PackStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for PackStream<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // The inner buffer still holds the bytes returned by the last `fill_buf`.
+        let consumed = &self.inner.fill_buf().unwrap()[..amount];
+        if let Err(err) = self.file.write_all(consumed)
+            && self.file_error.is_none()
+        {
+            self.file_error = Some(err);
+        }
+        self.crc32.update(consumed);
+        self.checksum.update(consumed);
+        self.offset += amount as u64;
+        self.inner.consume(amount);
+    }
+}
+
+/// Streams a pack into `objects/pack/` without holding it in memory. Objects are spilled
+/// to disk as they arrive, deltas are resolved from the stored pack afterwards.
+pub struct PackReader<R: BufRead> {
+    stream: PackStream<R>,
+    tmp_path: std::path::PathBuf,
+    objects_dir: std::path::PathBuf,
+    format: ObjectFormat,
+}
+
+impl<R: BufRead> PackReader<R> {
+    /// Ids and the trailing checksum of the pack use the hash function of `format`. `pack_dir` is
+    /// the `pack` directory of the object store thin pack bases are taken from.
+    pub fn new(stream: R, pack_dir: &Path, format: ObjectFormat) -> Result<Self> {
+        fs::create_dir_all(pack_dir).with_path(pack_dir)?;
+        let tmp_path = pack_dir.join(format!("tmp_pack_{}", std::process::id()));
+        let file = File::create(&tmp_path).with_path(&tmp_path)?;
+
+        Ok(Self {
+            stream: PackStream {
+                inner: stream,
+                file: BufWriter::new(file),
+                file_error: None,
+                offset: 0,
+                crc32: crc32fast::Hasher::new(),
+                checksum: format.hasher(),
+            },
+            tmp_path,
+            objects_dir: pack_dir.parent().unwrap_or(Path::new("")).to_path_buf(),
+            format,
+        })
+    }
+
+    /// Stores the pack with its index and returns the pack name (`pack-<checksum>`). REF_DELTA
+    /// bases missing from the pack (thin packs) are taken from the local object store and
+    /// appended, so the stored pack is self-contained. The partial pack is removed on error.
+    pub fn store(self) -> Result<String> {
+        let tmp_path = self.tmp_path.clone();
+        let result = self.store_inner();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
         }
+
+        result
     }
 
-    pub fn read(mut self) -> Vec<PackObject> {
-        let mut objects = BTreeMap::new();
+    fn store_inner(mut self) -> Result<String> {
+        let mut header = [0u8; 12];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|err| Error::from_stream(err, "truncated pack header"))?;
+
+        let pack_marker = &header[0..4];
+        if pack_marker != b"PACK" {
+            return Err(Error::BadPack(format!(
+                "invalid pack signature: {:?}",
+                pack_marker
+            )));
+        }
 
-        let _pack_marker = self.popn(4);
-        let _pack_version = self.popn(4);
-        let pack_object_count_bytes = self.popn(4);
-        let pack_object_count =
-            u32::from_be_bytes(pack_object_count_bytes[..].try_into().unwrap()) as usize;
+        let pack_version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if pack_version != 2 && pack_version != 3 {
+            return Err(Error::BadPack(format!(
+                "unsupported pack version: {}",
+                pack_version
+            )));
+        }
+
+        let pack_object_count = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+
+        let mut entries: Vec<PackIndexEntry> = vec![];
+        let mut deltas = vec![];
 
         for _ in 0..pack_object_count {
-            let object_location = self.slice_ptr;
-            let object_type = (self.slice[0] >> 4) & 0b111;
-            let _object_decompressed_size = self.read_varint(0b1000_1111);
+            let object_location = self.stream.offset;
+            self.stream.crc32 = crc32fast::Hasher::new();
+
+            let (object_type, object_decompressed_size) = read_entry_header(&mut self.stream)?;
 
             match object_type {
-                1 => {
-                    // Commit
-                    let (decoded, encoded_len) = self.decode_current();
-                    objects.insert(
-                        object_location,
-                        PackObject {
-                            kind: PackObjectType::Commit,
-                            decompressed_payload: decoded,
-                        },
-                    );
-
-                    self.dropn(encoded_len);
-                }
-                2 => {
-                    // Tree
-                    let (decoded, encoded_len) = self.decode_current();
-                    objects.insert(
-                        object_location,
-                        PackObject {
-                            kind: PackObjectType::Tree,
-                            decompressed_payload: decoded,
-                        },
-                    );
-
-                    self.dropn(encoded_len);
-                }
-                3 => {
-                    // Tree
-                    let (decoded, encoded_len) = self.decode_current();
-                    objects.insert(
-                        object_location,
-                        PackObject {
-                            kind: PackObjectType::Blob,
-                            decompressed_payload: decoded,
-                        },
-                    );
-
-                    self.dropn(encoded_len);
+                1..=4 => {
+                    // Commit, Tree, Blob, Tag
+                    let object = PackObject {
+                        kind: PackObjectType::from_type_id(object_type).unwrap(),
+                        decompressed_payload: inflate(&mut self.stream, object_decompressed_size)?,
+                    };
+                    entries.push(PackIndexEntry {
+                        id: object.id(self.format)?,
+                        offset: object_location,
+                        crc32: self.stream.crc32.clone().finalize(),
+                    });
                 }
                 6 => {
                     // OFS_DELTA
-                    let offset = self.read_offset_varint();
-                    let base_object = objects.get(&(object_location - offset)).unwrap();
-                    let (decoded, encoded_len) = self.decode_current();
-                    let mut decoded_reader = Reader::new(&decoded[..]);
-                    let _base_size = decoded_reader.pop_varint();
-                    let _result_size = decoded_reader.pop_varint();
-
-                    let mut payload = vec![];
-
-                    while !decoded_reader.is_empty() {
-                        let byte = decoded_reader.pop();
-
-                        match byte >> 7 {
-                            0 => {
-                                // Insert
-                                let size = byte & 0b0111_1111;
-                                payload.extend_from_slice(decoded_reader.popn(size as usize));
-                            }
-                            1 => {
-                                // Copy
-                                let offset_bits = byte & 0b1111;
-                                let size_bits = (byte >> 4) & 0b111;
-                                let offset = decoded_reader.pop_bit_masked_int(offset_bits);
-                                let size = decoded_reader.pop_bit_masked_int(size_bits);
-
-                                if size == 0 {
-                                    panic!("Lookup 0 case.");
-                                }
-
-                                payload.extend_from_slice(
-                                    &base_object.decompressed_payload[offset..offset + size],
-                                );
-                            }
-                            _ => panic!(),
-                        }
+                    let distance = read_offset_varint(&mut self.stream)?;
+                    if distance == 0 || distance > object_location {
+                        return Err(Error::BadPack(format!(
+                            "invalid OFS_DELTA base distance {} at {}",
+                            distance, object_location
+                        )));
                     }
 
-                    objects.insert(
-                        object_location,
-                        PackObject {
-                            kind: base_object.kind,
-                            decompressed_payload: payload,
-                        },
-                    );
-
-                    self.dropn(encoded_len);
+                    inflate_to_sink(&mut self.stream, object_decompressed_size)?;
+                    deltas.push((object_location, self.stream.crc32.clone().finalize()));
+                }
+                7 => {
+                    // REF_DELTA
+                    let mut base_id = vec![0u8; self.format.id_len()];
+                    self.stream
+                        .read_exact(&mut base_id)
+                        .map_err(|err| Error::from_stream(err, "truncated pack entry"))?;
+                    inflate_to_sink(&mut self.stream, object_decompressed_size)?;
+                    deltas.push((object_location, self.stream.crc32.clone().finalize()));
                 }
                 other => {
-                    error!("Unknown object type: {}", other);
-                    panic!()
+                    return Err(Error::BadPack(format!("unknown object type: {}", other)));
                 }
             };
         }
 
-        objects.into_values().collect()
+        let expected_checksum = self.stream.checksum.clone().finalize();
+        let mut pack_checksum = vec![0u8; self.format.id_len()];
+        self.stream
+            .read_exact(&mut pack_checksum)
+            .map_err(|err| Error::from_stream(err, "truncated pack: missing trailing checksum"))?;
+
+        if pack_checksum != expected_checksum.as_bytes() {
+            return Err(Error::BadPack(format!(
+                "pack checksum mismatch: expected {}, got {}",
+                expected_checksum,
+                bytes_to_string(&pack_checksum)
+            )));
+        }
+
+        let PackStream {
+            file, file_error, ..
+        } = self.stream;
+        if let Some(err) = file_error {
+            return Err(err).with_path(&self.tmp_path);
+        }
+        file.into_inner()
+            .map_err(|err| err.into_error())
+            .with_path(&self.tmp_path)?;
+
+        let thin_bases = resolve_deltas(
+            &self.tmp_path,
+            &mut entries,
+            deltas,
+            &self.objects_dir,
+            self.format,
+        )?;
+        if !thin_bases.is_empty() {
+            pack_checksum = fix_thin_pack(&self.tmp_path, &mut entries, &thin_bases, self.format)?;
+        }
+
+        let name = format!("pack-{}", bytes_to_string(&pack_checksum));
+        let pack_dir = self.tmp_path.parent().unwrap();
+        let idx_path = pack_dir.join(format!("{}.idx", name));
+        fs::write(
+            &idx_path,
+            build_index(&pack_checksum, &entries, self.format),
+        )
+        .with_path(&idx_path)?;
+        fs::rename(&self.tmp_path, pack_dir.join(format!("{}.pack", name)))
+            .with_path(&self.tmp_path)?;
+
+        Ok(name)
     }
+}
 
-    fn decode_current(&self) -> (Vec<u8>, usize) {
-        let mut decoder = ZlibDecoder::new(self.slice);
-        let mut content_buf = vec![];
-        decoder.read_to_end(&mut content_buf).unwrap();
+/// Resolves the ids of all delta entries by reading them back from the stored pack, and returns
+/// the bases that had to be taken from the local object store in `objects_dir`.
+fn resolve_deltas(
+    pack_path: &Path,
+    entries: &mut Vec<PackIndexEntry>,
+    mut deltas: Vec<(u64, u32)>,
+    objects_dir: &Path,
+    format: ObjectFormat,
+) -> Result<BTreeMap<ObjectId, PackObject>> {
+    let file = File::open(pack_path).with_path(pack_path)?;
+    let mut locations = entries
+        .iter()
+        .map(|entry| (entry.id, entry.offset))
+        .collect::<HashMap<_, _>>();
+    let mut thin_bases = BTreeMap::new();
+    let mut cache = BaseCache::default();
 
-        (content_buf, decoder.total_in() as usize)
+    while !deltas.is_empty() {
+        let mut unresolved = vec![];
+        let mut missing_bases = BTreeSet::new();
+        let pending_count = deltas.len();
+
+        for (object_location, crc32) in deltas {
+            // A REF_DELTA base, at the end of the chain of any delta, may appear later in the
+            // pack, or not at all in a thin pack.
+            let resolution = read_resolved(
+                &file,
+                object_location,
+                &locations,
+                &thin_bases,
+                &mut cache,
+                format,
+            )?;
+            let object = match resolution {
+                Resolution::Object(object) => object,
+                Resolution::MissingBase(base_id) => {
+                    missing_bases.insert(base_id);
+                    unresolved.push((object_location, crc32));
+                    continue;
+                }
+            };
+
+            let id = object.id(format)?;
+            locations.insert(id, object_location);
+            entries.push(PackIndexEntry {
+                id,
+                offset: object_location,
+                crc32,
+            });
+        }
+
+        if unresolved.len() == pending_count {
+            // No progress within the pack: the remaining bases have to come from the local
+            // object store. Some of them may be unresolved deltas of the pack.
+            let odb = ObjectDatabase::open(objects_dir, format)?;
+            let thin_base_count = thin_bases.len();
+            for base_id in &missing_bases {
+                let Some((kind, decompressed_payload)) = odb.read_raw(base_id)? else {
+                    continue;
+                };
+                let thin_base = PackObject {
+                    kind,
+                    decompressed_payload,
+                };
+                if thin_base.id(format)? != *base_id {
+                    return Err(Error::corrupt_object(*base_id, "content does not match id"));
+                }
+                thin_bases.insert(*base_id, thin_base);
+            }
+
+            if thin_bases.len() == thin_base_count {
+                return Err(Error::BadPack(format!(
+                    "missing REF_DELTA base object: {}",
+                    missing_bases.first().unwrap()
+                )));
+            }
+        }
+
+        deltas = unresolved;
     }
 
-    fn popn(&mut self, n: usize) -> Vec<u8> {
-        let out = &self.slice[0..n];
-        self.slice = &self.slice[n..];
-        self.slice_ptr += n;
-        out.to_vec()
+    Ok(thin_bases)
+}
+
+/// What reading a pack entry back gives: its object, or the REF_DELTA base that the end of its
+/// delta chain waits for.
+enum Resolution {
+    Object(PackObject),
+    MissingBase(ObjectId),
+}
+
+/// Recently resolved objects by pack offset, so that deltas sharing a base or a chain do not
+/// inflate it again. Its total size is bounded, the oldest objects go first.
+#[derive(Default)]
+struct BaseCache {
+    objects: HashMap<u64, PackObject>,
+    order: VecDeque<u64>,
+    size: usize,
+}
+
+impl BaseCache {
+    const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+    fn get(&self, offset: u64) -> Option<&PackObject> {
+        self.objects.get(&offset)
     }
 
-    fn dropn(&mut self, n: usize) {
-        self.slice = &self.slice[n..];
-        self.slice_ptr += n;
+    fn insert(&mut self, offset: u64, object: &PackObject) {
+        let len = object.decompressed_payload.len();
+        if len > Self::MAX_SIZE || self.objects.contains_key(&offset) {
+            return;
+        }
+
+        while self.size + len > Self::MAX_SIZE
+            && let Some(oldest) = self.order.pop_front()
+        {
+            if let Some(evicted) = self.objects.remove(&oldest) {
+                self.size -= evicted.decompressed_payload.len();
+            }
+        }
+        self.objects.insert(offset, object.clone());
+        self.order.push_back(offset);
+        self.size += len;
+    }
+}
+
+fn read_resolved(
+    file: &File,
+    offset: u64,
+    locations: &HashMap<ObjectId, u64>,
+    thin_bases: &BTreeMap<ObjectId, PackObject>,
+    cache: &mut BaseCache,
+    format: ObjectFormat,
+) -> Result<Resolution> {
+    // Walks down the chain to its base, keeping the deltas with the offset of the object each
+    // one rebuilds, then applies them bottom up: a long chain must not recurse. It ends: OFS_DELTA
+    // bases come earlier in the pack, and `locations` only has objects already resolved.
+    let mut deltas: Vec<(u64, Vec<u8>)> = vec![];
+    let mut current = offset;
+    let mut object = loop {
+        if let Some(object) = cache.get(current) {
+            break object.clone();
+        }
+        match read_pack_entry(file, current, format)? {
+            PackEntry::Object { kind, payload } => {
+                let object = PackObject {
+                    kind,
+                    decompressed_payload: payload,
+                };
+                cache.insert(current, &object);
+                break object;
+            }
+            PackEntry::OfsDelta { base_offset, delta } => {
+                deltas.push((current, delta));
+                current = base_offset;
+            }
+            PackEntry::RefDelta { base_id, delta } => {
+                match (locations.get(&base_id), thin_bases.get(&base_id)) {
+                    (Some(base_offset), _) => {
+                        deltas.push((current, delta));
+                        current = *base_offset;
+                    }
+                    (None, Some(base)) => {
+                        let object = apply_pack_delta(base, &delta)?;
+                        cache.insert(current, &object);
+                        break object;
+                    }
+                    (None, None) => return Ok(Resolution::MissingBase(base_id)),
+                }
+            }
+        }
+    };
+
+    for (offset, delta) in deltas.into_iter().rev() {
+        object = apply_pack_delta(&object, &delta)?;
+        cache.insert(offset, &object);
     }
+    Ok(Resolution::Object(object))
+}
+
+fn apply_pack_delta(base: &PackObject, delta: &[u8]) -> Result<PackObject> {
+    Ok(PackObject {
+        kind: base.kind,
+        decompressed_payload: apply_delta(&base.decompressed_payload[..], delta)?,
+    })
+}
+
+/// Makes a thin pack self-contained by appending the given base objects undeltified, then
+/// rewriting the object count and the trailing checksum. Returns the new checksum.
+fn fix_thin_pack(
+    pack_path: &Path,
+    entries: &mut Vec<PackIndexEntry>,
+    bases: &BTreeMap<ObjectId, PackObject>,
+    format: ObjectFormat,
+) -> Result<Vec<u8>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pack_path)
+        .with_path(pack_path)?;
+
+    let mut write = || -> io::Result<Vec<u8>> {
+        let mut object_count = [0u8; 4];
+        file.seek(SeekFrom::Start(8))?;
+        file.read_exact(&mut object_count)?;
+        let object_count = u32::from_be_bytes(object_count) + bases.len() as u32;
+        file.seek(SeekFrom::Start(8))?;
+        file.write_all(&object_count.to_be_bytes())?;
 
-    fn read_varint(&mut self, mut first_byte_mask: u8) -> usize {
-        let mut out = 0;
+        let mut offset = file.seek(SeekFrom::End(-(format.id_len() as i64)))?;
+        for (base_id, base) in bases {
+            let mut bytes =
+                encode_object_header(base.kind.type_id(), base.decompressed_payload.len());
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&base.decompressed_payload)?;
+            bytes.extend_from_slice(&encoder.finish()?);
+
+            file.write_all(&bytes)?;
+            entries.push(PackIndexEntry {
+                id: *base_id,
+                offset,
+                crc32: crc32fast::hash(&bytes),
+            });
+            offset += bytes.len() as u64;
+        }
+        file.set_len(offset)?;
+
+        let mut hasher = format.hasher();
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut file, &mut hasher)?;
+        let checksum = hasher.finalize().as_bytes().to_vec();
+        file.write_all(&checksum)?;
+
+        Ok(checksum)
+    };
+
+    write().with_path(pack_path)
+}
 
-        loop {
-            let byte = self.slice[0] & first_byte_mask;
-            first_byte_mask = 0xFF;
+#[cfg(test)]
+mod test {
+    use flate2::{Compression, write::ZlibEncoder};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fs,
+        fs::File,
+        io::Write,
+    };
 
-            self.dropn(1);
+    use crate::{
+        common::{bytes_to_string, create_object_payload_from_content},
+        error::Error,
+        index::encode_offset_varint,
+        object_id::ObjectFormat,
+        odb::ObjectDatabase,
+        pack::{
+            BaseCache, PackEntry, PackObject, PackObjectType, PackReader, Resolution, apply_delta,
+            encode_object_header, fix_thin_pack, read_pack_entry, read_resolved,
+        },
+        pack_index::PackIndex,
+        test_util::TempDir,
+    };
 
-            out <<= 7;
-            out |= (byte & 0b0111_1111) as usize;
+    const SHA1: ObjectFormat = ObjectFormat::Sha1;
 
-            if byte & 0b1000_0000 == 0 {
-                break;
+    fn build_pack(entries: &[(u8, Vec<u8>, Vec<u8>)], format: ObjectFormat) -> Vec<u8> {
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+
+        for (type_id, prefix, content) in entries {
+            pack.extend_from_slice(&encode_object_header(*type_id, content.len()));
+            pack.extend_from_slice(prefix);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content).unwrap();
+            pack.extend_from_slice(&encoder.finish().unwrap());
+        }
+
+        let checksum = format.hash(&pack);
+        pack.extend_from_slice(checksum.as_bytes());
+        pack
+    }
+
+    fn base_blob() -> PackObject {
+        PackObject {
+            kind: PackObjectType::Blob,
+            decompressed_payload: b"hello world".to_vec(),
+        }
+    }
+
+    fn hello_there_delta() -> Vec<u8> {
+        // Base size 11, result size 11, copy 5 bytes from offset 0, insert " there".
+        let mut delta = vec![11, 11, 0b1001_0001, 0, 5, 6];
+        delta.extend_from_slice(b" there");
+        delta
+    }
+
+    #[test]
+    fn test_ref_delta_with_base_later_in_pack() {
+        for format in [ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let base = base_blob();
+            let hello_there = PackObject {
+                kind: PackObjectType::Blob,
+                decompressed_payload: b"hello there".to_vec(),
+            };
+            let pack = build_pack(
+                &[
+                    (
+                        7,
+                        base.id(format).unwrap().as_bytes().to_vec(),
+                        hello_there_delta(),
+                    ),
+                    (3, vec![], base.decompressed_payload.clone()),
+                ],
+                format,
+            );
+
+            let pack_dir = TempDir::new(&format!("ref_delta_{}", format.name()));
+            let name = PackReader::new(&pack[..], &pack_dir, format)
+                .unwrap()
+                .store()
+                .unwrap();
+            assert_eq!(
+                format!(
+                    "pack-{}",
+                    bytes_to_string(&pack[pack.len() - format.id_len()..])
+                ),
+                name
+            );
+            assert_eq!(
+                pack,
+                fs::read(pack_dir.join(format!("{}.pack", name))).unwrap()
+            );
+
+            let index = PackIndex::parse(
+                fs::read(pack_dir.join(format!("{}.idx", name))).unwrap(),
+                format,
+            )
+            .unwrap();
+            assert_eq!(Some(12), index.find(&hello_there.id(format).unwrap()));
+            assert!(index.find(&base.id(format).unwrap()).is_some());
+        }
+    }
+
+    #[test]
+    fn test_ofs_delta_on_thin_ref_delta() {
+        // A REF_DELTA on a base only the local object store has, and an OFS_DELTA on top of it.
+        let base = base_blob();
+        let ref_delta = (
+            7,
+            base.id(SHA1).unwrap().as_bytes().to_vec(),
+            hello_there_delta(),
+        );
+        let distance = build_pack(std::slice::from_ref(&ref_delta), SHA1).len() - 12 - 20;
+        assert!(distance < 0x80);
+        // Copy the 11 bytes of "hello there", insert "!".
+        let exclaim_delta = vec![11, 12, 0b1001_0001, 0, 11, 1, b'!'];
+        let pack = build_pack(&[ref_delta, (6, vec![distance as u8], exclaim_delta)], SHA1);
+
+        let dir = TempDir::new("thin_ofs");
+        let objects_dir = dir.join("objects");
+        fs::create_dir_all(objects_dir.join("pack")).unwrap();
+        let loose_path = ObjectDatabase::open(&objects_dir, SHA1)
+            .unwrap()
+            .loose_object_path(&base.id(SHA1).unwrap());
+        fs::create_dir_all(loose_path.parent().unwrap()).unwrap();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&create_object_payload_from_content(
+                &base.decompressed_payload,
+                PackObjectType::Blob,
+            ))
+            .unwrap();
+        fs::write(&loose_path, encoder.finish().unwrap()).unwrap();
+
+        PackReader::new(&pack[..], &objects_dir.join("pack"), SHA1)
+            .unwrap()
+            .store()
+            .unwrap();
+
+        let exclaimed = PackObject {
+            kind: PackObjectType::Blob,
+            decompressed_payload: b"hello there!".to_vec(),
+        };
+        fs::remove_file(&loose_path).unwrap();
+        let odb = ObjectDatabase::open(&objects_dir, SHA1).unwrap();
+        assert_eq!(
+            Some((PackObjectType::Blob, exclaimed.decompressed_payload.clone())),
+            odb.read_raw(&exclaimed.id(SHA1).unwrap()).unwrap()
+        );
+        // The base got appended to the pack.
+        assert!(odb.read_raw(&base.id(SHA1).unwrap()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_long_delta_chain() {
+        // Each OFS_DELTA replaces the 8 bytes of the previous object with its own index.
+        let chain_len = 5_000u64;
+        let add_entry = |pack: &mut Vec<u8>, type_id, prefix: &[u8], content: &[u8]| {
+            let offset = pack.len() as u64;
+            pack.extend_from_slice(&encode_object_header(type_id, content.len()));
+            pack.extend_from_slice(prefix);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(content).unwrap();
+            pack.extend_from_slice(&encoder.finish().unwrap());
+            offset
+        };
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&(chain_len as u32 + 1).to_be_bytes());
+        let mut entry_offset = add_entry(&mut pack, 3, &[], &0u64.to_be_bytes());
+        for i in 1..=chain_len {
+            let distance = pack.len() as u64 - entry_offset;
+            let mut delta = vec![8, 8, 8];
+            delta.extend_from_slice(&i.to_be_bytes());
+            entry_offset = add_entry(&mut pack, 6, &encode_offset_varint(distance), &delta);
+        }
+
+        let dir = TempDir::new("long_chain");
+        fs::create_dir_all(&dir).unwrap();
+        let pack_path = dir.join("chain.pack");
+        fs::write(&pack_path, &pack).unwrap();
+
+        let resolution = read_resolved(
+            &File::open(&pack_path).unwrap(),
+            entry_offset,
+            &HashMap::new(),
+            &BTreeMap::new(),
+            &mut BaseCache::default(),
+            SHA1,
+        )
+        .unwrap();
+        let Resolution::Object(object) = resolution else {
+            panic!("base not found");
+        };
+        assert_eq!(
+            chain_len.to_be_bytes().to_vec(),
+            object.decompressed_payload
+        );
+    }
+
+    #[test]
+    fn test_missing_thin_base() {
+        let base = base_blob();
+        let pack = build_pack(
+            &[(
+                7,
+                base.id(SHA1).unwrap().as_bytes().to_vec(),
+                hello_there_delta(),
+            )],
+            SHA1,
+        );
+
+        let dir = TempDir::new("missing_thin_base");
+        let pack_dir = dir.join("objects").join("pack");
+        let result = PackReader::new(&pack[..], &pack_dir, SHA1).unwrap().store();
+        assert!(matches!(result, Err(Error::BadPack(_))));
+    }
+
+    #[test]
+    fn test_read_pack_entry() {
+        let pack = build_pack(&[(3, vec![], b"hello world".to_vec())], SHA1);
+
+        let pack_dir = TempDir::new("read_entry");
+        let name = PackReader::new(&pack[..], &pack_dir, SHA1)
+            .unwrap()
+            .store()
+            .unwrap();
+
+        let file = File::open(pack_dir.join(format!("{}.pack", name))).unwrap();
+        match read_pack_entry(&file, 12, SHA1).unwrap() {
+            PackEntry::Object { kind, payload } => {
+                assert!(kind == PackObjectType::Blob);
+                assert_eq!(b"hello world".to_vec(), payload);
             }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_fix_thin_pack() {
+        let base = base_blob();
+        let thin_pack = build_pack(
+            &[(
+                7,
+                base.id(SHA1).unwrap().as_bytes().to_vec(),
+                hello_there_delta(),
+            )],
+            SHA1,
+        );
+
+        let pack_dir = TempDir::new("thin");
+        fs::create_dir_all(&pack_dir).unwrap();
+        let pack_path = pack_dir.join("thin.pack");
+        fs::write(&pack_path, &thin_pack).unwrap();
+
+        let mut entries = vec![];
+        let checksum = fix_thin_pack(
+            &pack_path,
+            &mut entries,
+            &BTreeMap::from([(base.id(SHA1).unwrap(), base_blob())]),
+            SHA1,
+        )
+        .unwrap();
+
+        let pack = fs::read(&pack_path).unwrap();
+        assert_eq!(2u32.to_be_bytes(), pack[8..12]);
+        assert_eq!(checksum.to_vec(), pack[pack.len() - 20..].to_vec());
+        assert_eq!(
+            SHA1.hash(&pack[..pack.len() - 20]).as_bytes().to_vec(),
+            checksum.to_vec()
+        );
+
+        assert_eq!(1, entries.len());
+        assert_eq!(base.id(SHA1).unwrap(), entries[0].id);
+        assert_eq!((thin_pack.len() - 20) as u64, entries[0].offset);
+
+        let file = File::open(&pack_path).unwrap();
+        match read_pack_entry(&file, entries[0].offset, SHA1).unwrap() {
+            PackEntry::Object { payload, .. } => assert_eq!(base.decompressed_payload, payload),
+            _ => panic!(),
         }
+    }
+
+    #[test]
+    fn test_reject_bad_checksum() {
+        let mut pack = build_pack(&[(3, vec![], b"hello world".to_vec())], SHA1);
+        let last = pack.len() - 1;
+        pack[last] ^= 0xff;
 
-        out
+        let pack_dir = TempDir::new("bad_checksum");
+        let result = PackReader::new(&pack[..], &pack_dir, SHA1).unwrap().store();
+        assert!(result.is_err());
+        assert!(fs::read_dir(&pack_dir).unwrap().next().is_none());
     }
 
-    fn read_offset_varint(&mut self) -> usize {
-        let mut byte = self.popn(1)[0];
-        let mut out = (byte & 0b0111_1111) as usize;
+    #[test]
+    fn test_reject_bad_signature() {
+        let mut pack = build_pack(&[(3, vec![], b"hello world".to_vec())], SHA1);
+        pack[0] = b'J';
+
+        let pack_dir = TempDir::new("bad_signature");
+        let result = PackReader::new(&pack[..], &pack_dir, SHA1).unwrap().store();
+        assert!(result.is_err());
+        assert!(fs::read_dir(&pack_dir).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_reject_truncated_pack() {
+        let pack = build_pack(&[(3, vec![], b"hello world".to_vec())], SHA1);
+
+        let pack_dir = TempDir::new("truncated");
+        let result = PackReader::new(&pack[..pack.len() - 25], &pack_dir, SHA1)
+            .unwrap()
+            .store();
+        assert!(result.is_err());
+        assert!(fs::read_dir(&pack_dir).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_reject_size_mismatch() {
+        let mut pack = build_pack(&[(3, vec![], b"hello world".to_vec())], SHA1);
+        // Declare 10 bytes instead of 11, keeping the checksum valid.
+        pack[12] = (3 << 4) | 10;
+        let checksum = SHA1.hash(&pack[..pack.len() - 20]);
+        let len = pack.len();
+        pack[len - 20..].copy_from_slice(checksum.as_bytes());
+
+        let pack_dir = TempDir::new("size_mismatch");
+        let result = PackReader::new(&pack[..], &pack_dir, SHA1).unwrap().store();
+        assert!(result.is_err());
+        assert!(fs::read_dir(&pack_dir).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_reject_untrusted_sizes() {
+        let with_checksum = |mut pack: Vec<u8>| {
+            let checksum = SHA1.hash(&pack);
+            pack.extend_from_slice(checksum.as_bytes());
+            pack
+        };
+        let mut compressed = ZlibEncoder::new(Vec::new(), Compression::default());
+        compressed.write_all(b"hello world").unwrap();
+        let compressed = compressed.finish().unwrap();
+
+        let mut header = b"PACK".to_vec();
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&1u32.to_be_bytes());
+
+        // A blob declaring 2^53 - 1 bytes must not be allocated up front.
+        let mut huge = header.clone();
+        huge.extend_from_slice(&encode_object_header(3, (1 << 53) - 1));
+        huge.extend_from_slice(&compressed);
+        // Eleven continuation bytes make the size wider than 64 bits.
+        let mut overlong = header.clone();
+        overlong.extend_from_slice(&[0b1011_1111; 11]);
+        overlong.push(1);
+        overlong.extend_from_slice(&compressed);
+        // An entry inflating to more than it declares.
+        let mut larger = header.clone();
+        larger.extend_from_slice(&encode_object_header(3, 5));
+        larger.extend_from_slice(&compressed);
+        // An OFS_DELTA whose offset does not fit in 64 bits.
+        let mut offset = header;
+        offset.extend_from_slice(&encode_object_header(6, 11));
+        offset.extend_from_slice(&[0xff; 10]);
+        offset.push(0x7f);
+        offset.extend_from_slice(&compressed);
 
-        while byte & 0b1000_0000 > 0 {
-            byte = self.popn(1)[0];
-            out += 1;
-            out <<= 7;
-            out |= (byte & 0b0111_1111) as usize;
+        for (name, pack) in [
+            ("huge", huge),
+            ("overlong", overlong),
+            ("larger", larger),
+            ("offset", offset),
+        ] {
+            let pack_dir = TempDir::new(&format!("untrusted_{}", name));
+            let result = PackReader::new(&with_checksum(pack)[..], &pack_dir, SHA1)
+                .unwrap()
+                .store();
+            assert!(matches!(result, Err(Error::BadPack(_))), "{}", name);
         }
 
-        out
+        let mut delta = vec![0xff; 10];
+        delta.push(0x7f);
+        assert!(apply_delta(b"", &delta).is_err());
+        let delta = [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert!(apply_delta(b"", &delta).is_err());
     }
 }
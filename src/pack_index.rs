@@ -0,0 +1,326 @@
+use crate::{
+    error::{Error, Result},
+    object_id::{ObjectFormat, ObjectId},
+};
+
+const IDX_SIGNATURE: &[u8] = b"\xfftOc";
+const IDX_VERSION: u32 = 2;
+
+/// A parsed version 2 `.idx` file, mapping object ids to offsets in the matching pack. Ids and
+/// checksums are as long as the ids of the repository's object format.
+pub(crate) struct PackIndex {
+    bytes: Vec<u8>,
+    object_count: usize,
+    format: ObjectFormat,
+}
+
+impl PackIndex {
+    pub(crate) fn parse(bytes: Vec<u8>, format: ObjectFormat) -> Result<Self> {
+        let id_len = format.id_len();
+        if bytes.len() < 8 + 256 * 4 + 2 * id_len
+            || &bytes[0..4] != IDX_SIGNATURE
+            || u32::from_be_bytes(bytes[4..8].try_into().unwrap()) != IDX_VERSION
+        {
+            return Err(Error::BadPack("unsupported pack index version".to_string()));
+        }
+
+        // fanout[255] is the object count, and no entry may be smaller than the one before.
+        let fanout = bytes[8..8 + 256 * 4]
+            .chunks(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()) as usize)
+            .collect::<Vec<_>>();
+        if fanout.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(Error::BadPack(
+                "non-monotonic pack index fanout".to_string(),
+            ));
+        }
+        let object_count = fanout[255];
+
+        let offsets_start = 8 + 256 * 4 + object_count * (id_len + 4);
+        if bytes.len() < offsets_start + object_count * 4 + 2 * id_len {
+            return Err(Error::BadPack("truncated pack index".to_string()));
+        }
+
+        // Every offset with the MSB set points into the 64 bit table that follows the offsets.
+        let large_table_len = (bytes.len() - offsets_start - object_count * 4 - 2 * id_len) / 8;
+        if bytes[offsets_start..offsets_start + object_count * 4]
+            .chunks(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .any(|offset| {
+                offset & 0x8000_0000 != 0 && (offset & 0x7fff_ffff) as usize >= large_table_len
+            })
+        {
+            return Err(Error::BadPack("truncated pack index".to_string()));
+        }
+
+        Ok(Self {
+            bytes,
+            object_count,
+            format,
+        })
+    }
+
+    /// Offset of the object in the pack, if the pack contains it.
+    pub(crate) fn find(&self, id: &ObjectId) -> Option<u64> {
+        // Objects whose first byte is `id[0]` occupy the range [fanout[id[0] - 1], fanout[id[0]]).
+        let first_byte = id.as_bytes()[0];
+        let mut low = match first_byte {
+            0 => 0,
+            first_byte => self.fanout(first_byte - 1),
+        };
+        let mut high = self.fanout(first_byte);
+
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.id_at(mid).cmp(id) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(self.offset_at(mid)),
+            }
+        }
+
+        None
+    }
+
+    /// Ids of the pack starting with an (at least two characters long) hex prefix.
+    pub(crate) fn ids_with_prefix(&self, prefix: &str) -> Vec<ObjectId> {
+        let mut lower_bound = vec![0u8; self.format.id_len()];
+        for (i, digit) in prefix.bytes().take(self.format.hex_len()).enumerate() {
+            let nibble = (digit as char).to_digit(16).unwrap_or(0) as u8;
+            lower_bound[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        let lower_bound = ObjectId::from_slice(&lower_bound).unwrap();
+
+        let first_byte = lower_bound.as_bytes()[0];
+        let mut low = match first_byte {
+            0 => 0,
+            first_byte => self.fanout(first_byte - 1),
+        };
+        let mut high = self.fanout(first_byte);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.id_at(mid) < lower_bound {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        (low..self.object_count)
+            .map(|i| self.id_at(i))
+            .take_while(|id| id.starts_with_hex(prefix))
+            .collect()
+    }
+
+    fn fanout(&self, first_byte: u8) -> usize {
+        let start = 8 + first_byte as usize * 4;
+        u32::from_be_bytes(self.bytes[start..start + 4].try_into().unwrap()) as usize
+    }
+
+    fn id_at(&self, i: usize) -> ObjectId {
+        let id_len = self.format.id_len();
+        let start = 8 + 256 * 4 + i * id_len;
+        ObjectId::from_slice(&self.bytes[start..start + id_len]).unwrap()
+    }
+
+    fn offset_at(&self, i: usize) -> u64 {
+        let offsets_start = 8 + 256 * 4 + self.object_count * (self.format.id_len() + 4);
+        let start = offsets_start + i * 4;
+        let offset = u32::from_be_bytes(self.bytes[start..start + 4].try_into().unwrap());
+
+        if offset & 0x8000_0000 == 0 {
+            return offset as u64;
+        }
+
+        let large_start =
+            offsets_start + self.object_count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        u64::from_be_bytes(self.bytes[large_start..large_start + 8].try_into().unwrap())
+    }
+}
+
+/// What the index records about one object of a pack.
+pub(crate) struct PackIndexEntry {
+    pub(crate) id: ObjectId,
+    pub(crate) offset: u64,
+    pub(crate) crc32: u32,
+}
+
+pub(crate) fn build_index(
+    pack_checksum: &[u8],
+    entries: &[PackIndexEntry],
+    format: ObjectFormat,
+) -> Vec<u8> {
+    // \377tOc <version>
+    // <fanout: 256 x u32, number of objects with first id byte <= i>
+    // <sorted ids>
+    // <crc32 per id>
+    // <31 bit offsets, MSB set: index into the 64 bit table>
+    // <64 bit offsets>
+    // <pack checksum> <index checksum>
+    let mut entries = entries
+        .iter()
+        .map(|entry| (entry.id, entry.crc32, entry.offset))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.0);
+
+    let mut bytes = IDX_SIGNATURE.to_vec();
+    bytes.extend_from_slice(&IDX_VERSION.to_be_bytes());
+
+    let mut fanout = [0u32; 256];
+    for (id, _, _) in &entries {
+        fanout[id.as_bytes()[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        bytes.extend_from_slice(&total.to_be_bytes());
+    }
+
+    for (id, _, _) in &entries {
+        bytes.extend_from_slice(id.as_bytes());
+    }
+
+    for (_, crc32, _) in &entries {
+        bytes.extend_from_slice(&crc32.to_be_bytes());
+    }
+
+    let mut large_offsets = vec![];
+    for (_, _, offset) in &entries {
+        if *offset < 0x8000_0000 {
+            bytes.extend_from_slice(&(*offset as u32).to_be_bytes());
+        } else {
+            bytes.extend_from_slice(&(0x8000_0000 | large_offsets.len() as u32).to_be_bytes());
+            large_offsets.push(*offset);
+        }
+    }
+
+    for offset in large_offsets {
+        bytes.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    bytes.extend_from_slice(pack_checksum);
+
+    let checksum = format.hash(&bytes);
+    bytes.extend_from_slice(checksum.as_bytes());
+
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        object_id::{ObjectFormat, ObjectId},
+        pack::{PackObject, PackObjectType},
+        pack_index::{PackIndex, PackIndexEntry, build_index},
+    };
+
+    fn blob_entries(count: usize, format: ObjectFormat) -> Vec<PackIndexEntry> {
+        (0..count)
+            .map(|i| PackIndexEntry {
+                id: PackObject {
+                    kind: PackObjectType::Blob,
+                    decompressed_payload: format!("blob {}", i).into_bytes(),
+                }
+                .id(format)
+                .unwrap(),
+                offset: 12 + i as u64 * 10,
+                crc32: i as u32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_index() {
+        let index = build_index(
+            &[7u8; 20],
+            &blob_entries(3, ObjectFormat::Sha1),
+            ObjectFormat::Sha1,
+        );
+
+        assert_eq!(4 + 4 + 256 * 4 + 3 * (20 + 4 + 4) + 20 + 20, index.len());
+        assert_eq!(b"\xfftOc\0\0\0\x02".to_vec(), index[..8].to_vec());
+        assert_eq!(3u32.to_be_bytes(), index[8 + 255 * 4..8 + 256 * 4]);
+
+        let ids_start = 8 + 256 * 4;
+        let ids = index[ids_start..ids_start + 60]
+            .chunks(20)
+            .collect::<Vec<_>>();
+        assert!(ids[0] < ids[1] && ids[1] < ids[2]);
+        assert_eq!([7u8; 20], index[index.len() - 40..index.len() - 20]);
+    }
+
+    #[test]
+    fn test_find_in_index() {
+        for format in [ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let mut entries = blob_entries(100, format);
+            entries[42].offset = 0x1_0000_0000;
+
+            let pack_checksum = vec![0u8; format.id_len()];
+            let index =
+                PackIndex::parse(build_index(&pack_checksum, &entries, format), format).unwrap();
+
+            for entry in &entries {
+                assert_eq!(Some(entry.offset), index.find(&entry.id));
+            }
+            assert_eq!(None, index.find(&ObjectId::null(format)));
+        }
+    }
+
+    #[test]
+    fn test_reject_corrupt_index() {
+        let format = ObjectFormat::Sha1;
+        let mut entries = blob_entries(10, format);
+        entries[3].offset = 0x1_0000_0000;
+        let index = build_index(&[0u8; 20], &entries, format);
+
+        // Cut off in the middle of the 64 bit offsets.
+        let truncated = index[..index.len() - 2 * 20 - 4].to_vec();
+        assert!(PackIndex::parse(truncated, format).is_err());
+
+        // Cut off in the middle of the ids.
+        assert!(PackIndex::parse(index[..8 + 256 * 4 + 30].to_vec(), format).is_err());
+
+        // An object count far beyond the file.
+        let mut huge_count = index.clone();
+        huge_count[8 + 255 * 4..8 + 256 * 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(PackIndex::parse(huge_count, format).is_err());
+
+        // A fanout entry larger than the ones after it.
+        let mut non_monotonic = index.clone();
+        non_monotonic[8..12].copy_from_slice(&5u32.to_be_bytes());
+        assert!(PackIndex::parse(non_monotonic, format).is_err());
+
+        assert!(PackIndex::parse(index, format).is_ok());
+    }
+
+    #[test]
+    fn test_sha256_index_layout() {
+        let entries = blob_entries(3, ObjectFormat::Sha256);
+        let index = build_index(&[7u8; 32], &entries, ObjectFormat::Sha256);
+
+        assert_eq!(4 + 4 + 256 * 4 + 3 * (32 + 4 + 4) + 32 + 32, index.len());
+        assert_eq!([7u8; 32], index[index.len() - 64..index.len() - 32]);
+    }
+
+    #[test]
+    fn test_ids_with_prefix() {
+        let entries = blob_entries(100, ObjectFormat::Sha1);
+        let index = PackIndex::parse(
+            build_index(&[0u8; 20], &entries, ObjectFormat::Sha1),
+            ObjectFormat::Sha1,
+        )
+        .unwrap();
+
+        for entry in &entries {
+            let hex = entry.id.to_string();
+            assert_eq!(vec![entry.id], index.ids_with_prefix(&hex));
+            assert!(index.ids_with_prefix(&hex[..5]).contains(&entry.id));
+        }
+
+        let matching_count = entries
+            .iter()
+            .filter(|entry| entry.id.starts_with_hex("a"))
+            .count();
+        assert_eq!(matching_count, index.ids_with_prefix("a").len());
+    }
+}
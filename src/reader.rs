@@ -13,14 +13,30 @@ impl<'a, T> Reader<'a, T> {
         out
     }
 
+    pub(crate) fn try_pop(&mut self) -> Option<&'a T> {
+        if self.stream.is_empty() {
+            None
+        } else {
+            Some(self.pop())
+        }
+    }
+
     pub(crate) fn popn(&mut self, n: usize) -> &'a [T] {
         let out = &self.stream[..n];
         self.stream = &self.stream[n..];
         out
     }
 
+    pub(crate) fn try_popn(&mut self, n: usize) -> Option<&'a [T]> {
+        if self.stream.len() < n {
+            None
+        } else {
+            Some(self.popn(n))
+        }
+    }
+
     pub(crate) fn pop_all(&mut self) -> &'a [T] {
-        let out = &self.stream[..];
+        let out = self.stream;
         self.stream = &self.stream[self.stream.len()..];
         out
     }
@@ -47,23 +63,51 @@ impl<'a, T> Reader<'a, T> {
     pub(crate) fn is_empty(&self) -> bool {
         self.stream.is_empty()
     }
+
+    pub(crate) fn len(&self) -> usize {
+        self.stream.len()
+    }
 }
 
 impl<'a> Reader<'a, u8> {
-    pub(crate) fn pop_varint(&mut self) -> u64 {
+    /// Size varint of delta headers: 7 bit groups, least significant first. None if the stream
+    /// ends before the varint does or the value does not fit in 64 bits.
+    pub(crate) fn pop_varint(&mut self) -> Option<u64> {
         let mut result = 0u64;
+        let mut shift = 0;
 
         loop {
-            result <<= 7;
-            let byte = self.pop();
-            result |= (byte & 0b0111_1111) as u64;
+            let byte = self.try_pop()?;
+            let group = (byte & 0b0111_1111) as u64;
+            let bits = group.checked_shl(shift)?;
+            if bits >> shift != group {
+                return None;
+            }
+            result |= bits;
+            shift += 7;
 
             if byte & 0b1000_0000 == 0 {
                 break;
             }
         }
 
-        result
+        Some(result)
+    }
+
+    /// Offset varint of OFS_DELTA and index v4 paths: 7 bit groups, most significant first, with
+    /// each continuation adding one so that every value has a single encoding. None if the stream
+    /// ends before the varint does or the value does not fit in 64 bits.
+    pub(crate) fn pop_offset_varint(&mut self) -> Option<u64> {
+        let mut byte = *self.try_pop()?;
+        let mut result = (byte & 0b0111_1111) as u64;
+
+        while byte & 0b1000_0000 > 0 {
+            byte = *self.try_pop()?;
+            result = result.checked_add(1)?.checked_mul(1 << 7)?;
+            result |= (byte & 0b0111_1111) as u64;
+        }
+
+        Some(result)
     }
 
     pub(crate) fn pop_bit_masked_int(&mut self, mut mask: u8) -> usize {
@@ -84,6 +128,7 @@ impl<'a> Reader<'a, u8> {
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec)]
 mod test {
     use crate::reader::Reader;
 
@@ -96,4 +141,37 @@ mod test {
             reader.pop_bit_masked_int(0b1010)
         );
     }
+
+    #[test]
+    fn test_pop_varint() {
+        let v = [0b1001_0000u8, 0b0000_0001, 0b0000_0101];
+        let mut reader = Reader::new(&v[..]);
+        assert_eq!(Some(16 + (1 << 7)), reader.pop_varint());
+        assert_eq!(Some(5), reader.pop_varint());
+        assert_eq!(None, reader.pop_varint());
+    }
+
+    #[test]
+    fn test_pop_offset_varint() {
+        let v = [0b1000_0000u8, 0b0000_0000, 0b0111_1111, 0b1000_0001];
+        let mut reader = Reader::new(&v[..]);
+        assert_eq!(Some(128), reader.pop_offset_varint());
+        assert_eq!(Some(127), reader.pop_offset_varint());
+        assert_eq!(None, reader.pop_offset_varint());
+
+        let v = [0xffu8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(None, Reader::new(&v[..]).pop_offset_varint());
+    }
+
+    #[test]
+    fn test_pop_varint_overflow() {
+        let mut v = vec![0xffu8; 9];
+        v.push(0x01);
+        assert_eq!(Some(u64::MAX), Reader::new(&v[..]).pop_varint());
+        *v.last_mut().unwrap() = 0x02;
+        assert_eq!(None, Reader::new(&v[..]).pop_varint());
+        let mut v = vec![0x80u8; 10];
+        v.push(0x01);
+        assert_eq!(None, Reader::new(&v[..]).pop_varint());
+    }
 }
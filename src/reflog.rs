@@ -0,0 +1,413 @@
+//! Reflogs: the history of a ref, one line per update in `logs/<ref>`, oldest first.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    commit::Identity,
+    config::Config,
+    date,
+    error::{Error, IoResultExt, Result},
+    object_id::ObjectId,
+    refs,
+};
+
+/// One update of a ref, from `old` to `new` (the null id for a ref that did not exist, or
+/// that got deleted).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old: ObjectId,
+    pub new: ObjectId,
+    pub committer: Identity,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    // <old id> <new id> <name> <<email>> <timestamp> <timezone>\t<message>
+    pub fn parse(line: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("invalid reflog entry: {}", line);
+
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let (old, rest) = header.split_once(' ').ok_or_else(invalid)?;
+        let (new, committer) = rest.split_once(' ').ok_or_else(invalid)?;
+
+        Ok(Self {
+            old: ObjectId::from_hex(old).ok_or_else(invalid)?,
+            new: ObjectId::from_hex(new).ok_or_else(invalid)?,
+            committer: Identity::parse(committer.as_bytes())?,
+            message: message.to_string(),
+        })
+    }
+
+    /// The line of the entry, with its newline. An empty message leaves out the tab.
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{} {} {}",
+            self.old,
+            self.new,
+            String::from_utf8_lossy(&self.committer.to_bytes())
+        );
+        if !self.message.is_empty() {
+            line.push('\t');
+            line.push_str(&self.message);
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// The entries of the reflog of `name`, oldest first, None if the ref has no reflog.
+pub fn read(git_dir: &Path, name: &str) -> Result<Option<Vec<ReflogEntry>>> {
+    let log_path = git_dir.join("logs").join(name);
+    let content = match fs::read_to_string(&log_path) {
+        Ok(content) => content,
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::IsADirectory
+            ) =>
+        {
+            return Ok(None);
+        }
+        Err(err) => return Err(err).with_path(log_path),
+    };
+
+    content
+        .lines()
+        .map(|line| ReflogEntry::parse(line).map_err(Error::Other))
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+pub fn exists(git_dir: &Path, name: &str) -> bool {
+    git_dir.join("logs").join(name).is_file()
+}
+
+/// Names of the refs that have a reflog, sorted.
+pub fn list(git_dir: &Path) -> Result<Vec<String>> {
+    fn collect(logs_dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+        let dir_path = logs_dir.join(prefix);
+        let dir_entries = match fs::read_dir(&dir_path) {
+            Ok(dir_entries) => dir_entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_path(dir_path),
+        };
+
+        for dir_entry in dir_entries {
+            let dir_entry = dir_entry.with_path(&dir_path)?;
+            let name = match prefix {
+                "" => dir_entry.file_name().to_string_lossy().to_string(),
+                prefix => format!("{}/{}", prefix, dir_entry.file_name().to_string_lossy()),
+            };
+            if dir_entry.path().is_dir() {
+                collect(logs_dir, &name, names)?;
+            } else if !name.ends_with(".lock") {
+                names.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    let mut names = vec![];
+    collect(&git_dir.join("logs"), "", &mut names)?;
+    names.sort();
+    Ok(names)
+}
+
+/// Records an update of `name`, if its updates are logged: when it already has a reflog, or
+/// following `core.logAllRefUpdates` (HEAD, branches, remote-tracking branches and notes, unless
+/// the repository is bare).
+pub(crate) fn append(git_dir: &Path, name: &str, entry: &ReflogEntry) -> Result<()> {
+    if !exists(git_dir, name) && !logs_updates(git_dir, name)? {
+        return Ok(());
+    }
+
+    let log_path = git_dir.join("logs").join(name);
+    if let Some(folder_path) = log_path.parent() {
+        fs::create_dir_all(folder_path).with_path(folder_path)?;
+    }
+
+    // Messages are kept on their line.
+    let entry = ReflogEntry {
+        message: entry
+            .message
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        ..entry.clone()
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .and_then(|mut file| file.write_all(entry.to_line().as_bytes()))
+        .with_path(log_path)
+}
+
+fn logs_updates(git_dir: &Path, name: &str) -> Result<bool> {
+    let config = Config::read_at(git_dir)?;
+    let setting = config.get("core", None, "logallrefupdates");
+    if setting.is_some_and(|setting| setting.eq_ignore_ascii_case("always")) {
+        return Ok(true);
+    }
+
+    let enabled = match setting {
+        Some(_) => config.get_bool("core", None, "logallrefupdates") == Some(true),
+        None => config.get_bool("core", None, "bare") != Some(true),
+    };
+    Ok(enabled
+        && (name == "HEAD"
+            || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))))
+}
+
+/// Replaces the reflog of `name` with `entries`, through a lock file.
+pub fn write(git_dir: &Path, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+    let log_path = git_dir.join("logs").join(name);
+    let lock_path = git_dir.join("logs").join(format!("{}.lock", name));
+
+    let mut lock = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_path)
+        .with_path(&lock_path)?;
+    let content = entries.iter().map(ReflogEntry::to_line).collect::<String>();
+    if let Err(err) = lock.write_all(content.as_bytes()) {
+        let _ = fs::remove_file(&lock_path);
+        return Err(err).with_path(lock_path);
+    }
+
+    fs::rename(&lock_path, &log_path).with_path(log_path)
+}
+
+/// The full name of the ref whose reflog `name` designates.
+pub fn ref_name(git_dir: &Path, name: &str) -> Result<String> {
+    refs::full_name(git_dir, name)?.ok_or_else(|| Error::UnknownRevision(name.to_string()))
+}
+
+/// Prunes the entries older than `expire` from the reflogs of `names`, or of every ref with
+/// `all`. `expire` is a date, `all` pruning every entry and `never` none.
+pub fn expire(git_dir: &Path, expire: &str, all: bool, names: &[String]) -> Result<()> {
+    let expire = match expire {
+        "never" | "false" => return Ok(()),
+        "all" => i64::MAX,
+        expire => {
+            date::parse(expire)
+                .ok_or_else(|| Error::Usage(format!("invalid timestamp '{}'", expire)))?
+                .0
+        }
+    };
+
+    let names = match all {
+        true => list(git_dir)?,
+        false => names
+            .iter()
+            .map(|name| ref_name(git_dir, name))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    for name in names {
+        let Some(mut entries) = read(git_dir, &name)? else {
+            continue;
+        };
+        let len = entries.len();
+        entries.retain(|entry| entry.committer.timestamp >= expire);
+        if entries.len() != len {
+            write(git_dir, &name, &entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes reflog entries named `<ref>@{<n>}`, `n` counting from the newest entry. With
+/// `rewrite`, each remaining entry starts where the one before it ends, keeping the log a chain.
+pub fn delete_entries(git_dir: &Path, specs: &[String], rewrite: bool) -> Result<()> {
+    let mut deleted_by_ref: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+    for spec in specs {
+        let not_a_reflog = || Error::Other(format!("not a reflog: {}", spec));
+        let (name, nth) = spec.split_once("@{").ok_or_else(not_a_reflog)?;
+        let nth = nth
+            .strip_suffix('}')
+            .and_then(|nth| nth.parse::<usize>().ok())
+            .ok_or_else(|| Error::Other(format!("invalid reflog entry: {}", spec)))?;
+        let name = match name {
+            "" => refs::resolve_symbolic(git_dir, "HEAD")?,
+            name => ref_name(git_dir, name)?,
+        };
+        if !exists(git_dir, &name) {
+            return Err(not_a_reflog());
+        }
+        deleted_by_ref.entry(name).or_default().insert(nth);
+    }
+
+    for (name, deleted) in deleted_by_ref {
+        let entries = read(git_dir, &name)?.unwrap_or_default();
+        let mut kept: Vec<ReflogEntry> = vec![];
+        let len = entries.len();
+        for (i, mut entry) in entries.into_iter().enumerate() {
+            if deleted.contains(&(len - 1 - i)) {
+                continue;
+            }
+            if rewrite {
+                entry.old = match kept.last() {
+                    Some(previous) => previous.new,
+                    None => ObjectId::null(entry.new.format()),
+                };
+            }
+            kept.push(entry);
+        }
+        write(git_dir, &name, &kept)?;
+    }
+    Ok(())
+}
+
+/// Deletes the reflog of `name`, which may not have one.
+pub(crate) fn delete(git_dir: &Path, name: &str) -> Result<()> {
+    let log_path = git_dir.join("logs").join(name);
+    match fs::remove_file(&log_path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_path(log_path),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        commit::Identity,
+        object_id::ObjectId,
+        reflog::{self, ReflogEntry},
+        test_util::TempDir,
+    };
+    use std::{fs, path::Path};
+
+    /// A git directory whose `main` branch has a reflog of four entries, made at timestamps
+    /// 100 to 400, each moving the branch from the id of `n - 1` to the one of `n`.
+    fn test_git_dir(name: &str) -> TempDir {
+        let git_dir = TempDir::new(name);
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        fs::create_dir_all(git_dir.join("logs/refs/heads")).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(git_dir.join("refs/heads/main"), format!("{}\n", id(4))).unwrap();
+
+        let entries = (1..=4)
+            .map(|n| ReflogEntry {
+                old: id(n - 1),
+                new: id(n),
+                committer: Identity {
+                    name: b"A U Thor".to_vec(),
+                    email: b"author@example.com".to_vec(),
+                    timestamp: n as i64 * 100,
+                    timezone: "+0000".to_string(),
+                    space_before_email: true,
+                },
+                message: format!("update {}", n),
+            })
+            .collect::<Vec<_>>();
+        reflog::write(&git_dir, "refs/heads/main", &entries).unwrap();
+        git_dir
+    }
+
+    /// The null id for 0, else an id made of the digit `n`.
+    fn id(n: usize) -> ObjectId {
+        n.to_string().repeat(40).parse().unwrap()
+    }
+
+    fn messages(git_dir: &Path) -> Vec<String> {
+        reflog::read(git_dir, "refs/heads/main")
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_expire() {
+        let git_dir = test_git_dir("reflog_expire");
+
+        reflog::expire(&git_dir, "never", false, &["main".to_string()]).unwrap();
+        assert_eq!(4, messages(&git_dir).len());
+
+        reflog::expire(&git_dir, "@250 +0000", false, &["main".to_string()]).unwrap();
+        assert_eq!(vec!["update 3", "update 4"], messages(&git_dir));
+
+        reflog::expire(&git_dir, "all", true, &[]).unwrap();
+        assert!(messages(&git_dir).is_empty());
+
+        assert!(reflog::expire(&git_dir, "someday", false, &["main".to_string()]).is_err());
+        assert!(reflog::expire(&git_dir, "all", false, &["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_delete_entries() {
+        let git_dir = test_git_dir("reflog_delete");
+
+        // `main@{1}` is the second newest entry, `@{0}` the newest of the branch HEAD is on.
+        reflog::delete_entries(&git_dir, &["main@{1}".to_string()], false).unwrap();
+        assert_eq!(vec!["update 1", "update 2", "update 4"], messages(&git_dir));
+        let entries = reflog::read(&git_dir, "refs/heads/main").unwrap().unwrap();
+        assert_eq!(id(3), entries[2].old);
+
+        reflog::delete_entries(
+            &git_dir,
+            &["@{0}".to_string(), "refs/heads/main@{2}".to_string()],
+            true,
+        )
+        .unwrap();
+        let entries = reflog::read(&git_dir, "refs/heads/main").unwrap().unwrap();
+        assert_eq!(
+            vec![(id(0), id(2))],
+            entries
+                .iter()
+                .map(|entry| (entry.old, entry.new))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(reflog::delete_entries(&git_dir, &["main".to_string()], false).is_err());
+        assert!(reflog::delete_entries(&git_dir, &["main@{x}".to_string()], false).is_err());
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let line = "0000000000000000000000000000000000000000 \
+                    41b4ce8b7730b0c6fe700cd8239756eeda5dd3af \
+                    A U Thor <author@example.com> 1700000001 +0200\tcommit (initial): c1\n";
+        let entry = ReflogEntry::parse(line.trim_end()).unwrap();
+        assert!(entry.old.is_null());
+        assert_eq!(
+            "41b4ce8b7730b0c6fe700cd8239756eeda5dd3af",
+            entry.new.to_string()
+        );
+        assert_eq!(
+            Identity {
+                name: b"A U Thor".to_vec(),
+                email: b"author@example.com".to_vec(),
+                timestamp: 1700000001,
+                timezone: "+0200".to_string(),
+                space_before_email: true,
+            },
+            entry.committer
+        );
+        assert_eq!("commit (initial): c1", entry.message);
+        assert_eq!(line, entry.to_line());
+
+        let deletion = "41b4ce8b7730b0c6fe700cd8239756eeda5dd3af \
+                        0000000000000000000000000000000000000000 \
+                        A U Thor <author@example.com> 1700000002 +0200";
+        let entry = ReflogEntry::parse(deletion).unwrap();
+        assert_eq!("", entry.message);
+        assert_eq!(format!("{}\n", deletion), entry.to_line());
+        // Rewritten reflogs keep the tab.
+        assert_eq!(
+            entry,
+            ReflogEntry::parse(&format!("{}\t", deletion)).unwrap()
+        );
+
+        assert!(ReflogEntry::parse("41b4ce8b 0000 A <a> 1 +0000").is_err());
+    }
+}
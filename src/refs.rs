@@ -0,0 +1,890 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    commit::Identity,
+    error::{Error, IoResultExt, Result},
+    object_id::ObjectId,
+    reflog::{self, ReflogEntry},
+};
+
+/// Where a short ref name is looked up, in order, like git's `ref_rev_parse_rules`.
+const SEARCH_PREFIXES: [&str; 5] = ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"];
+
+/// Symbolic refs pointing at symbolic refs are followed at most this many times.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+
+/// A ref and the object it points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ref {
+    pub name: String,
+    pub id: ObjectId,
+    /// The object an annotated tag points at, when `packed-refs` records it.
+    pub peeled: Option<ObjectId>,
+    /// The ref this one points at if it is a symbolic ref, `id` then being the resolved value.
+    pub symref_target: Option<String>,
+}
+
+/// What a ref file contains.
+#[derive(Debug, PartialEq, Eq)]
+enum RefValue {
+    Direct(ObjectId),
+    Symbolic(String),
+}
+
+/// Whether `name` is a valid full ref name (`refs/heads/main`, or a pseudoref like `HEAD`),
+/// following git-check-ref-format(1).
+pub fn is_valid_name(name: &str) -> bool {
+    (name.starts_with("refs/") || is_pseudoref(name))
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.ends_with('.')
+        && !name.chars().any(|c| {
+            c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+        })
+        && name.split('/').all(|component| {
+            !component.is_empty() && !component.starts_with('.') && !component.ends_with(".lock")
+        })
+}
+
+/// Whether `name` looks like `HEAD` or another ref kept at the top of the git directory
+/// (`ORIG_HEAD`, `FETCH_HEAD`): upper case letters and underscores only.
+fn is_pseudoref(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|c| c.is_ascii_uppercase() || c == b'_')
+}
+
+/// Resolves the HEAD of the repository whose git directory is `git_dir`, following it if it is a
+/// symbolic ref (`ref: refs/heads/main`).
+pub fn read_head(git_dir: &Path) -> Result<ObjectId> {
+    read_ref_at(git_dir, "HEAD", 0)?.ok_or_else(|| Error::UnknownRevision("HEAD".to_string()))
+}
+
+/// Resolves a full (`refs/heads/main`) or short (`main`, `v1.0`, `origin/main`) ref name, None
+/// if no ref has that name.
+pub fn resolve(git_dir: &Path, name: &str) -> Result<Option<ObjectId>> {
+    match full_name(git_dir, name)? {
+        Some(full_name) => read_ref_at(git_dir, &full_name, 0),
+        None => Ok(None),
+    }
+}
+
+/// The full name of the ref a short name stands for, None if no ref has that name.
+pub fn full_name(git_dir: &Path, name: &str) -> Result<Option<String>> {
+    if name.is_empty()
+        || name
+            .split('/')
+            .any(|component| component.is_empty() || component == "..")
+    {
+        return Ok(None);
+    }
+
+    let packed_refs = read_packed_refs(git_dir)?;
+    Ok(SEARCH_PREFIXES
+        .iter()
+        // Other files of the git directory (`index`, `config`) are not refs.
+        .filter(|prefix| !prefix.is_empty() || name.starts_with("refs/") || is_pseudoref(name))
+        .map(|prefix| format!("{}{}", prefix, name))
+        // A remote name alone stands for its default branch.
+        .chain([format!("refs/remotes/{}/HEAD", name)])
+        .find(|full_name| {
+            git_dir.join(full_name).is_file()
+                || packed_refs.iter().any(|packed| packed.name == *full_name)
+        }))
+}
+
+/// The ref a symbolic ref points at (`refs/heads/main` for HEAD on main), None if `name` is not
+/// a symbolic ref.
+pub fn read_symbolic(git_dir: &Path, name: &str) -> Result<Option<String>> {
+    match read_value(git_dir, name)? {
+        Some(RefValue::Symbolic(target)) => Ok(Some(target)),
+        _ => Ok(None),
+    }
+}
+
+/// The ref `name` leads to once symbolic refs are followed, which may not exist yet (the branch
+/// of a new repository for HEAD).
+pub fn resolve_symbolic(git_dir: &Path, name: &str) -> Result<String> {
+    let mut name = name.to_string();
+    for _ in 0..=MAX_SYMREF_DEPTH {
+        match read_symbolic(git_dir, &name)? {
+            Some(target) => name = target,
+            None => return Ok(name),
+        }
+    }
+    Err(Error::Other(format!("symbolic ref loop at {}", name)))
+}
+
+fn read_ref_at(git_dir: &Path, name: &str, depth: usize) -> Result<Option<ObjectId>> {
+    match read_value(git_dir, name)? {
+        Some(RefValue::Symbolic(_)) if depth == MAX_SYMREF_DEPTH => {
+            Err(Error::Other(format!("symbolic ref loop at {}", name)))
+        }
+        Some(RefValue::Symbolic(target)) => read_ref_at(git_dir, &target, depth + 1),
+        Some(RefValue::Direct(id)) => Ok(Some(id)),
+        None => Ok(None),
+    }
+}
+
+/// The value of a ref, from its loose file or else from `packed-refs`.
+fn read_value(git_dir: &Path, name: &str) -> Result<Option<RefValue>> {
+    match read_loose(git_dir, name)? {
+        Some(value) => Ok(Some(value)),
+        None => Ok(read_packed_refs(git_dir)?
+            .into_iter()
+            .find(|packed| packed.name == name)
+            .map(|packed| RefValue::Direct(packed.id))),
+    }
+}
+
+fn read_loose(git_dir: &Path, name: &str) -> Result<Option<RefValue>> {
+    let ref_path = git_dir.join(name);
+    if !ref_path.is_file() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(&ref_path).with_path(&ref_path)?;
+    let content = content.trim_end();
+
+    match content.strip_prefix("ref: ") {
+        Some(target) => Ok(Some(RefValue::Symbolic(target.to_string()))),
+        None => ObjectId::from_hex(content)
+            .map(|id| Some(RefValue::Direct(id)))
+            .ok_or_else(|| Error::Other(format!("{}: invalid sha1 pointer", name))),
+    }
+}
+
+/// Every ref under `refs/`, loose or packed, sorted by name. Symbolic refs are resolved,
+/// dangling ones are left out.
+pub fn list(git_dir: &Path) -> Result<Vec<Ref>> {
+    let mut refs = read_packed_refs(git_dir)?
+        .into_iter()
+        .map(|packed| (packed.name.clone(), packed))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut loose_names = vec![];
+    collect_loose_names(git_dir, "refs", &mut loose_names)?;
+    for name in loose_names {
+        let (id, symref_target) = match read_loose(git_dir, &name)? {
+            Some(RefValue::Direct(id)) => (id, None),
+            Some(RefValue::Symbolic(target)) => match read_ref_at(git_dir, &target, 1)? {
+                Some(id) => (id, Some(target)),
+                None => {
+                    refs.remove(&name);
+                    continue;
+                }
+            },
+            None => continue,
+        };
+
+        // A loose ref overrides its packed value, peeled id included.
+        refs.insert(
+            name.clone(),
+            Ref {
+                name,
+                id,
+                peeled: None,
+                symref_target,
+            },
+        );
+    }
+
+    Ok(refs.into_values().collect())
+}
+
+fn collect_loose_names(git_dir: &Path, dir: &str, names: &mut Vec<String>) -> Result<()> {
+    let dir_path = git_dir.join(dir);
+    let dir_entries = match fs::read_dir(&dir_path) {
+        Ok(dir_entries) => dir_entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_path(dir_path),
+    };
+
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry.with_path(&dir_path)?;
+        let name = format!("{}/{}", dir, dir_entry.file_name().to_string_lossy());
+        if dir_entry.path().is_dir() {
+            collect_loose_names(git_dir, &name, names)?;
+        } else if !name.ends_with(".lock") {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// The refs of `packed-refs`, a missing file having none.
+fn read_packed_refs(git_dir: &Path) -> Result<Vec<Ref>> {
+    let packed_refs_path = git_dir.join("packed-refs");
+    match fs::read_to_string(&packed_refs_path) {
+        Ok(content) => parse_packed_refs(&content),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err).with_path(packed_refs_path),
+    }
+}
+
+/// Parses `packed-refs`: a `<id> <name>` line per ref, an annotated tag being followed by a
+/// `^<id>` line with the object it points at.
+fn parse_packed_refs(content: &str) -> Result<Vec<Ref>> {
+    let mut refs: Vec<Ref> = vec![];
+    for line in content.lines() {
+        let unexpected_line = || Error::Other(format!("unexpected line in packed-refs: {}", line));
+
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            let last = refs.last_mut().ok_or_else(unexpected_line)?;
+            last.peeled = Some(ObjectId::from_hex(peeled).ok_or_else(unexpected_line)?);
+            continue;
+        }
+
+        let (id, name) = line.split_once(' ').ok_or_else(unexpected_line)?;
+        refs.push(Ref {
+            name: name.to_string(),
+            id: ObjectId::from_hex(id).ok_or_else(unexpected_line)?,
+            peeled: None,
+            symref_target: None,
+        });
+    }
+    Ok(refs)
+}
+
+fn packed_refs_to_string(refs: &[Ref]) -> String {
+    let mut content = PACKED_REFS_HEADER.to_string();
+    for packed in refs {
+        content.push_str(&format!("{} {}\n", packed.id, packed.name));
+        if let Some(peeled) = packed.peeled {
+            content.push_str(&format!("^{}\n", peeled));
+        }
+    }
+    content
+}
+
+/// Points `name` at `new`. With `old`, the update only happens if the ref currently is at
+/// `old`, the null id meaning that it must not exist yet. A symbolic ref is replaced, not
+/// followed, see `resolve_symbolic` to update the ref it points at.
+///
+/// The update is recorded in the reflog of the ref, and in the one of HEAD when HEAD points at
+/// the ref.
+pub fn update(
+    git_dir: &Path,
+    name: &str,
+    new: &ObjectId,
+    old: Option<&ObjectId>,
+    committer: &Identity,
+    message: &str,
+) -> Result<()> {
+    let lock = RefLock::acquire(git_dir, name)?;
+    let current = check_old_value(git_dir, name, old)?;
+    lock.commit(&format!("{}\n", new))?;
+
+    let entry = ReflogEntry {
+        old: current.unwrap_or(ObjectId::null(new.format())),
+        new: *new,
+        committer: committer.clone(),
+        message: message.to_string(),
+    };
+    reflog::append(git_dir, name, &entry)?;
+    if name != "HEAD" && read_symbolic(git_dir, "HEAD")?.as_deref() == Some(name) {
+        reflog::append(git_dir, "HEAD", &entry)?;
+    }
+    Ok(())
+}
+
+/// Adds `refs` to `packed-refs`, replacing packed refs of the same names. Unlike `update`,
+/// nothing is checked or logged: this is how clone stores what it fetched.
+pub fn add_packed(git_dir: &Path, refs: &[Ref]) -> Result<()> {
+    let packed_lock = RefLock::acquire(git_dir, "packed-refs")?;
+    let packed_refs = read_packed_refs(git_dir)?
+        .into_iter()
+        .chain(refs.iter().cloned())
+        .map(|packed| (packed.name.clone(), packed))
+        .collect::<BTreeMap<_, _>>()
+        .into_values()
+        .collect::<Vec<_>>();
+    packed_lock.commit(&packed_refs_to_string(&packed_refs))
+}
+
+/// Makes `name` a symbolic ref pointing at the ref `target`.
+pub fn write_symbolic(git_dir: &Path, name: &str, target: &str) -> Result<()> {
+    RefLock::acquire(git_dir, name)?.commit(&format!("ref: {}\n", target))
+}
+
+/// Deletes a ref, loose and packed, and its reflog, checking its value first like `update`
+/// does. HEAD's reflog records the deletion if HEAD pointed at the ref.
+pub fn delete(
+    git_dir: &Path,
+    name: &str,
+    old: Option<&ObjectId>,
+    committer: &Identity,
+    message: &str,
+) -> Result<()> {
+    let lock = RefLock::acquire(git_dir, name)?;
+    let current = check_old_value(git_dir, name, old)?;
+
+    let ref_path = git_dir.join(name);
+    match fs::remove_file(&ref_path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_path(ref_path),
+    }
+
+    // Read under the lock, so that a concurrent update of `packed-refs` is not lost.
+    let packed_lock = RefLock::acquire(git_dir, "packed-refs")?;
+    let mut packed_refs = read_packed_refs(git_dir)?;
+    if packed_refs.iter().any(|packed| packed.name == name) {
+        packed_refs.retain(|packed| packed.name != name);
+        packed_lock.commit(&packed_refs_to_string(&packed_refs))?;
+    }
+    reflog::delete(git_dir, name)?;
+
+    if let Some(current) = current
+        && name != "HEAD"
+        && read_symbolic(git_dir, "HEAD")?.as_deref() == Some(name)
+    {
+        let entry = ReflogEntry {
+            old: current,
+            new: ObjectId::null(current.format()),
+            committer: committer.clone(),
+            message: message.to_string(),
+        };
+        reflog::append(git_dir, "HEAD", &entry)?;
+    }
+
+    drop(lock);
+    Ok(())
+}
+
+/// The current value of the ref, once checked against `old` when given.
+fn check_old_value(git_dir: &Path, name: &str, old: Option<&ObjectId>) -> Result<Option<ObjectId>> {
+    let current = read_ref_at(git_dir, name, 0)?;
+    let Some(old) = old else {
+        return Ok(current);
+    };
+
+    let reason = match current {
+        Some(_) if old.is_null() => "reference already exists".to_string(),
+        None if !old.is_null() => format!("unable to resolve reference '{}'", name),
+        Some(current) if current != *old => {
+            format!("is at {} but expected {}", current, old)
+        }
+        _ => return Ok(current),
+    };
+    Err(Error::CannotLockRef {
+        name: name.to_string(),
+        reason,
+    })
+}
+
+/// A `<ref>.lock` file, which keeps other writers out while a ref is rewritten and then
+/// atomically replaces it. Dropping the lock without committing releases it.
+struct RefLock {
+    file: File,
+    path: PathBuf,
+    lock_path: PathBuf,
+    committed: bool,
+}
+
+impl RefLock {
+    fn acquire(git_dir: &Path, name: &str) -> Result<Self> {
+        let cannot_lock = |reason: String| Error::CannotLockRef {
+            name: name.to_string(),
+            reason,
+        };
+
+        // `refs/heads/a` and `refs/heads/a/b` cannot both exist.
+        let path = git_dir.join(name);
+        if path.is_dir() && fs::read_dir(&path).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(cannot_lock(format!("there are refs under '{}/'", name)));
+        }
+        let mut parent = name;
+        while let Some((folder, _)) = parent.rsplit_once('/') {
+            if git_dir.join(folder).is_file() {
+                return Err(cannot_lock(format!(
+                    "'{}' exists; cannot create '{}'",
+                    folder, name
+                )));
+            }
+            parent = folder;
+        }
+
+        if let Some(folder_path) = path.parent() {
+            fs::create_dir_all(folder_path).with_path(folder_path)?;
+        }
+        // An empty directory left by deleted refs is in the way of the ref file.
+        if path.is_dir() {
+            fs::remove_dir(&path).with_path(&path)?;
+        }
+
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(cannot_lock(format!(
+                    "Unable to create '{}': File exists.",
+                    lock_path.display()
+                )));
+            }
+            Err(err) => return Err(err).with_path(lock_path),
+        };
+
+        Ok(Self {
+            file,
+            path,
+            lock_path,
+            committed: false,
+        })
+    }
+
+    fn commit(mut self, content: &str) -> Result<()> {
+        self.file
+            .write_all(content.as_bytes())
+            .with_path(&self.lock_path)?;
+        fs::rename(&self.lock_path, &self.path).with_path(&self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for RefLock {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+/// The `for-each-ref` format used without `--format`.
+pub const DEFAULT_FORMAT: &str = "%(objectname) %(objecttype)\t%(refname)";
+
+/// A piece of a `for-each-ref` format.
+pub enum FormatPart {
+    Literal(Vec<u8>),
+    Field(RefField),
+}
+
+/// A `%(<name>[:<modifier>])` placeholder of a `for-each-ref` format, `*` before the name
+/// meaning that it applies to the object an annotated tag points at.
+pub struct RefField {
+    pub name: String,
+    pub modifier: Option<String>,
+    pub deref: bool,
+}
+
+impl RefField {
+    pub fn parse(field: &str) -> Result<Self> {
+        let (deref, field) = match field.strip_prefix('*') {
+            Some(field) => (true, field),
+            None => (false, field),
+        };
+        let (name, modifier) = match field.split_once(':') {
+            Some((name, modifier)) => (name, Some(modifier)),
+            None => (field, None),
+        };
+
+        let modifier_ok = match (name, modifier) {
+            (_, None) => true,
+            ("refname" | "symref", Some(modifier)) => {
+                modifier == "short"
+                    || modifier
+                        .strip_prefix("lstrip=")
+                        .or_else(|| modifier.strip_prefix("strip="))
+                        .is_some_and(|n| n.parse::<isize>().is_ok())
+            }
+            ("objectname", Some(modifier)) => modifier == "short",
+            ("objecttype" | "objectsize" | "HEAD", Some(_)) => {
+                return Err(Error::Other(format!("%({}) does not take arguments", name)));
+            }
+            _ => false,
+        };
+
+        match name {
+            "refname" | "symref" | "objectname" | "objecttype" | "objectsize" | "HEAD" => {}
+            _ => return Err(Error::Other(format!("unknown field name: {}", field))),
+        }
+        if !modifier_ok {
+            return Err(Error::Other(format!(
+                "unrecognized %({}) argument: {}",
+                field,
+                modifier.unwrap_or_default()
+            )));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            modifier: modifier.map(str::to_string),
+            deref,
+        })
+    }
+
+    /// Applies `:short`, `:lstrip=<n>` or `:strip=<n>` to a ref name, a negative `n` keeping
+    /// that many components at the end.
+    pub fn shorten_name(&self, name: &str) -> String {
+        let Some(modifier) = &self.modifier else {
+            return name.to_string();
+        };
+        let Some(n) = modifier
+            .strip_prefix("lstrip=")
+            .or_else(|| modifier.strip_prefix("strip="))
+            .and_then(|n| n.parse::<isize>().ok())
+        else {
+            return shorten(name).to_string();
+        };
+
+        let components = name.split('/').collect::<Vec<_>>();
+        let skipped = match n < 0 {
+            true => components.len().saturating_sub(n.unsigned_abs()),
+            false => (n as usize).min(components.len()),
+        };
+        components[skipped..].join("/")
+    }
+}
+
+/// A `for-each-ref --sort` key: a field, descending with a `-` prefix.
+pub struct SortKey {
+    pub field: RefField,
+    pub descending: bool,
+}
+
+impl SortKey {
+    pub fn parse(key: &str) -> Result<Self> {
+        Ok(match key.strip_prefix('-') {
+            Some(key) => Self {
+                field: RefField::parse(key)?,
+                descending: true,
+            },
+            None => Self {
+                field: RefField::parse(key)?,
+                descending: false,
+            },
+        })
+    }
+
+    /// Compares the values of this key for two refs, numerically for `objectsize`.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        let ordering = match self.field.name.as_str() {
+            "objectsize" => a
+                .parse::<u64>()
+                .unwrap_or_default()
+                .cmp(&b.parse::<u64>().unwrap_or_default()),
+            _ => a.cmp(b),
+        };
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+}
+
+/// Splits a `for-each-ref` format into literal text and fields; `%%` is a percent sign and
+/// `%<hex><hex>` the byte with that value.
+pub fn parse_format(format: &str) -> Result<Vec<FormatPart>> {
+    let mut parts = vec![];
+    let mut literal = vec![];
+    let mut rest = format;
+
+    while let Some(percent) = rest.find('%') {
+        literal.extend_from_slice(&rest.as_bytes()[..percent]);
+        rest = &rest[percent + 1..];
+
+        if let Some(field) = rest.strip_prefix('(') {
+            let (field, after) = field
+                .split_once(')')
+                .ok_or_else(|| Error::Usage(format!("malformed format string %({}", field)))?;
+            parts.push(FormatPart::Literal(std::mem::take(&mut literal)));
+            parts.push(FormatPart::Field(RefField::parse(field)?));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('%') {
+            literal.push(b'%');
+            rest = after;
+        } else if let Some(byte) = rest
+            .get(..2)
+            .filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            literal.push(byte);
+            rest = &rest[2..];
+        } else {
+            literal.push(b'%');
+        }
+    }
+    literal.extend_from_slice(rest.as_bytes());
+    parts.push(FormatPart::Literal(literal));
+
+    Ok(parts)
+}
+
+/// The short name git shows for a ref: `main` for `refs/heads/main`, `origin/main` for
+/// `refs/remotes/origin/main`.
+pub fn shorten(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// Whether a ref name matches a `for-each-ref` pattern: a glob (`refs/tags/v*`, `*` not
+/// matching `/`), or a literal matching whole leading components (`refs/heads`).
+pub fn matches_pattern(name: &str, pattern: &str) -> bool {
+    if pattern.contains(['*', '?', '[']) {
+        return wildmatch(pattern.as_bytes(), name.as_bytes());
+    }
+
+    let pattern = pattern.trim_end_matches('/');
+    name == pattern
+        || name
+            .strip_prefix(pattern)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Glob matching where wildcards never match a `/`.
+fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => (0..=text.len())
+            .take_while(|&skipped| !text[..skipped].contains(&b'/'))
+            .any(|skipped| wildmatch(&pattern[1..], &text[skipped..])),
+        Some(b'?') => {
+            text.first().is_some_and(|&c| c != b'/') && wildmatch(&pattern[1..], &text[1..])
+        }
+        Some(b'[') => {
+            let Some(end) = pattern
+                .iter()
+                .skip(2)
+                .position(|&c| c == b']')
+                .map(|i| i + 2)
+            else {
+                return text.first() == Some(&b'[') && wildmatch(&pattern[1..], &text[1..]);
+            };
+            let (negated, set) = match pattern[1] {
+                b'!' | b'^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let Some(&c) = text.first().filter(|&&c| c != b'/') else {
+                return false;
+            };
+
+            let mut in_set = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == b'-' {
+                    in_set |= (set[i]..=set[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    in_set |= set[i] == c;
+                    i += 1;
+                }
+            }
+            in_set != negated && wildmatch(&pattern[end + 1..], &text[1..])
+        }
+        Some(&c) => text.first() == Some(&c) && wildmatch(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        object_id::ObjectId,
+        refs::{
+            FormatPart, Ref, RefField, SortKey, full_name, is_valid_name, matches_pattern,
+            packed_refs_to_string, parse_format, parse_packed_refs, shorten,
+        },
+        test_util::TempDir,
+    };
+    use std::fs;
+
+    #[test]
+    fn test_full_name_skips_git_dir_files() {
+        let git_dir = TempDir::new("full_name");
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        let id = format!("{}\n", "1".repeat(40));
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/index\n").unwrap();
+        fs::write(git_dir.join("ORIG_HEAD"), &id).unwrap();
+        fs::write(git_dir.join("index"), "DIRC").unwrap();
+        fs::write(git_dir.join("config"), "").unwrap();
+        fs::write(git_dir.join("refs/heads/index"), &id).unwrap();
+        fs::write(
+            git_dir.join("packed-refs"),
+            format!("{} refs/heads/config\n", "2".repeat(40)),
+        )
+        .unwrap();
+
+        let full_name = |name| full_name(&git_dir, name).unwrap();
+        assert_eq!(Some("refs/heads/index".to_string()), full_name("index"));
+        assert_eq!(Some("refs/heads/config".to_string()), full_name("config"));
+        assert_eq!(Some("HEAD".to_string()), full_name("HEAD"));
+        assert_eq!(Some("ORIG_HEAD".to_string()), full_name("ORIG_HEAD"));
+        assert_eq!(
+            Some("refs/heads/index".to_string()),
+            full_name("refs/heads/index")
+        );
+        assert_eq!(None, full_name("description"));
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        for name in [
+            "refs/heads/main",
+            "refs/tags/v1.0",
+            "refs/heads/feature/a-b_c",
+            "HEAD",
+            "ORIG_HEAD",
+        ] {
+            assert!(is_valid_name(name), "{}", name);
+        }
+        for name in [
+            "",
+            "@",
+            "main",
+            "heads/main",
+            "index",
+            "Head",
+            "refs/heads/",
+            "/refs/heads/main",
+            "refs//heads",
+            "refs/heads/a..b",
+            "refs/heads/.hidden",
+            "refs/heads/main.lock",
+            "refs/heads/main.",
+            "refs/heads/a@{1}",
+            "refs/heads/a b",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a?",
+            "refs/heads/a*",
+            "refs/heads/a[",
+            "refs/heads/a\\b",
+            "refs/heads/a\tb",
+        ] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_parse_format() {
+        let parts = parse_format("%(refname:short)%09%%%(*objectname)%zz").unwrap();
+        let described = parts
+            .iter()
+            .map(|part| match part {
+                FormatPart::Literal(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                FormatPart::Field(field) => format!(
+                    "<{}{}:{}>",
+                    if field.deref { "*" } else { "" },
+                    field.name,
+                    field.modifier.as_deref().unwrap_or_default()
+                ),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["", "<refname:short>", "\t%", "<*objectname:>", "%zz"],
+            described
+        );
+
+        assert!(parse_format("%(unknown)").is_err());
+        assert!(parse_format("%(objecttype:short)").is_err());
+        assert!(parse_format("%(refname:lstrip=x)").is_err());
+        assert!(parse_format("%(refname").is_err());
+    }
+
+    #[test]
+    fn test_ref_field_shorten_name() {
+        let shorten_with = |field: &str| RefField::parse(field).unwrap();
+        let name = "refs/remotes/origin/main";
+        assert_eq!(name, shorten_with("refname").shorten_name(name));
+        assert_eq!(
+            "origin/main",
+            shorten_with("refname:short").shorten_name(name)
+        );
+        assert_eq!("main", shorten_with("refname:lstrip=3").shorten_name(name));
+        assert_eq!(
+            "origin/main",
+            shorten_with("refname:strip=-2").shorten_name(name)
+        );
+        assert_eq!("", shorten_with("refname:lstrip=9").shorten_name(name));
+    }
+
+    #[test]
+    fn test_sort_key_compare() {
+        let size = SortKey::parse("objectsize").unwrap();
+        assert!(size.compare("9", "10").is_lt());
+        let name = SortKey::parse("-refname").unwrap();
+        assert!(name.compare("refs/heads/a", "refs/heads/b").is_gt());
+    }
+
+    #[test]
+    fn test_packed_refs_round_trip() {
+        let content = "# pack-refs with: peeled fully-peeled sorted \n\
+ba0d6650ef69e546784976cb0e33eea955e584c7 refs/heads/main\n\
+bc7f11f4332c686647660615fccc1ab3571acb37 refs/tags/v1\n\
+^6769413a80cc5ad757d7dc789fc708a0c054104a\n";
+
+        let refs = parse_packed_refs(content).unwrap();
+        assert_eq!(
+            vec![
+                Ref {
+                    name: "refs/heads/main".to_string(),
+                    id: "ba0d6650ef69e546784976cb0e33eea955e584c7".parse().unwrap(),
+                    peeled: None,
+                    symref_target: None,
+                },
+                Ref {
+                    name: "refs/tags/v1".to_string(),
+                    id: "bc7f11f4332c686647660615fccc1ab3571acb37".parse().unwrap(),
+                    peeled: Some("6769413a80cc5ad757d7dc789fc708a0c054104a".parse().unwrap()),
+                    symref_target: None,
+                },
+            ],
+            refs
+        );
+        assert_eq!(content, packed_refs_to_string(&refs));
+
+        assert!(parse_packed_refs("^6769413a80cc5ad757d7dc789fc708a0c054104a\n").is_err());
+        assert!(parse_packed_refs("6769413a refs/heads/main\n").is_err());
+        assert_eq!(
+            Some(ObjectId::Sha1([0; 20])),
+            parse_packed_refs(&format!("{} refs/heads/a\n", "0".repeat(40)))
+                .unwrap()
+                .first()
+                .map(|packed| packed.id)
+        );
+    }
+
+    #[test]
+    fn test_shorten() {
+        assert_eq!("main", shorten("refs/heads/main"));
+        assert_eq!("v1.0", shorten("refs/tags/v1.0"));
+        assert_eq!("origin/main", shorten("refs/remotes/origin/main"));
+        assert_eq!("origin/HEAD", shorten("refs/remotes/origin/HEAD"));
+        assert_eq!("notes/commits", shorten("refs/notes/commits"));
+        assert_eq!("HEAD", shorten("HEAD"));
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("refs/heads/main", "refs/heads"));
+        assert!(matches_pattern("refs/heads/main", "refs/heads/"));
+        assert!(matches_pattern("refs/heads/main", "refs/heads/main"));
+        assert!(!matches_pattern("refs/heads/main", "refs/heads/ma"));
+        assert!(!matches_pattern("refs/heads/main", "refs/h"));
+
+        assert!(matches_pattern("refs/tags/v1.0", "refs/tags/v*"));
+        assert!(!matches_pattern("refs/tags/v1/rc", "refs/tags/v*"));
+        assert!(matches_pattern("refs/tags/v1/rc", "refs/tags/v*/*"));
+        assert!(matches_pattern("refs/heads/a1", "refs/heads/?[0-9]"));
+        assert!(!matches_pattern("refs/heads/ab", "refs/heads/?[0-9]"));
+        assert!(matches_pattern("refs/heads/ab", "refs/heads/?[!0-9]"));
+    }
+}
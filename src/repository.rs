@@ -0,0 +1,2065 @@
+use flate2::{Compression, write::ZlibEncoder};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsStr,
+    fs,
+    io::{self, BufRead, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    commit::{Commit, Identity, Role, cleanup_message},
+    common::{
+        FileMode, Object, create_object_blob_payload_from_file, create_object_payload_from_content,
+    },
+    config::Config,
+    date,
+    error::{Error, IoResultExt, Result},
+    index::{Index, IndexEntry},
+    object_id::{ObjectFormat, ObjectId},
+    odb::ObjectDatabase,
+    pack::{PackObjectType, PackReader},
+    reflog::{self, ReflogEntry},
+    refs::{self, FormatPart, Ref, RefField, SortKey},
+    revision,
+    tag::Tag,
+    transport::Remote,
+};
+
+/// A repository: its git directory and, unless it is bare, its work tree.
+pub struct Repository {
+    git_dir: PathBuf,
+    work_tree: Option<PathBuf>,
+    /// The current directory relative to the top of the work tree, empty or ending with `/`.
+    prefix: String,
+    odb: ObjectDatabase,
+}
+
+/// The branch HEAD points at in a new repository, unless `InitOptions::initial_branch` says
+/// otherwise.
+const DEFAULT_BRANCH: &str = "main";
+
+/// How `Repository::init` sets up a new repository.
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    /// Create the repository without a work tree, in the directory itself.
+    pub bare: bool,
+    /// Sha1 for a new repository. Re-initializing checks it against the existing format.
+    pub object_format: Option<ObjectFormat>,
+    pub initial_branch: Option<String>,
+    /// Files copied into the git directory when missing, `$GIT_TEMPLATE_DIR` if unset.
+    pub template: Option<PathBuf>,
+    /// Where to put the git directory, `.git` then being a file pointing at it.
+    pub separate_git_dir: Option<PathBuf>,
+}
+
+/// How `Repository::commit` records the staged files.
+#[derive(Debug, Default)]
+pub struct CommitOptions {
+    /// Replace the commit at HEAD, keeping its parents and author, instead of adding one.
+    pub amend: bool,
+    /// Commit even if the staged files are those of the parent commit.
+    pub allow_empty: bool,
+}
+
+/// A ref update of `git update-ref`, see `Repository::resolve_ref_update`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// The ref to update, symbolic refs being followed unless asked otherwise.
+    pub name: String,
+    /// None to delete the ref.
+    pub new: Option<ObjectId>,
+    /// The value the ref must have, the null id if it must not exist.
+    pub old: Option<ObjectId>,
+}
+
+/// What a clone does with the refs a remote advertises, see `Repository::clone`.
+#[derive(Debug, PartialEq, Eq)]
+struct ClonePlan {
+    /// The objects to fetch, sorted.
+    wants: Vec<ObjectId>,
+    /// The refs to store in `packed-refs`: branches as remote-tracking branches of `origin`,
+    /// tags as they are, with their peeled ids.
+    packed: Vec<Ref>,
+    /// The commit to check out, None if the remote has no HEAD.
+    head: Option<ObjectId>,
+    /// The branch HEAD is on, None for a detached HEAD.
+    branch: Option<String>,
+    /// What `refs/remotes/origin/HEAD` points at, when HEAD is on a branch.
+    origin_head: Option<String>,
+}
+
+impl ClonePlan {
+    fn new(remote_refs: &[Ref]) -> Self {
+        let head = remote_refs
+            .iter()
+            .find(|remote_ref| remote_ref.name == "HEAD");
+        let fetched = remote_refs
+            .iter()
+            .filter(|remote_ref| {
+                remote_ref.name.starts_with("refs/heads/")
+                    || remote_ref.name.starts_with("refs/tags/")
+            })
+            .collect::<Vec<_>>();
+
+        // Remotes that do not tell where their HEAD points get the first branch at its commit.
+        let head_branch = head.and_then(|head| {
+            head.symref_target.clone().or_else(|| {
+                fetched
+                    .iter()
+                    .find(|branch| branch.name.starts_with("refs/heads/") && branch.id == head.id)
+                    .map(|branch| branch.name.clone())
+            })
+        });
+        let branch = head_branch
+            .as_deref()
+            .and_then(|head_branch| head_branch.strip_prefix("refs/heads/"))
+            .map(str::to_string);
+
+        let mut wants = fetched
+            .iter()
+            .map(|remote_ref| remote_ref.id)
+            .chain(head.map(|head| head.id))
+            .collect::<Vec<_>>();
+        wants.sort();
+        wants.dedup();
+
+        let packed = fetched
+            .iter()
+            .map(|remote_ref| Ref {
+                name: match remote_ref.name.strip_prefix("refs/heads/") {
+                    Some(branch) => format!("refs/remotes/origin/{}", branch),
+                    None => remote_ref.name.clone(),
+                },
+                ..(*remote_ref).clone()
+            })
+            .collect();
+
+        Self {
+            wants,
+            packed,
+            head: head.map(|head| head.id),
+            origin_head: branch
+                .as_ref()
+                .map(|branch| format!("refs/remotes/origin/{}", branch)),
+            branch,
+        }
+    }
+}
+
+/// Which refs `Repository::show_refs` lists.
+#[derive(Debug, Default)]
+pub struct ShowRefOptions {
+    /// List HEAD first.
+    pub head: bool,
+    /// Only branches, or with `tags` branches and tags.
+    pub heads: bool,
+    /// Only tags, or with `heads` branches and tags.
+    pub tags: bool,
+    /// Only refs named so or ending with `/<pattern>`, every ref when empty.
+    pub patterns: Vec<String>,
+}
+
+/// The outcome of `Repository::init`.
+#[derive(Debug)]
+pub struct Initialized {
+    pub git_dir: PathBuf,
+    /// Whether the repository already existed, in which case its HEAD and config were kept.
+    pub reinitialized: bool,
+}
+
+impl Repository {
+    /// Creates an empty repository in `dir`, or completes the repository already there without
+    /// touching its content.
+    pub fn init(dir: &Path, options: &InitOptions) -> Result<Initialized> {
+        if options.bare && options.separate_git_dir.is_some() {
+            return Err(Error::Other(
+                "options '--separate-git-dir' and '--bare' cannot be used together".to_string(),
+            ));
+        }
+        if let Some(branch) = &options.initial_branch
+            && !refs::is_valid_name(&format!("refs/heads/{}", branch))
+        {
+            return Err(Error::Other(format!(
+                "invalid initial branch name: '{}'",
+                branch
+            )));
+        }
+
+        fs::create_dir_all(dir).with_path(dir)?;
+        let dot_git = dir.join(".git");
+        let git_dir = if options.bare {
+            dir.to_path_buf()
+        } else if let Some(separate_git_dir) = &options.separate_git_dir {
+            // An existing git directory moves to its new place.
+            if dot_git.is_dir() {
+                fs::rename(&dot_git, separate_git_dir).with_path(separate_git_dir)?;
+            }
+            separate_git_dir.clone()
+        } else if dot_git.is_file() {
+            read_git_file(&dot_git)?
+        } else {
+            dot_git.clone()
+        };
+
+        let reinitialized = git_dir.join("HEAD").is_file();
+        let object_format = match reinitialized {
+            true => {
+                let format = object_format(&Config::read_at(&git_dir)?)?;
+                if options
+                    .object_format
+                    .is_some_and(|requested| requested != format)
+                {
+                    return Err(Error::Other(
+                        "attempt to reinitialize repository with different hash".to_string(),
+                    ));
+                }
+                format
+            }
+            false => options.object_format.unwrap_or_default(),
+        };
+
+        fs::create_dir_all(&git_dir).with_path(&git_dir)?;
+        let template_config = match options
+            .template
+            .clone()
+            .or_else(|| std::env::var_os("GIT_TEMPLATE_DIR").map(PathBuf::from))
+        {
+            Some(template) if template.is_dir() => copy_template(&template, &git_dir)?,
+            Some(template) => {
+                warn!("templates not found in {}", template.display());
+                None
+            }
+            None => None,
+        };
+
+        for dir in ["objects/info", "objects/pack", "refs/heads", "refs/tags"] {
+            let path = git_dir.join(dir);
+            fs::create_dir_all(&path).with_path(path)?;
+        }
+
+        if !reinitialized {
+            let head_path = git_dir.join("HEAD");
+            let branch = options.initial_branch.as_deref().unwrap_or(DEFAULT_BRANCH);
+            fs::write(&head_path, format!("ref: refs/heads/{}\n", branch)).with_path(head_path)?;
+        }
+
+        let config_path = git_dir.join("config");
+        if !config_path.exists() {
+            // Values written last win, the core settings override those of the template.
+            let mut config = template_config.unwrap_or_default();
+            config.push_str(&core_config(object_format, options.bare));
+            fs::write(&config_path, config).with_path(config_path)?;
+        }
+
+        if options.separate_git_dir.is_some() {
+            let git_dir = fs::canonicalize(&git_dir).with_path(&git_dir)?;
+            fs::write(&dot_git, format!("gitdir: {}\n", git_dir.display())).with_path(&dot_git)?;
+        }
+
+        info!("Initialized git directory");
+        Ok(Initialized {
+            git_dir: fs::canonicalize(&git_dir).with_path(&git_dir)?,
+            reinitialized,
+        })
+    }
+
+    /// Opens the repository whose git directory is `git_dir`, `work_tree` being None for a bare
+    /// repository.
+    pub fn open(git_dir: &Path, work_tree: Option<&Path>) -> Result<Self> {
+        if !is_git_dir(git_dir) {
+            return Err(Error::Other(format!(
+                "not a git repository: '{}'",
+                git_dir.display()
+            )));
+        }
+
+        let config = Config::read_at(git_dir)?;
+        Ok(Self {
+            git_dir: git_dir.to_path_buf(),
+            work_tree: work_tree.map(Path::to_path_buf),
+            prefix: String::new(),
+            odb: ObjectDatabase::open(&git_dir.join("objects"), object_format(&config)?)?,
+        })
+    }
+
+    /// Finds the repository of the current directory the way git does: `$GIT_DIR`, or the
+    /// closest directory up from the current one that has a `.git` directory or file, or is a
+    /// bare repository. The search stops below the directories of `$GIT_CEILING_DIRECTORIES`.
+    /// The work tree is `$GIT_WORK_TREE`, `core.worktree` or where the repository was found.
+    pub fn discover() -> Result<Self> {
+        let cwd = env::current_dir().with_path(".")?;
+        let (git_dir, work_tree) = match env::var_os("GIT_DIR") {
+            // Without more information the current directory is the top of the work tree.
+            Some(git_dir) => (cwd.join(git_dir), Some(cwd.clone())),
+            None => find_git_dir(&cwd)?,
+        };
+
+        let mut repo = Self::open(&git_dir, work_tree.as_deref())?;
+        let config = repo.config()?;
+        if let Some(work_tree) = env::var_os("GIT_WORK_TREE") {
+            repo.work_tree = Some(cwd.join(work_tree));
+        } else if let Some(work_tree) = config.get("core", None, "worktree") {
+            repo.work_tree = Some(git_dir.join(work_tree));
+        } else if config.get_bool("core", None, "bare") == Some(true) {
+            repo.work_tree = None;
+        }
+
+        if let Some(work_tree) = &repo.work_tree {
+            let work_tree = fs::canonicalize(work_tree).with_path(work_tree)?;
+            if let Ok(relative) = cwd.strip_prefix(&work_tree) {
+                repo.prefix = relative
+                    .iter()
+                    .map(|component| format!("{}/", component.to_string_lossy()))
+                    .collect();
+            }
+            repo.work_tree = Some(work_tree);
+        }
+
+        Ok(repo)
+    }
+
+    /// Clones the repository at `url` into `dir` like git does: its branches become the
+    /// remote-tracking branches of `origin` and its tags are kept, both in `packed-refs`, then the
+    /// branch its HEAD points at is created, set to track `origin`, and checked out.
+    pub fn clone(url: &str, dir: &str) -> Result<Self> {
+        let is_empty_dir = |path: &Path| {
+            fs::read_dir(path).is_ok_and(|mut dir_entries| dir_entries.next().is_none())
+        };
+        if Path::new(dir).exists() && !is_empty_dir(Path::new(dir)) {
+            return Err(Error::Other(format!(
+                "destination path '{}' already exists and is not an empty directory.",
+                dir
+            )));
+        }
+
+        let remote = Remote::new(url);
+        let remote_refs = remote.refs()?;
+        let plan = ClonePlan::new(&remote_refs);
+
+        let initialized = Self::init(
+            Path::new(dir),
+            &InitOptions {
+                object_format: remote_refs.first().map(|remote_ref| remote_ref.id.format()),
+                initial_branch: plan.branch.clone(),
+                ..InitOptions::default()
+            },
+        )?;
+        let mut repo = Self::open(&initialized.git_dir, Some(Path::new(dir)))?;
+        // Cloning an empty repository leaves HEAD on the default branch of a new one.
+        let branch = match &plan.branch {
+            Some(branch) => Some(branch.clone()),
+            None if plan.wants.is_empty() => refs::read_symbolic(&repo.git_dir, "HEAD")?
+                .and_then(|head| head.strip_prefix("refs/heads/").map(str::to_string)),
+            None => None,
+        };
+
+        let mut config = format!(
+            "[remote \"origin\"]\n\turl = {}\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n",
+            url
+        );
+        if let Some(branch) = &branch {
+            config.push_str(&format!(
+                "[branch \"{}\"]\n\tremote = origin\n\tmerge = refs/heads/{}\n",
+                branch, branch
+            ));
+        }
+        let config_path = repo.git_dir.join("config");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&config_path)
+            .and_then(|mut file| file.write_all(config.as_bytes()))
+            .with_path(config_path)?;
+
+        if plan.wants.is_empty() {
+            warn!("You appear to have cloned an empty repository.");
+            return Ok(repo);
+        }
+        repo.store_pack(remote.fetch_pack(&plan.wants)?)?;
+        refs::add_packed(&repo.git_dir, &plan.packed)?;
+
+        let Some(head) = plan.head else {
+            warn!("remote HEAD refers to nonexistent ref, unable to checkout");
+            return Ok(repo);
+        };
+        let message = format!("clone: from {}", url);
+        let null = ObjectId::null(repo.object_format());
+        match (&plan.branch, &plan.origin_head) {
+            // HEAD points at the branch since init.
+            (Some(branch), Some(origin_head_target)) => {
+                let head_branch = format!("refs/heads/{}", branch);
+                repo.update_ref(&head_branch, &head, Some(&null), &message)?;
+
+                let origin_head = "refs/remotes/origin/HEAD";
+                refs::write_symbolic(&repo.git_dir, origin_head, origin_head_target)?;
+                let entry = ReflogEntry {
+                    old: null,
+                    new: head,
+                    committer: repo.reflog_identity()?,
+                    message,
+                };
+                reflog::append(&repo.git_dir, origin_head, &entry)?;
+            }
+            _ => repo.update_ref("HEAD", &head, Some(&null), &message)?,
+        }
+        repo.checkout(&head)?;
+
+        Ok(repo)
+    }
+
+    pub(crate) fn odb(&self) -> &ObjectDatabase {
+        &self.odb
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// The top of the work tree, None for a bare repository.
+    pub fn work_tree(&self) -> Option<&Path> {
+        self.work_tree.as_deref()
+    }
+
+    /// The current directory relative to the top of the work tree, empty or ending with `/`.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn config(&self) -> Result<Config> {
+        Config::read_at(&self.git_dir)
+    }
+
+    /// Who authors or commits: `GIT_AUTHOR_NAME`, `GIT_AUTHOR_EMAIL` and `GIT_AUTHOR_DATE`
+    /// (`GIT_COMMITTER_*` for the committer), else `author.*`/`committer.*` then `user.*` in the
+    /// config, and the current time. The login name stands in for a missing name, an email must
+    /// be set.
+    pub fn identity(&self, role: Role) -> Result<Identity> {
+        self.lookup_identity(role, true)
+    }
+
+    /// The committer recorded in reflogs, like `identity` but with `<login>@<host>` standing in
+    /// for a missing email.
+    fn reflog_identity(&self) -> Result<Identity> {
+        self.lookup_identity(Role::Committer, false)
+    }
+
+    fn lookup_identity(&self, role: Role, strict: bool) -> Result<Identity> {
+        let (env_prefix, section) = match role {
+            Role::Author => ("GIT_AUTHOR", "author"),
+            Role::Committer => ("GIT_COMMITTER", "committer"),
+        };
+        let config = self.config()?;
+        let setting = |key: &str| {
+            env::var(format!("{}_{}", env_prefix, key.to_ascii_uppercase()))
+                .ok()
+                .or_else(|| config.get(section, None, key).map(str::to_string))
+                .or_else(|| config.get("user", None, key).map(str::to_string))
+        };
+
+        let user = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        let name = setting("name").unwrap_or_else(|| user.clone());
+        let email = match setting("email").or_else(|| env::var("EMAIL").ok()) {
+            Some(email) => email,
+            None => {
+                let host = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+                let email = format!("{}@{}", user, host.trim());
+                if strict {
+                    return Err(Error::Other(format!(
+                        "unable to auto-detect email address (got '{}'), \
+                         set user.email in the config",
+                        email
+                    )));
+                }
+                email
+            }
+        };
+
+        let (timestamp, timezone) = match env::var(format!("{}_DATE", env_prefix)) {
+            Ok(date) => date::parse(&date)
+                .ok_or_else(|| Error::Other(format!("invalid date format: {}", date)))?,
+            Err(_) => date::now(),
+        };
+
+        Ok(Identity {
+            name: name.into_bytes(),
+            email: email.into_bytes(),
+            timestamp,
+            timezone,
+            space_before_email: true,
+        })
+    }
+
+    /// The work tree, or an error for commands that need one.
+    fn require_work_tree(&self) -> Result<&Path> {
+        self.work_tree()
+            .ok_or_else(|| Error::Other("this operation must be run in a work tree".to_string()))
+    }
+
+    /// Turns a path relative to the current directory into an index path: relative to the top of
+    /// the work tree, slash separated, without `.` and `..` components. The top of the work tree
+    /// is the empty path.
+    pub fn normalize_pathspec(&self, pathspec: &str) -> Result<String> {
+        let mut components: Vec<&str> = vec![];
+        for component in self.prefix.split('/').chain(pathspec.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop().ok_or_else(|| {
+                        Error::Other(format!(
+                            "'{}' is outside repository at '{}'",
+                            pathspec,
+                            self.work_tree().unwrap_or(&self.git_dir).display()
+                        ))
+                    })?;
+                }
+                component => components.push(component),
+            }
+        }
+        Ok(components.join("/"))
+    }
+
+    pub fn object_format(&self) -> ObjectFormat {
+        self.odb.format()
+    }
+
+    pub fn read_object(&self, hash: &ObjectId) -> Result<Object> {
+        self.odb.read(hash)
+    }
+
+    /// Resolves a revision expression (`HEAD~2`, `v1.0^{tree}`, `main:src/lib.rs`, ...) to
+    /// the id of the object it names.
+    pub fn rev_parse(&self, spec: &str) -> Result<ObjectId> {
+        revision::rev_parse(self, spec)
+    }
+
+    /// Follows tags, and commits down to their tree, until reaching an object of `kind`.
+    pub fn peel(&self, hash: &ObjectId, kind: PackObjectType) -> Result<ObjectId> {
+        let mut hash = *hash;
+        loop {
+            let object = self.read_object(&hash)?;
+            if object.kind() == kind {
+                return Ok(hash);
+            }
+
+            hash = match object {
+                Object::Tag { tag } => tag.object,
+                Object::Commit { commit } if kind == PackObjectType::Tree => commit.tree,
+                _ => {
+                    return Err(Error::WrongObjectType {
+                        id: hash.to_string(),
+                        expected: kind.to_string(),
+                    });
+                }
+            };
+        }
+    }
+
+    /// Follows tags until reaching an object that is not one.
+    pub fn peel_tags(&self, hash: &ObjectId) -> Result<ObjectId> {
+        let mut hash = *hash;
+        while let Object::Tag { tag } = self.read_object(&hash)? {
+            hash = tag.object;
+        }
+        Ok(hash)
+    }
+
+    /// The shortest prefix of at least `min_len` characters naming only this object.
+    pub fn abbreviate(&self, hash: &ObjectId, min_len: usize) -> Result<String> {
+        let hex_len = self.object_format().hex_len();
+        for len in min_len.clamp(4, hex_len)..hex_len {
+            let prefix = format!("{:.*}", len, hash);
+            if self.odb.ids_with_prefix(&prefix)?.len() <= 1 {
+                return Ok(prefix);
+            }
+        }
+        Ok(hash.to_string())
+    }
+
+    /// The kind and content of an object without parsing it, None if it does not exist.
+    pub fn read_raw_object(&self, hash: &ObjectId) -> Result<Option<(PackObjectType, Vec<u8>)>> {
+        self.odb.read_raw(hash)
+    }
+
+    /// Stores an object given its content (without the `<kind> <size>\0` header).
+    pub fn write_object(&self, kind: PackObjectType, content: &[u8]) -> Result<ObjectId> {
+        write_object_payload_to_file(
+            &self.odb,
+            &create_object_payload_from_content(content, kind)[..],
+        )
+    }
+
+    /// Stores the content of a file as a blob.
+    pub fn write_blob_from_file(&self, file_path: &Path) -> Result<ObjectId> {
+        write_object_payload_to_file(
+            &self.odb,
+            &create_object_blob_payload_from_file(file_path, PackObjectType::Blob)?[..],
+        )
+    }
+
+    /// Stages files, or every file under directories. Staged files that no longer exist in
+    /// the worktree are removed from the index.
+    pub fn add(&self, pathspecs: &[String]) -> Result<()> {
+        let work_tree = self.require_work_tree()?;
+        let mut index = Index::read(&self.git_dir, self.object_format())?;
+
+        for pathspec in pathspecs {
+            let path = self.normalize_pathspec(pathspec)?;
+            let fs_path = work_tree.join(&path);
+
+            match fs::symlink_metadata(&fs_path) {
+                Ok(metadata) => {
+                    if metadata.is_dir() {
+                        index.entries.retain(|entry| {
+                            !is_under(&entry.path, &path)
+                                || fs::symlink_metadata(work_tree.join(&entry.path)).is_ok()
+                        });
+                    }
+                    self.add_path(&mut index, &path, &metadata)?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    let len = index.entries.len();
+                    index.entries.retain(|entry| !is_under(&entry.path, &path));
+                    if index.entries.len() == len {
+                        return Err(Error::PathspecNoMatch(pathspec.clone()));
+                    }
+                }
+                Err(err) => return Err(err).with_path(fs_path),
+            }
+        }
+
+        index.write(&self.git_dir)
+    }
+
+    fn add_dir(&self, index: &mut Index, dir: &str) -> Result<()> {
+        let fs_dir = self.require_work_tree()?.join(dir);
+
+        for entry in fs::read_dir(&fs_dir).with_path(&fs_dir)? {
+            let entry = entry.with_path(&fs_dir)?;
+            // Index paths are UTF-8, file names need not be.
+            let filename = entry.file_name().into_string().map_err(|filename| {
+                Error::Other(format!(
+                    "cannot add '{}': path is not valid UTF-8",
+                    Path::new(dir).join(filename).display()
+                ))
+            })?;
+            if filename == ".git" {
+                continue;
+            }
+
+            let path = if dir.is_empty() {
+                filename
+            } else {
+                format!("{}/{}", dir, filename)
+            };
+            let metadata = entry.metadata().with_path(&path)?;
+            self.add_path(index, &path, &metadata)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stages a worktree path, `metadata` comes from `symlink_metadata`.
+    fn add_path(&self, index: &mut Index, path: &str, metadata: &fs::Metadata) -> Result<()> {
+        let fs_path = self.require_work_tree()?.join(path);
+        let dot_git = fs_path.join(".git");
+        let mode = FileMode::from_metadata(metadata);
+        let hash = match mode {
+            FileMode::Tree if !path.is_empty() && dot_git.exists() => {
+                // A nested repository is staged as a submodule at its checked out commit.
+                let git_dir = match dot_git.is_file() {
+                    true => read_git_file(&dot_git)?,
+                    false => dot_git,
+                };
+                let hash = refs::read_head(&git_dir)?;
+                index.add(IndexEntry::from_metadata(
+                    path,
+                    FileMode::Gitlink,
+                    hash,
+                    metadata,
+                ));
+                return Ok(());
+            }
+            FileMode::Tree => return self.add_dir(index, path),
+            FileMode::Symlink => {
+                let target = fs::read_link(&fs_path).with_path(&fs_path)?;
+                self.write_object(PackObjectType::Blob, target.as_os_str().as_bytes())?
+            }
+            FileMode::Regular | FileMode::Executable | FileMode::Gitlink => {
+                self.write_blob_from_file(&fs_path)?
+            }
+        };
+
+        index.add(IndexEntry::from_metadata(path, mode, hash, metadata));
+        Ok(())
+    }
+
+    /// Unstages files, and unless `cached` also deletes them from the worktree. Directories need
+    /// `recursive`. Unless `force`, files whose changes would be lost are refused. Returns the
+    /// removed paths.
+    pub fn remove(
+        &self,
+        pathspecs: &[String],
+        cached: bool,
+        recursive: bool,
+        force: bool,
+    ) -> Result<Vec<String>> {
+        let mut index = Index::read(&self.git_dir, self.object_format())?;
+
+        let mut removed_paths: Vec<String> = vec![];
+        for pathspec in pathspecs {
+            let path = self.normalize_pathspec(pathspec)?;
+            let matching_paths = index
+                .entries
+                .iter()
+                .filter(|entry| is_under(&entry.path, &path))
+                .map(|entry| entry.path.clone())
+                .collect::<Vec<_>>();
+
+            if matching_paths.is_empty() {
+                return Err(Error::PathspecNoMatch(pathspec.clone()));
+            }
+            if !recursive && matching_paths.iter().any(|matching| matching != &path) {
+                return Err(Error::Other(format!(
+                    "not removing '{}' recursively without -r",
+                    pathspec
+                )));
+            }
+
+            removed_paths.extend(matching_paths);
+        }
+        removed_paths.sort();
+        removed_paths.dedup();
+
+        if !force {
+            self.check_removable(&index, &removed_paths, cached)?;
+        }
+
+        for path in &removed_paths {
+            index.remove(path);
+
+            if !cached {
+                let work_tree = self.require_work_tree()?;
+                let fs_path = work_tree.join(path);
+                match fs::remove_file(&fs_path) {
+                    Ok(()) => remove_empty_parents(work_tree, path),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err).with_path(fs_path),
+                }
+            }
+        }
+
+        index.write(&self.git_dir)?;
+        Ok(removed_paths)
+    }
+
+    /// Refuses to remove files whose staged content differs from both the work tree and HEAD, and
+    /// unless `cached`, files with staged or unstaged changes.
+    fn check_removable(&self, index: &Index, paths: &[String], cached: bool) -> Result<()> {
+        let head_tree = match refs::resolve(&self.git_dir, "HEAD")? {
+            Some(head) => Some(self.peel(&head, PackObjectType::Tree)?),
+            None => None,
+        };
+
+        let mut staged_and_modified = vec![];
+        let mut staged = vec![];
+        let mut modified = vec![];
+        for entry in &index.entries {
+            if entry.stage != 0 || paths.binary_search(&entry.path).is_err() {
+                continue;
+            }
+
+            let local_changes = self.has_local_changes(entry)?;
+            let staged_changes = match head_tree {
+                Some(tree) => self.tree_file(tree, &entry.path)? != Some((entry.mode, entry.hash)),
+                None => true,
+            };
+
+            if local_changes && staged_changes {
+                if !cached || !entry.intent_to_add {
+                    staged_and_modified.push(entry.path.as_str());
+                }
+            } else if !cached && staged_changes {
+                staged.push(entry.path.as_str());
+            } else if !cached && local_changes {
+                modified.push(entry.path.as_str());
+            }
+        }
+
+        let (paths, description, hint) = if !staged_and_modified.is_empty() {
+            (
+                staged_and_modified,
+                "staged content different from both the file and the HEAD",
+                "(use -f to force removal)",
+            )
+        } else if !staged.is_empty() {
+            (
+                staged,
+                "changes staged in the index",
+                "(use --cached to keep the file, or -f to force removal)",
+            )
+        } else if !modified.is_empty() {
+            (
+                modified,
+                "local modifications",
+                "(use --cached to keep the file, or -f to force removal)",
+            )
+        } else {
+            return Ok(());
+        };
+
+        let subject = match paths.len() {
+            1 => "the following file has",
+            _ => "the following files have",
+        };
+        let listing = paths
+            .iter()
+            .map(|path| format!("    {}\n", path))
+            .collect::<String>();
+        Err(Error::Other(format!(
+            "{} {}:\n{}{}",
+            subject, description, listing, hint
+        )))
+    }
+
+    /// Whether the work tree file of an index entry differs from what is staged. A missing file
+    /// or a directory does not count as a change.
+    fn has_local_changes(&self, entry: &IndexEntry) -> Result<bool> {
+        let fs_path = self.require_work_tree()?.join(&entry.path);
+        let metadata = match fs::symlink_metadata(&fs_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).with_path(fs_path),
+        };
+
+        let mode = FileMode::from_metadata(&metadata);
+        let content = match mode {
+            FileMode::Tree | FileMode::Gitlink => return Ok(false),
+            FileMode::Symlink => fs::read_link(&fs_path)
+                .with_path(&fs_path)?
+                .into_os_string()
+                .into_vec(),
+            FileMode::Regular | FileMode::Executable => fs::read(&fs_path).with_path(&fs_path)?,
+        };
+        let hash = self
+            .object_format()
+            .hash(&create_object_payload_from_content(
+                &content,
+                PackObjectType::Blob,
+            ));
+
+        Ok(mode != entry.mode || hash != entry.hash)
+    }
+
+    /// The mode and id of the entry at the slash separated `path` in a tree, None if there is
+    /// no such entry.
+    fn tree_file(&self, tree: ObjectId, path: &str) -> Result<Option<(FileMode, ObjectId)>> {
+        let mut found = (FileMode::Tree, tree);
+        for component in path.split('/') {
+            let Object::Tree { entries } = self.read_object(&found.1)? else {
+                return Ok(None);
+            };
+            match entries
+                .into_iter()
+                .find(|entry| entry.filename == component.as_bytes())
+            {
+                Some(entry) => found = (entry.mode, entry.hash),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(found))
+    }
+
+    /// Stores the staged files as trees, and returns the id of the root tree.
+    pub fn write_tree(&self) -> Result<ObjectId> {
+        let index = Index::read(&self.git_dir, self.object_format())?;
+        if let Some(entry) = index.entries.iter().find(|entry| entry.stage != 0) {
+            return Err(Error::Unmerged(entry.path.clone()));
+        }
+
+        let entries = index
+            .entries
+            .iter()
+            .filter(|entry| !entry.intent_to_add)
+            .collect::<Vec<_>>();
+        self.write_tree_from_entries(&entries, "")
+    }
+
+    /// Writes the tree of the directory `prefix` (empty or ending with `/`), `entries` are the
+    /// sorted index entries under it.
+    fn write_tree_from_entries(&self, entries: &[&IndexEntry], prefix: &str) -> Result<ObjectId> {
+        // Keyed by name, with a trailing `/` for subtrees, which is the order git sorts trees in.
+        let mut folder_entries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+
+        let mut i = 0;
+        while i < entries.len() {
+            let relative_path = &entries[i].path[prefix.len()..];
+
+            match relative_path.split_once('/') {
+                Some((dir_name, _)) => {
+                    let dir_prefix = format!("{}{}/", prefix, dir_name);
+                    let count = entries[i..]
+                        .iter()
+                        .take_while(|entry| entry.path.starts_with(&dir_prefix))
+                        .count();
+                    let hash = self.write_tree_from_entries(&entries[i..i + count], &dir_prefix)?;
+
+                    folder_entries.insert(
+                        format!("{}/", dir_name),
+                        tree_entry_bytes("40000", dir_name, &hash),
+                    );
+                    i += count;
+                }
+                None => {
+                    folder_entries.insert(
+                        relative_path.to_string(),
+                        tree_entry_bytes(
+                            entries[i].mode.as_tree_str(),
+                            relative_path,
+                            &entries[i].hash,
+                        ),
+                    );
+                    i += 1;
+                }
+            }
+        }
+
+        let entries = folder_entries.into_values().flatten().collect::<Vec<_>>();
+
+        self.write_object(PackObjectType::Tree, &entries[..])
+    }
+
+    /// Commits the staged files on top of HEAD and advances HEAD, or its branch, to the new
+    /// commit. The message is cleaned up like git does without an editor; None reuses the message
+    /// of the amended commit. A pending merge (`MERGE_HEAD`) adds its commits as parents.
+    ///
+    /// Returns None, committing nothing, when the staged files are those of the parent commit
+    /// (of the amended commit's parent) unless `allow_empty`.
+    pub fn commit(
+        &self,
+        message: Option<&str>,
+        options: &CommitOptions,
+    ) -> Result<Option<ObjectId>> {
+        self.require_work_tree()?;
+        let tree = self.write_tree()?;
+        let head = refs::resolve(&self.git_dir, "HEAD")?;
+        let head_commit = head
+            .map(|head| match self.read_object(&head)? {
+                Object::Commit { commit } => Ok(commit),
+                _ => Err(Error::WrongObjectType {
+                    id: head.to_string(),
+                    expected: "commit",
+                }),
+            })
+            .transpose()?;
+
+        let merge_head_path = self.git_dir.join("MERGE_HEAD");
+        let merge_heads = match fs::read_to_string(&merge_head_path) {
+            Ok(content) => content
+                .lines()
+                .map(str::parse)
+                .collect::<Result<Vec<ObjectId>>>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err).with_path(merge_head_path),
+        };
+
+        let (parents, author, message) = match (options.amend, &head_commit) {
+            (true, None) => return Err(Error::Other("You have nothing to amend.".to_string())),
+            (true, Some(amended)) => (
+                amended.parents.clone(),
+                amended.author.clone(),
+                message.map_or_else(
+                    || String::from_utf8_lossy(&amended.message).to_string(),
+                    str::to_string,
+                ),
+            ),
+            (false, _) => (
+                head.into_iter()
+                    .chain(merge_heads.iter().copied())
+                    .collect(),
+                self.identity(Role::Author)?,
+                message.unwrap_or_default().to_string(),
+            ),
+        };
+
+        let message = cleanup_message(&message, false);
+        if message.is_empty() {
+            return Err(Error::Other(
+                "Aborting commit due to empty commit message.".to_string(),
+            ));
+        }
+
+        // A merge records something even without changes.
+        if !options.allow_empty && parents.len() <= 1 {
+            let parent_tree = match parents.first() {
+                Some(parent) => self.peel(parent, PackObjectType::Tree)?,
+                None => self.object_format().hash_object(b"tree 0\0")?,
+            };
+            if tree == parent_tree {
+                return Ok(None);
+            }
+        }
+
+        let commit = Commit {
+            tree,
+            parents,
+            author,
+            committer: self.identity(Role::Committer)?,
+            extra_headers: vec![],
+            message: message.clone().into_bytes(),
+        };
+        let id = self.write_object(PackObjectType::Commit, &commit.to_bytes())?;
+
+        let kind = if options.amend {
+            " (amend)"
+        } else if commit.parents.is_empty() {
+            " (initial)"
+        } else if commit.parents.len() > 1 {
+            " (merge)"
+        } else {
+            ""
+        };
+        let subject = message.lines().next().unwrap_or_default();
+        let head_ref = refs::resolve_symbolic(&self.git_dir, "HEAD")?;
+        let old = head.unwrap_or(ObjectId::null(self.object_format()));
+        self.update_ref(
+            &head_ref,
+            &id,
+            Some(&old),
+            &format!("commit{}: {}", kind, subject),
+        )?;
+
+        for merge_file in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"] {
+            let merge_file_path = self.git_dir.join(merge_file);
+            match fs::remove_file(&merge_file_path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err).with_path(merge_file_path),
+            }
+        }
+
+        Ok(Some(id))
+    }
+
+    /// Stores a commit of `tree` on top of `parents`, by the configured author and committer,
+    /// without touching any ref. The message is kept as is.
+    pub fn commit_tree(
+        &self,
+        tree: &ObjectId,
+        parents: &[ObjectId],
+        message: &[u8],
+    ) -> Result<ObjectId> {
+        for (id, kind) in std::iter::once((tree, PackObjectType::Tree)).chain(
+            parents
+                .iter()
+                .map(|parent| (parent, PackObjectType::Commit)),
+        ) {
+            if self.read_object(id)?.kind() != kind {
+                return Err(Error::Other(format!(
+                    "{} is not a valid '{}' object",
+                    id,
+                    kind.to_string()
+                )));
+            }
+        }
+
+        let commit = Commit {
+            tree: *tree,
+            parents: parents.to_vec(),
+            author: self.identity(Role::Author)?,
+            committer: self.identity(Role::Committer)?,
+            extra_headers: vec![],
+            message: message.to_vec(),
+        };
+        self.write_object(PackObjectType::Commit, &commit.to_bytes())
+    }
+
+    /// Creates `refs/tags/<name>` pointing at `target`, or at HEAD. With a message the tag is
+    /// annotated: a tag object by the committer is stored, its message cleaned up, and the ref
+    /// points at it. An existing tag is only replaced with `force`.
+    pub fn create_tag(
+        &self,
+        name: &str,
+        target: Option<ObjectId>,
+        message: Option<&str>,
+        force: bool,
+    ) -> Result<ObjectId> {
+        let ref_name = format!("refs/tags/{}", name);
+        if !refs::is_valid_name(&ref_name) {
+            return Err(Error::Other(format!("'{}' is not a valid tag name.", name)));
+        }
+        let old = refs::resolve(&self.git_dir, &ref_name)?;
+        if old.is_some() && !force {
+            return Err(Error::Other(format!("tag '{}' already exists", name)));
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None => refs::read_head(&self.git_dir)?,
+        };
+
+        let hash = match message {
+            Some(message) => {
+                let tag = Tag {
+                    kind: self.read_object(&target)?.kind().to_string().to_string(),
+                    object: target,
+                    tag: name.to_string(),
+                    tagger: Some(self.identity(Role::Committer)?),
+                    extra_headers: vec![],
+                    message: cleanup_message(message, true).into_bytes(),
+                };
+
+                self.write_object(PackObjectType::Tag, &tag.to_bytes())?
+            }
+            None => target,
+        };
+
+        let old = old.unwrap_or(ObjectId::null(self.object_format()));
+        self.update_ref(&ref_name, &hash, Some(&old), "")?;
+        Ok(hash)
+    }
+
+    /// Points the ref `name` at `new`, see `refs::update`, `message` going to its reflog. The
+    /// object must exist, and be a commit for a branch.
+    pub fn update_ref(
+        &self,
+        name: &str,
+        new: &ObjectId,
+        old: Option<&ObjectId>,
+        message: &str,
+    ) -> Result<()> {
+        let cannot_update =
+            |reason: String| Error::Other(format!("cannot update ref '{}': {}", name, reason));
+
+        match self.read_raw_object(new)? {
+            None => Err(cannot_update(format!(
+                "trying to write ref '{}' with nonexistent object {}",
+                name, new
+            ))),
+            Some((kind, _))
+                if name.starts_with("refs/heads/") && kind != PackObjectType::Commit =>
+            {
+                Err(cannot_update(format!(
+                    "trying to write non-commit object {} to branch '{}'",
+                    new, name
+                )))
+            }
+            Some(_) => refs::update(
+                &self.git_dir,
+                name,
+                new,
+                old,
+                &self.reflog_identity()?,
+                message,
+            ),
+        }
+    }
+
+    /// Deletes the ref `name` and its reflog, see `refs::delete`.
+    pub fn delete_ref(&self, name: &str, old: Option<&ObjectId>, message: &str) -> Result<()> {
+        refs::delete(&self.git_dir, name, old, &self.reflog_identity()?, message)
+    }
+
+    /// Resolves the arguments of `git update-ref`: `new` names the object to point the ref at,
+    /// none meaning deletion, and `old` the value the ref must have, an empty one meaning that
+    /// the ref must not exist. Unless `no_deref`, a symbolic ref updates the ref it points at.
+    pub fn resolve_ref_update(
+        &self,
+        name: &str,
+        new: Option<&str>,
+        old: Option<&str>,
+        no_deref: bool,
+    ) -> Result<RefUpdate> {
+        if !refs::is_valid_name(name) {
+            return Err(Error::Other(format!(
+                "update_ref failed for ref '{}': refusing to update ref with bad name '{}'",
+                name, name
+            )));
+        }
+
+        let new = new
+            .map(|new| {
+                self.rev_parse(new)
+                    .map_err(|_| Error::Other(format!("{}: not a valid SHA1", new)))
+            })
+            .transpose()?;
+        let old = old
+            .map(|old| match old {
+                "" => Ok(ObjectId::null(self.object_format())),
+                old => self
+                    .rev_parse(old)
+                    .map_err(|_| Error::Other(format!("{}: not a valid old SHA1", old))),
+            })
+            .transpose()?;
+
+        let name = match no_deref {
+            true => name.to_string(),
+            false => refs::resolve_symbolic(&self.git_dir, name)?,
+        };
+
+        Ok(RefUpdate { name, new, old })
+    }
+
+    /// Updates or deletes a ref as resolved by `resolve_ref_update`.
+    pub fn apply_ref_update(&self, update: &RefUpdate, message: &str) -> Result<()> {
+        match update.new {
+            Some(new) => self
+                .update_ref(&update.name, &new, update.old.as_ref(), message)
+                .map_err(|err| {
+                    Error::Other(format!(
+                        "update_ref failed for ref '{}': {}",
+                        update.name, err
+                    ))
+                }),
+            // Deleting checks nothing against the null id.
+            None => self.delete_ref(
+                &update.name,
+                update.old.filter(|old| !old.is_null()).as_ref(),
+                message,
+            ),
+        }
+    }
+
+    /// The refs `show-ref` lists, sorted by name after HEAD.
+    pub fn show_refs(&self, options: &ShowRefOptions) -> Result<Vec<Ref>> {
+        let mut shown = vec![];
+        if options.head {
+            shown.extend(self.exact_ref("HEAD")?);
+        }
+
+        shown.extend(refs::list(&self.git_dir)?.into_iter().filter(|found| {
+            let kind_matches = (!options.heads && !options.tags)
+                || (options.heads && found.name.starts_with("refs/heads/"))
+                || (options.tags && found.name.starts_with("refs/tags/"));
+            kind_matches
+                && (options.patterns.is_empty()
+                    || options.patterns.iter().any(|pattern| {
+                        found.name == *pattern || found.name.ends_with(&format!("/{}", pattern))
+                    }))
+        }));
+        Ok(shown)
+    }
+
+    /// The ref with exactly this full name, HEAD included, as `show-ref --verify` wants it.
+    pub fn exact_ref(&self, name: &str) -> Result<Option<Ref>> {
+        match name {
+            "HEAD" => Ok(refs::resolve(&self.git_dir, "HEAD")?.map(|id| Ref {
+                name: "HEAD".to_string(),
+                id,
+                peeled: None,
+                symref_target: None,
+            })),
+            name if name.starts_with("refs/") => Ok(refs::list(&self.git_dir)?
+                .into_iter()
+                .find(|found| found.name == name)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The object an annotated tag under `refs/tags/` points at, None for other refs.
+    pub fn peeled_tag(&self, found: &Ref) -> Result<Option<ObjectId>> {
+        if !found.name.starts_with("refs/tags/") {
+            return Ok(None);
+        }
+        let peeled = match found.peeled {
+            Some(peeled) => peeled,
+            None => self.peel_tags(&found.id)?,
+        };
+        Ok(Some(peeled).filter(|peeled| *peeled != found.id))
+    }
+
+    /// The refs `for-each-ref` lists: those matching one of `patterns`, or all of them without
+    /// patterns, sorted on `sort_keys`, the last key being the primary one.
+    pub fn for_each_ref(&self, patterns: &[String], sort_keys: &[SortKey]) -> Result<Vec<Ref>> {
+        let head_target = refs::read_symbolic(&self.git_dir, "HEAD")?;
+        let mut listed = vec![];
+        for found in refs::list(&self.git_dir)? {
+            if !patterns.is_empty()
+                && !patterns
+                    .iter()
+                    .any(|pattern| refs::matches_pattern(&found.name, pattern))
+            {
+                continue;
+            }
+            let values = sort_keys
+                .iter()
+                .map(|key| self.ref_field_value(&key.field, &found, head_target.as_deref()))
+                .collect::<Result<Vec<_>>>()?;
+            listed.push((found, values));
+        }
+
+        listed.sort_by(|(_, a), (_, b)| {
+            sort_keys
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, key)| key.compare(&a[i], &b[i]))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(listed.into_iter().map(|(found, _)| found).collect())
+    }
+
+    /// The `for-each-ref` line of a ref, without its newline.
+    pub fn format_ref(&self, format: &[FormatPart], found: &Ref) -> Result<Vec<u8>> {
+        let head_target = refs::read_symbolic(&self.git_dir, "HEAD")?;
+        let mut line = vec![];
+        for part in format {
+            match part {
+                FormatPart::Literal(bytes) => line.extend_from_slice(bytes),
+                FormatPart::Field(field) => line.extend_from_slice(
+                    self.ref_field_value(field, found, head_target.as_deref())?
+                        .as_bytes(),
+                ),
+            }
+        }
+        Ok(line)
+    }
+
+    fn ref_field_value(
+        &self,
+        field: &RefField,
+        found: &Ref,
+        head_target: Option<&str>,
+    ) -> Result<String> {
+        let id = match field.deref {
+            false => found.id,
+            true => match self.read_object(&found.id)? {
+                Object::Tag { tag } => tag.object,
+                _ => return Ok(String::new()),
+            },
+        };
+
+        Ok(match field.name.as_str() {
+            "refname" => field.shorten_name(&found.name),
+            "symref" => found
+                .symref_target
+                .as_deref()
+                .map(|target| field.shorten_name(target))
+                .unwrap_or_default(),
+            "objectname" if field.modifier.is_some() => self.abbreviate(&id, 7)?,
+            "objectname" => id.to_string(),
+            "objecttype" | "objectsize" => {
+                let (kind, content) = self
+                    .read_raw_object(&id)?
+                    .ok_or_else(|| Error::ObjectNotFound(id.to_string()))?;
+                match field.name.as_str() {
+                    "objecttype" => kind.to_string().to_string(),
+                    _ => content.len().to_string(),
+                }
+            }
+            "HEAD" => match head_target == Some(found.name.as_str()) {
+                true => "*".to_string(),
+                false => " ".to_string(),
+            },
+            _ => unreachable!("fields are checked when parsed"),
+        })
+    }
+
+    /// Stores a received pack with its index and makes its objects readable.
+    pub fn store_pack(&mut self, pack: impl BufRead) -> Result<()> {
+        let format = self.object_format();
+        let objects_dir = self.git_dir.join("objects");
+        let pack_name = PackReader::new(pack, &objects_dir.join("pack"), format)?.store()?;
+        info!("Stored pack {}", pack_name);
+
+        self.odb = ObjectDatabase::open(&objects_dir, format)?;
+        Ok(())
+    }
+
+    /// Writes the tree of `commit_hash` into the worktree and the index.
+    pub fn checkout(&self, commit_hash: &ObjectId) -> Result<()> {
+        let Object::Commit { commit } = self.read_object(commit_hash)? else {
+            return Err(Error::WrongObjectType {
+                id: commit_hash.to_string(),
+                expected: "commit",
+            });
+        };
+
+        let mut index = Index::new(self.object_format());
+        self.materialize_tree(&mut index, &commit.tree, "")?;
+        index.write(&self.git_dir)
+    }
+
+    fn materialize_tree(&self, index: &mut Index, hash: &ObjectId, path: &str) -> Result<()> {
+        let Object::Tree { entries } = self.read_object(hash)? else {
+            return Err(Error::WrongObjectType {
+                id: hash.to_string(),
+                expected: "tree",
+            });
+        };
+
+        for tree_entry in entries {
+            let filename = String::from_utf8_lossy(&tree_entry.filename);
+            let tree_entry_path = if path.is_empty() {
+                filename.to_string()
+            } else {
+                format!("{}/{}", path, filename)
+            };
+            // Index paths are UTF-8, tree entry names need not be.
+            if str::from_utf8(&tree_entry.filename).is_err() {
+                return Err(Error::Other(format!(
+                    "cannot check out '{}': path is not valid UTF-8",
+                    tree_entry_path
+                )));
+            }
+            if !is_valid_path_component(&tree_entry.filename) {
+                return Err(Error::Other(format!("invalid path '{}'", tree_entry_path)));
+            }
+
+            let fs_path = self.require_work_tree()?.join(&tree_entry_path);
+            // A symlink checked out earlier under the same name must not be followed.
+            if let Ok(metadata) = fs::symlink_metadata(&fs_path)
+                && metadata.file_type().is_symlink()
+                && matches!(tree_entry.mode, FileMode::Tree | FileMode::Gitlink)
+            {
+                return Err(Error::Other(format!(
+                    "cannot check out '{}': beyond a symbolic link",
+                    tree_entry_path
+                )));
+            }
+            match tree_entry.mode {
+                FileMode::Tree => {
+                    fs::create_dir_all(&fs_path).with_path(&fs_path)?;
+                    self.materialize_tree(index, &tree_entry.hash, &tree_entry_path)?;
+                }
+                FileMode::Gitlink => {
+                    // Submodules are not cloned, their directory stays empty.
+                    fs::create_dir_all(&fs_path).with_path(&fs_path)?;
+                    let metadata = fs::symlink_metadata(&fs_path).with_path(&fs_path)?;
+                    index.add(IndexEntry::from_metadata(
+                        &tree_entry_path,
+                        FileMode::Gitlink,
+                        tree_entry.hash,
+                        &metadata,
+                    ));
+                }
+                mode => self.materialize_blob(index, tree_entry.hash, mode, &tree_entry_path)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn materialize_blob(
+        &self,
+        index: &mut Index,
+        hash: ObjectId,
+        mode: FileMode,
+        path: &str,
+    ) -> Result<()> {
+        let Object::Blob { content } = self.read_object(&hash)? else {
+            return Err(Error::WrongObjectType {
+                id: hash.to_string(),
+                expected: "blob",
+            });
+        };
+
+        let fs_path = self.require_work_tree()?.join(path);
+        match fs::remove_file(&fs_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_path(fs_path),
+        }
+
+        if mode == FileMode::Symlink {
+            std::os::unix::fs::symlink(OsStr::from_bytes(&content), &fs_path)
+                .with_path(&fs_path)?;
+        } else {
+            fs::write(&fs_path, &content).with_path(&fs_path)?;
+
+            if mode == FileMode::Executable {
+                // Executable for whoever can read it, which keeps the umask applied on creation.
+                let mut permissions = fs::metadata(&fs_path).with_path(&fs_path)?.permissions();
+                permissions.set_mode(permissions.mode() | ((permissions.mode() & 0o444) >> 2));
+                fs::set_permissions(&fs_path, permissions).with_path(&fs_path)?;
+            }
+        }
+
+        let metadata = fs::symlink_metadata(&fs_path).with_path(&fs_path)?;
+        index.add(IndexEntry::from_metadata(path, mode, hash, &metadata));
+        Ok(())
+    }
+}
+
+/// Whether a tree entry name can be checked out, following git's `verify_path`: it must be a
+/// single component that is neither `.`, `..` nor the git dir.
+fn is_valid_path_component(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name != b"."
+        && name != b".."
+        && !name.eq_ignore_ascii_case(b".git")
+        && !name.contains(&b'/')
+        && !name.contains(&0)
+}
+
+/// Whether `path` is `dir` or inside it.
+fn is_under(path: &str, dir: &str) -> bool {
+    dir.is_empty()
+        || path == dir
+        || (path.starts_with(dir) && path.as_bytes().get(dir.len()) == Some(&b'/'))
+}
+
+/// Removes the directories of the work tree path `path` that are left empty, deepest first.
+fn remove_empty_parents(work_tree: &Path, path: &str) {
+    let mut path = path;
+    while let Some((folder_path, _)) = path.rsplit_once('/') {
+        if fs::remove_dir(work_tree.join(folder_path)).is_err() {
+            break;
+        }
+        path = folder_path;
+    }
+}
+
+fn tree_entry_bytes(mode: &str, filename: &str, hash: &ObjectId) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(mode.as_bytes());
+    bytes.push(b' ');
+    bytes.extend_from_slice(filename.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(hash.as_bytes());
+    bytes
+}
+
+/// The object format a repository declares in its config.
+fn object_format(config: &Config) -> Result<ObjectFormat> {
+    let format_version = config
+        .get("core", None, "repositoryformatversion")
+        .unwrap_or("0");
+    match format_version {
+        "0" => Ok(ObjectFormat::Sha1),
+        "1" => {
+            let name = config
+                .get("extensions", None, "objectformat")
+                .unwrap_or("sha1");
+            ObjectFormat::from_name(name).ok_or_else(|| {
+                Error::Other(format!(
+                    "invalid value for 'extensions.objectformat': '{}'",
+                    name
+                ))
+            })
+        }
+        version => Err(Error::Other(format!(
+            "Expected git repo version <= 1, found {}",
+            version
+        ))),
+    }
+}
+
+/// The `[core]` section of a new repository, with the `[extensions]` its object format needs.
+fn core_config(object_format: ObjectFormat, bare: bool) -> String {
+    // Repositories using extensions have to declare version 1, so that older versions of git
+    // refuse them instead of misreading them.
+    let mut config = format!(
+        "[core]\n\
+         \trepositoryformatversion = {}\n\
+         \tfilemode = true\n\
+         \tbare = {}\n",
+        match object_format {
+            ObjectFormat::Sha1 => 0,
+            ObjectFormat::Sha256 => 1,
+        },
+        bare
+    );
+    if !bare {
+        config.push_str("\tlogallrefupdates = true\n");
+    }
+    if object_format != ObjectFormat::Sha1 {
+        config.push_str(&format!(
+            "[extensions]\n\tobjectformat = {}\n",
+            object_format.name()
+        ));
+    }
+    config
+}
+
+/// Walks up from `cwd` to the closest directory holding a repository, and returns its git
+/// directory and work tree.
+fn find_git_dir(cwd: &Path) -> Result<(PathBuf, Option<PathBuf>)> {
+    // Absolute paths only, the search never enters them but can start in one.
+    let ceilings = env::var_os("GIT_CEILING_DIRECTORIES")
+        .map(|ceilings| {
+            env::split_paths(&ceilings)
+                .filter(|ceiling| ceiling.is_absolute())
+                .map(|ceiling| fs::canonicalize(&ceiling).unwrap_or(ceiling))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    for dir in cwd.ancestors() {
+        if dir != cwd && ceilings.iter().any(|ceiling| ceiling == dir) {
+            break;
+        }
+
+        let dot_git = dir.join(".git");
+        if dot_git.is_file() {
+            return Ok((read_git_file(&dot_git)?, Some(dir.to_path_buf())));
+        }
+        if is_git_dir(&dot_git) {
+            return Ok((dot_git, Some(dir.to_path_buf())));
+        }
+        // A bare repository, or the inside of a git directory.
+        if is_git_dir(dir) {
+            return Ok((dir.to_path_buf(), None));
+        }
+    }
+
+    Err(Error::NotARepository)
+}
+
+/// Whether `path` looks like a git directory: it has a HEAD, objects and refs.
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+/// The git directory a `.git` file (`gitdir: <path>`) points at, relative to the file.
+fn read_git_file(path: &Path) -> Result<PathBuf> {
+    let content = fs::read_to_string(path).with_path(path)?;
+    let target = content
+        .trim_end()
+        .strip_prefix("gitdir: ")
+        .ok_or_else(|| Error::Other(format!("invalid gitfile format: {}", path.display())))?;
+    Ok(path.parent().unwrap_or(Path::new("")).join(target))
+}
+
+/// Copies the files of a template directory missing from `git_dir`. The template config is
+/// not copied but returned, to be completed with the core settings.
+fn copy_template(template: &Path, git_dir: &Path) -> Result<Option<String>> {
+    fn copy_missing(from: &Path, to: &Path, skip: &Path) -> Result<()> {
+        fs::create_dir_all(to).with_path(to)?;
+        for dir_entry in fs::read_dir(from).with_path(from)? {
+            let from = dir_entry.with_path(from)?.path();
+            let to = to.join(from.file_name().unwrap());
+            if from == skip {
+                continue;
+            } else if from.is_dir() {
+                copy_missing(&from, &to, skip)?;
+            } else if !to.exists() {
+                fs::copy(&from, &to).with_path(&to)?;
+            }
+        }
+        Ok(())
+    }
+
+    let config_path = template.join("config");
+    copy_missing(template, git_dir, &config_path)?;
+    match config_path.is_file() {
+        true => Ok(Some(
+            fs::read_to_string(&config_path).with_path(&config_path)?,
+        )),
+        false => Ok(None),
+    }
+}
+
+fn write_object_payload_to_file(odb: &ObjectDatabase, payload: &[u8]) -> Result<ObjectId> {
+    let hash = odb.format().hash_object(payload)?;
+    if odb.contains(&hash) {
+        return Ok(hash);
+    }
+
+    let file_path = odb.loose_object_path(&hash);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload).with_path(&file_path)?;
+    let content_encoded = encoder.finish().with_path(&file_path)?;
+
+    // Written aside and renamed into place, so that an object is either complete or absent.
+    let folder_path = file_path.parent().unwrap();
+    fs::create_dir_all(folder_path).with_path(folder_path)?;
+    let tmp_path = folder_path.join(format!("tmp_obj_{}", std::process::id()));
+    if let Err(err) = fs::write(&tmp_path, content_encoded) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err).with_path(&tmp_path);
+    }
+    fs::rename(&tmp_path, &file_path).with_path(&file_path)?;
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        index::Index,
+        object_id::ObjectId,
+        pack::PackObjectType,
+        refs::{self, Ref, SortKey},
+        repository::{ClonePlan, InitOptions, RefUpdate, Repository, ShowRefOptions},
+        test_util::TempDir,
+    };
+    use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+
+    /// A new repository on `main` with a committer configured, in its work tree.
+    fn test_repo(name: &str) -> (TempDir, Repository) {
+        let dir = TempDir::new(name);
+        let options = InitOptions {
+            initial_branch: Some("main".to_string()),
+            ..InitOptions::default()
+        };
+        let git_dir = Repository::init(&dir, &options).unwrap().git_dir;
+        let mut config = fs::read_to_string(git_dir.join("config")).unwrap();
+        config.push_str("[user]\n\tname = Test\n\temail = test@example.com\n");
+        fs::write(git_dir.join("config"), config).unwrap();
+
+        let repo = Repository::open(&git_dir, Some(&dir)).unwrap();
+        (dir, repo)
+    }
+
+    fn commit(repo: &Repository, parents: &[ObjectId], message: &str) -> ObjectId {
+        let tree = repo.write_object(PackObjectType::Tree, b"").unwrap();
+        repo.commit_tree(&tree, parents, message.as_bytes())
+            .unwrap()
+    }
+
+    fn remote_ref(name: &str, digit: char) -> Ref {
+        Ref {
+            name: name.to_string(),
+            id: digit.to_string().repeat(40).parse().unwrap(),
+            peeled: None,
+            symref_target: None,
+        }
+    }
+
+    #[test]
+    fn test_clone_plan() {
+        let tag = Ref {
+            peeled: Some("1".repeat(40).parse().unwrap()),
+            ..remote_ref("refs/tags/v1", '3')
+        };
+        let mut remote_refs = vec![
+            Ref {
+                symref_target: Some("refs/heads/topic".to_string()),
+                ..remote_ref("HEAD", '2')
+            },
+            remote_ref("refs/heads/main", '2'),
+            remote_ref("refs/heads/topic", '2'),
+            remote_ref("refs/pull/1/head", '4'),
+            tag.clone(),
+        ];
+
+        // HEAD says which branch it is on, even when several are at its commit.
+        let plan = ClonePlan::new(&remote_refs);
+        assert_eq!(
+            ClonePlan {
+                wants: vec![remote_refs[1].id, tag.id],
+                packed: vec![
+                    remote_ref("refs/remotes/origin/main", '2'),
+                    remote_ref("refs/remotes/origin/topic", '2'),
+                    tag.clone(),
+                ],
+                head: Some(remote_refs[1].id),
+                branch: Some("topic".to_string()),
+                origin_head: Some("refs/remotes/origin/topic".to_string()),
+            },
+            plan
+        );
+
+        // Without a symref, the first branch at the commit of HEAD.
+        remote_refs[0].symref_target = None;
+        let plan = ClonePlan::new(&remote_refs);
+        assert_eq!(Some("main".to_string()), plan.branch);
+        assert_eq!(
+            Some("refs/remotes/origin/main".to_string()),
+            plan.origin_head
+        );
+
+        // A HEAD at no branch is detached.
+        remote_refs[0] = remote_ref("HEAD", '5');
+        let plan = ClonePlan::new(&remote_refs);
+        assert_eq!(None, plan.branch);
+        assert_eq!(None, plan.origin_head);
+        assert_eq!(Some(remote_refs[0].id), plan.head);
+        assert!(plan.wants.contains(&remote_refs[0].id));
+
+        // Nothing to check out without HEAD, nothing to fetch from an empty repository.
+        let plan = ClonePlan::new(&remote_refs[1..]);
+        assert_eq!((None, None), (plan.head, plan.branch));
+        assert!(ClonePlan::new(&[]).wants.is_empty());
+    }
+
+    #[test]
+    fn test_clone_into_non_empty_dir() {
+        let dir = TempDir::new("clone_dest");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), "").unwrap();
+
+        // Refused before reaching the remote.
+        let dir_name = dir.to_str().unwrap();
+        let err = Repository::clone("http://127.0.0.1:9/repo.git", dir_name)
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!(
+                "destination path '{}' already exists and is not an empty directory.",
+                dir_name
+            ),
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_checkout_rejects_unsafe_paths() {
+        let (dir, repo) = test_repo("checkout_unsafe");
+        let outside = TempDir::new("checkout_unsafe_outside");
+        fs::create_dir_all(&outside).unwrap();
+
+        let tree = |entries: &[(&str, &[u8], &ObjectId)]| {
+            let mut content = vec![];
+            for (mode, name, id) in entries {
+                content.extend_from_slice(format!("{} ", mode).as_bytes());
+                content.extend_from_slice(name);
+                content.push(0);
+                content.extend_from_slice(id.as_bytes());
+            }
+            repo.write_object(PackObjectType::Tree, &content).unwrap()
+        };
+        let blob = repo.write_object(PackObjectType::Blob, b"x").unwrap();
+        let file_tree = tree(&[("100644", b"file", &blob)]);
+        let link_target = repo
+            .write_object(PackObjectType::Blob, outside.to_str().unwrap().as_bytes())
+            .unwrap();
+
+        let roots = [
+            tree(&[("40000", b"..", &file_tree)]),
+            tree(&[("40000", b".git", &file_tree)]),
+            tree(&[("40000", b".GIT", &file_tree)]),
+            tree(&[("100644", b"", &blob)]),
+            tree(&[("100644", b"../file", &blob)]),
+            tree(&[
+                ("120000", b"link", &link_target),
+                ("40000", b"link", &file_tree),
+            ]),
+        ];
+        for root in roots {
+            let commit = repo.commit_tree(&root, &[], b"unsafe\n").unwrap();
+            assert!(repo.checkout(&commit).is_err());
+        }
+        assert!(!dir.join(".git/file").exists());
+        assert!(!dir.with_file_name("file").exists());
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_write_existing_object() {
+        let (_dir, repo) = test_repo("write_existing");
+        let id = repo.write_object(PackObjectType::Blob, b"hello\n").unwrap();
+        let hex = id.to_string();
+        let folder = repo.git_dir().join("objects").join(&hex[..2]);
+        let loose_path = folder.join(&hex[2..]);
+        let written = fs::read(&loose_path).unwrap();
+
+        // An object already there is left alone.
+        fs::write(&loose_path, b"kept").unwrap();
+        assert_eq!(
+            id,
+            repo.write_object(PackObjectType::Blob, b"hello\n").unwrap()
+        );
+        assert_eq!(b"kept".to_vec(), fs::read(&loose_path).unwrap());
+
+        fs::remove_file(&loose_path).unwrap();
+        repo.write_object(PackObjectType::Blob, b"hello\n").unwrap();
+        assert_eq!(written, fs::read(&loose_path).unwrap());
+        assert_eq!(1, fs::read_dir(&folder).unwrap().count());
+    }
+
+    #[test]
+    fn test_add_non_utf8_path() {
+        let (dir, repo) = test_repo("add_non_utf8");
+        fs::write(dir.join(OsStr::from_bytes(b"caf\xe9")), "").unwrap();
+
+        let err = repo.add(&[".".to_string()]).err().unwrap();
+        assert_eq!(
+            "cannot add 'caf\u{fffd}': path is not valid UTF-8",
+            err.to_string()
+        );
+        assert!(
+            Index::read(repo.git_dir(), repo.object_format())
+                .unwrap()
+                .entries
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_update_ref() {
+        let (_dir, repo) = test_repo("update_ref");
+        let first = commit(&repo, &[], "first\n");
+        let second = commit(&repo, &[first], "second\n");
+        let first_hex = first.to_string();
+        let second_hex = second.to_string();
+
+        // HEAD stands for the branch it points at, unless the update does not follow it.
+        let update = repo
+            .resolve_ref_update("HEAD", Some(&first_hex[..7]), Some(""), false)
+            .unwrap();
+        assert_eq!(
+            RefUpdate {
+                name: "refs/heads/main".to_string(),
+                new: Some(first),
+                old: Some(ObjectId::null(repo.object_format())),
+            },
+            update
+        );
+        assert_eq!(
+            "HEAD",
+            repo.resolve_ref_update("HEAD", Some(&first_hex), None, true)
+                .unwrap()
+                .name
+        );
+
+        repo.apply_ref_update(&update, "create").unwrap();
+        assert_eq!(
+            Some(first),
+            refs::resolve(repo.git_dir(), "refs/heads/main").unwrap()
+        );
+        // The ref exists now.
+        assert!(repo.apply_ref_update(&update, "create").is_err());
+
+        let update = repo
+            .resolve_ref_update(
+                "refs/heads/main",
+                Some(&second_hex),
+                Some(&second_hex),
+                false,
+            )
+            .unwrap();
+        assert!(repo.apply_ref_update(&update, "wrong old value").is_err());
+        let update = repo
+            .resolve_ref_update(
+                "refs/heads/main",
+                Some(&second_hex),
+                Some(&first_hex),
+                false,
+            )
+            .unwrap();
+        repo.apply_ref_update(&update, "advance").unwrap();
+
+        let delete = repo
+            .resolve_ref_update("refs/heads/main", None, Some(&second_hex), false)
+            .unwrap();
+        repo.apply_ref_update(&delete, "").unwrap();
+        assert_eq!(None, refs::resolve(repo.git_dir(), "main").unwrap());
+
+        assert!(
+            repo.resolve_ref_update("main", Some(&first_hex), None, false)
+                .is_err()
+        );
+        assert!(
+            repo.resolve_ref_update("refs/heads/x", Some("no-such-revision"), None, false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_show_refs() {
+        let (_dir, repo) = test_repo("show_refs");
+        let first = commit(&repo, &[], "first\n");
+        for name in [
+            "refs/heads/main",
+            "refs/heads/topic",
+            "refs/remotes/origin/main",
+        ] {
+            repo.update_ref(name, &first, None, "").unwrap();
+        }
+        let tag = repo
+            .create_tag("v1", Some(first), Some("Version 1"), false)
+            .unwrap();
+        repo.create_tag("light", Some(first), None, false).unwrap();
+
+        let names = |options: &ShowRefOptions| {
+            repo.show_refs(options)
+                .unwrap()
+                .into_iter()
+                .map(|found| found.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![
+                "refs/heads/main",
+                "refs/heads/topic",
+                "refs/remotes/origin/main",
+                "refs/tags/light",
+                "refs/tags/v1",
+            ],
+            names(&ShowRefOptions::default())
+        );
+        assert_eq!(
+            vec!["HEAD", "refs/heads/main", "refs/heads/topic"],
+            names(&ShowRefOptions {
+                head: true,
+                heads: true,
+                ..ShowRefOptions::default()
+            })
+        );
+        assert_eq!(
+            vec!["refs/heads/main", "refs/remotes/origin/main"],
+            names(&ShowRefOptions {
+                patterns: vec!["main".to_string()],
+                ..ShowRefOptions::default()
+            })
+        );
+        assert!(
+            names(&ShowRefOptions {
+                patterns: vec!["ain".to_string()],
+                ..ShowRefOptions::default()
+            })
+            .is_empty()
+        );
+
+        let v1 = repo.exact_ref("refs/tags/v1").unwrap().unwrap();
+        assert_eq!(tag, v1.id);
+        assert_eq!(Some(first), repo.peeled_tag(&v1).unwrap());
+        let light = repo.exact_ref("refs/tags/light").unwrap().unwrap();
+        assert_eq!(None, repo.peeled_tag(&light).unwrap());
+        assert_eq!(first, repo.exact_ref("HEAD").unwrap().unwrap().id);
+        assert_eq!(None, repo.exact_ref("main").unwrap());
+    }
+
+    #[test]
+    fn test_for_each_ref() {
+        let (_dir, repo) = test_repo("for_each_ref");
+        let first = commit(&repo, &[], "first\n");
+        let second = commit(&repo, &[first], "second, with a longer message\n");
+        repo.update_ref("refs/heads/main", &first, None, "")
+            .unwrap();
+        repo.update_ref("refs/heads/topic", &second, None, "")
+            .unwrap();
+        repo.create_tag("v1", Some(first), Some("Version 1"), false)
+            .unwrap();
+
+        let format =
+            refs::parse_format("%(HEAD) %(refname:short) %(objecttype) %(*objectname)").unwrap();
+        let lines = |patterns: &[&str], sort: &[&str]| {
+            let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            let sort = sort
+                .iter()
+                .map(|key| SortKey::parse(key).unwrap())
+                .collect::<Vec<_>>();
+            repo.for_each_ref(&patterns, &sort)
+                .unwrap()
+                .iter()
+                .map(|found| String::from_utf8(repo.format_ref(&format, found).unwrap()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                "* main commit ".to_string(),
+                "  topic commit ".to_string(),
+                format!("  v1 tag {}", first),
+            ],
+            lines(&[], &["refname"])
+        );
+        assert_eq!(
+            vec!["  topic commit ", "* main commit "],
+            lines(&["refs/heads"], &["-refname"])
+        );
+        // The last key is the primary one, sizes compare as numbers.
+        assert_eq!(
+            vec!["* main commit ", "  topic commit "],
+            lines(&["refs/heads/*"], &["-refname", "objectsize"])
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Revision expressions naming objects, see gitrevisions(7).
+
+use crate::{
+    common::Object,
+    date,
+    error::{Error, Result},
+    index::Index,
+    object_id::ObjectId,
+    pack::PackObjectType,
+    reflog, refs,
+    repository::Repository,
+};
+
+/// Short object ids need at least this many characters.
+const MIN_PREFIX_LEN: usize = 4;
+
+/// In `@{<n>}`, numbers from this one on are timestamps rather than reflog positions.
+const MIN_REFLOG_TIMESTAMP: u64 = 100000000;
+
+/// An operator applied after the base revision.
+#[derive(Debug, PartialEq, Eq)]
+enum Suffix {
+    /// `^N`, `^0` being the commit itself.
+    Parent(usize),
+    /// `~N`, the Nth first-parent ancestor.
+    Ancestor(usize),
+    /// `^{kind}`, or `^{}` for any object that is not a tag.
+    Peel(Option<PackObjectType>),
+    /// `^{object}`, the object itself once it is known to exist.
+    Exists,
+}
+
+pub(crate) fn rev_parse(repo: &Repository, spec: &str) -> Result<ObjectId> {
+    let unknown = || Error::UnknownRevision(spec.to_string());
+
+    // :<path> and :<stage>:<path> name blobs of the index.
+    if let Some(path) = spec.strip_prefix(':') {
+        return index_path(repo, path);
+    }
+
+    if let Some((rev, path)) = split_rev_path(spec) {
+        let tree = repo.peel(&rev_parse(repo, rev)?, PackObjectType::Tree)?;
+        return tree_path(repo, tree, rev, path);
+    }
+
+    let (base, suffixes) = split_suffixes(spec).ok_or_else(unknown)?;
+    let mut hash = resolve_base(repo, base)?.ok_or_else(unknown)?;
+
+    for suffix in suffixes {
+        hash = match suffix {
+            Suffix::Parent(0) => repo.peel(&hash, PackObjectType::Commit)?,
+            Suffix::Parent(n) => nth_parent(repo, &hash, n)?.ok_or_else(unknown)?,
+            Suffix::Ancestor(n) => {
+                for _ in 0..n {
+                    hash = nth_parent(repo, &hash, 1)?.ok_or_else(unknown)?;
+                }
+                hash
+            }
+            Suffix::Peel(Some(kind)) => repo.peel(&hash, kind)?,
+            Suffix::Peel(None) => repo.peel_tags(&hash)?,
+            Suffix::Exists => match repo.read_raw_object(&hash)? {
+                Some(_) => hash,
+                None => return Err(unknown()),
+            },
+        };
+    }
+
+    Ok(hash)
+}
+
+/// Splits `<rev>:<path>` at the first colon that is not part of an `@{...}`.
+fn split_rev_path(spec: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ':' if depth == 0 => return Some((&spec[..i], &spec[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits the base revision from its `^` and `~` suffixes, None if a suffix is malformed.
+fn split_suffixes(spec: &str) -> Option<(&str, Vec<Suffix>)> {
+    let mut depth = 0;
+    let base_len = spec
+        .char_indices()
+        .find(|&(_, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            depth == 0 && (c == '^' || c == '~')
+        })
+        .map_or(spec.len(), |(i, _)| i);
+
+    let mut suffixes = vec![];
+    let mut rest = &spec[base_len..];
+    while let Some(operator) = rest.chars().next() {
+        rest = &rest[operator.len_utf8()..];
+
+        if operator == '^'
+            && let Some(braced) = rest.strip_prefix('{')
+        {
+            let (kind, after) = braced.split_once('}')?;
+            match kind {
+                "" => suffixes.push(Suffix::Peel(None)),
+                "object" => suffixes.push(Suffix::Exists),
+                kind => suffixes.push(Suffix::Peel(Some(PackObjectType::from_name(kind)?))),
+            }
+            rest = after;
+            continue;
+        }
+
+        let digits_len = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let n = match digits_len {
+            0 => 1,
+            _ => rest[..digits_len].parse().ok()?,
+        };
+        rest = &rest[digits_len..];
+
+        suffixes.push(match operator {
+            '^' => Suffix::Parent(n),
+            '~' => Suffix::Ancestor(n),
+            _ => return None,
+        });
+    }
+
+    Some((&spec[..base_len], suffixes))
+}
+
+/// Resolves a revision without suffixes: an object id, a ref, `@`, `[<branch>]@{upstream}`, or
+/// `[<ref>]@{<n>}` and `[<ref>]@{<date>}` from the reflog.
+fn resolve_base(repo: &Repository, base: &str) -> Result<Option<ObjectId>> {
+    if base.is_empty() {
+        return Ok(None);
+    }
+    if base == "@" {
+        return refs::resolve(repo.git_dir(), "HEAD");
+    }
+
+    if let Some((name, braced)) = base.split_once("@{") {
+        let at = braced.strip_suffix('}').unwrap_or_default();
+        return match at.to_ascii_lowercase().as_str() {
+            "u" | "upstream" => upstream(repo, name).map(Some),
+            _ => reflog_entry(repo, name, at),
+        };
+    }
+
+    // In a sha256 repository 40 hex digits are only a prefix.
+    if base.len() == repo.object_format().hex_len()
+        && let Some(hash) = ObjectId::from_hex(base)
+    {
+        return Ok(Some(hash));
+    }
+
+    if let Some(hash) = refs::resolve(repo.git_dir(), base)? {
+        return Ok(Some(hash));
+    }
+
+    resolve_prefix(repo, base)
+}
+
+/// The object a short hex id stands for, None if it is not a short id of an existing object.
+fn resolve_prefix(repo: &Repository, prefix: &str) -> Result<Option<ObjectId>> {
+    if prefix.len() < MIN_PREFIX_LEN
+        || prefix.len() > repo.object_format().hex_len()
+        || !prefix.bytes().all(|c| c.is_ascii_hexdigit())
+    {
+        return Ok(None);
+    }
+
+    let mut ids = repo.odb().ids_with_prefix(&prefix.to_ascii_lowercase())?;
+    match ids.len() {
+        0 | 1 => Ok(ids.pop()),
+        _ => Err(Error::AmbiguousObject(prefix.to_string())),
+    }
+}
+
+/// The commit `branch@{upstream}` points at, the current branch if `branch` is empty.
+fn upstream(repo: &Repository, branch: &str) -> Result<ObjectId> {
+    let branch = match branch {
+        "" => refs::read_symbolic(repo.git_dir(), "HEAD")?
+            .and_then(|target| target.strip_prefix("refs/heads/").map(str::to_string))
+            .ok_or_else(|| Error::Other("HEAD does not point to a branch".to_string()))?,
+        branch => branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or(branch)
+            .to_string(),
+    };
+
+    let config = repo.config()?;
+    let no_upstream = || Error::Other(format!("no upstream configured for branch '{}'", branch));
+    let remote = config
+        .get("branch", Some(&branch), "remote")
+        .ok_or_else(no_upstream)?;
+    let merge = config
+        .get("branch", Some(&branch), "merge")
+        .ok_or_else(no_upstream)?;
+
+    // The remote "." is the repository itself, its branches are tracked directly.
+    let upstream_ref = match remote {
+        "." => merge.to_string(),
+        remote => format!(
+            "refs/remotes/{}/{}",
+            remote,
+            merge.strip_prefix("refs/heads/").unwrap_or(merge)
+        ),
+    };
+
+    refs::resolve(repo.git_dir(), &upstream_ref)?.ok_or_else(|| {
+        Error::Other(format!(
+            "upstream branch '{}' not stored as a remote-tracking branch",
+            merge
+        ))
+    })
+}
+
+/// The value `ref@{at}` names: the ref `at` updates ago, or at the date `at`. Without a ref, the
+/// reflog of the current branch is used.
+fn reflog_entry(repo: &Repository, name: &str, at: &str) -> Result<Option<ObjectId>> {
+    let full_name = match name {
+        "" => refs::resolve_symbolic(repo.git_dir(), "HEAD")?,
+        "@" => "HEAD".to_string(),
+        name => match refs::full_name(repo.git_dir(), name)? {
+            Some(full_name) => full_name,
+            None => return Ok(None),
+        },
+    };
+    let display_name = match name {
+        "" => refs::shorten(&full_name),
+        name => name,
+    };
+    let Some(entries) = reflog::read(repo.git_dir(), &full_name)? else {
+        return Ok(None);
+    };
+
+    let nth = at
+        .parse::<u64>()
+        .ok()
+        .filter(|_| at.bytes().all(|c| c.is_ascii_digit()));
+    if let Some(nth) = nth.filter(|&nth| nth < MIN_REFLOG_TIMESTAMP) {
+        // Entries count from the newest one, the oldest one also gives the value before it.
+        let nth = nth as usize;
+        return match entries.len().checked_sub(nth + 1) {
+            Some(i) => Ok(Some(entries[i].new)),
+            None if nth == entries.len() && !entries[0].old.is_null() => Ok(Some(entries[0].old)),
+            None => Err(Error::Other(format!(
+                "log for '{}' only has {} entries",
+                display_name,
+                entries.len()
+            ))),
+        };
+    }
+
+    let timestamp = match nth {
+        Some(timestamp) => timestamp as i64,
+        None => match date::parse(at) {
+            Some((timestamp, _)) => timestamp,
+            None => return Ok(None),
+        },
+    };
+    if let Some(entry) = entries
+        .iter()
+        .rev()
+        .find(|entry| entry.committer.timestamp <= timestamp)
+    {
+        return Ok(Some(entry.new));
+    }
+
+    let oldest = entries
+        .first()
+        .ok_or_else(|| Error::Other(format!("log for '{}' is empty", display_name)))?;
+    warn!(
+        "log for '{}' only goes back to {}",
+        display_name,
+        date::format_rfc2822(oldest.committer.timestamp, &oldest.committer.timezone)
+    );
+    match oldest.old.is_null() {
+        true => Ok(Some(oldest.new)),
+        false => Ok(Some(oldest.old)),
+    }
+}
+
+fn nth_parent(repo: &Repository, hash: &ObjectId, n: usize) -> Result<Option<ObjectId>> {
+    let commit = repo.peel(hash, PackObjectType::Commit)?;
+    match repo.read_object(&commit)? {
+        Object::Commit { commit } => Ok(commit.parents.get(n - 1).copied()),
+        _ => unreachable!("peeled to a commit"),
+    }
+}
+
+/// Paths in revisions are relative to the top of the work tree, unless they start with `./` or
+/// `../`.
+fn relative_to_top(repo: &Repository, path: &str) -> Result<String> {
+    match path.starts_with("./") || path.starts_with("../") {
+        true => repo.normalize_pathspec(path),
+        false => Ok(path.to_string()),
+    }
+}
+
+/// The object at `path` in a tree.
+fn tree_path(repo: &Repository, tree: ObjectId, rev: &str, path: &str) -> Result<ObjectId> {
+    let mut hash = tree;
+    let path = &relative_to_top(repo, path)?;
+    for component in path.split('/').filter(|component| !component.is_empty()) {
+        let entries = match repo.read_object(&hash)? {
+            Object::Tree { entries } => entries,
+            _ => vec![],
+        };
+        hash = entries
+            .into_iter()
+            .find(|entry| entry.filename == component.as_bytes())
+            .map(|entry| entry.hash)
+            .ok_or_else(|| Error::Other(format!("path '{}' does not exist in '{}'", path, rev)))?;
+    }
+    Ok(hash)
+}
+
+/// The blob staged at `path`, `path` may be prefixed with a stage number (`1:file`).
+fn index_path(repo: &Repository, path: &str) -> Result<ObjectId> {
+    let (stage, path) = match path.split_once(':') {
+        Some((stage @ ("0" | "1" | "2" | "3"), path)) => (stage.parse().unwrap(), path),
+        _ => (0, path),
+    };
+    let path = relative_to_top(repo, path)?;
+
+    Index::read(repo.git_dir(), repo.object_format())?
+        .entries
+        .into_iter()
+        .find(|entry| entry.path == path && entry.stage == stage)
+        .map(|entry| entry.hash)
+        .ok_or_else(|| {
+            Error::Other(format!(
+                "path '{}' is in the index, but not at stage {}",
+                path, stage
+            ))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        pack::PackObjectType,
+        revision::{Suffix, split_rev_path, split_suffixes},
+    };
+
+    #[test]
+    fn test_split_suffixes() {
+        assert_eq!(Some(("HEAD", vec![])), split_suffixes("HEAD"));
+        assert_eq!(
+            Some((
+                "main",
+                vec![
+                    Suffix::Parent(1),
+                    Suffix::Parent(2),
+                    Suffix::Ancestor(1),
+                    Suffix::Ancestor(12),
+                    Suffix::Parent(0),
+                ]
+            )),
+            split_suffixes("main^^2~~12^0")
+        );
+        assert_eq!(
+            Some((
+                "v1.0",
+                vec![
+                    Suffix::Peel(None),
+                    Suffix::Peel(Some(PackObjectType::Tree)),
+                    Suffix::Exists,
+                    Suffix::Parent(1),
+                ]
+            )),
+            split_suffixes("v1.0^{}^{tree}^{object}^")
+        );
+        assert_eq!(
+            Some(("main@{upstream}", vec![Suffix::Ancestor(2)])),
+            split_suffixes("main@{upstream}~2")
+        );
+        assert_eq!(None, split_suffixes("HEAD^{tre}"));
+        assert_eq!(None, split_suffixes("HEAD^{tree"));
+        assert_eq!(None, split_suffixes("HEAD~é"));
+        assert_eq!(None, split_suffixes("HEAD^2é"));
+    }
+
+    #[test]
+    fn test_split_rev_path() {
+        assert_eq!(Some(("HEAD", "d/e/z")), split_rev_path("HEAD:d/e/z"));
+        assert_eq!(Some(("HEAD^{tree}", "")), split_rev_path("HEAD^{tree}:"));
+        assert_eq!(
+            Some(("@{2020-01-01 10:00:00}", "a")),
+            split_rev_path("@{2020-01-01 10:00:00}:a")
+        );
+        assert_eq!(None, split_rev_path("HEAD~2"));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    commit::{ExtraHeaders, Identity, parse_headers, write_extra_headers},
+    object_id::ObjectId,
+    reader::Reader,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub object: ObjectId,
+    pub kind: String,
+    pub tag: String,
+    /// Very old tags were created without a tagger line.
+    pub tagger: Option<Identity>,
+    /// Headers after the tagger, like a `gpgsig` signature.
+    pub extra_headers: ExtraHeaders,
+    pub message: Vec<u8>,
+}
+
+impl Tag {
+    pub fn parse(content: &[u8]) -> Result<Self, String> {
+        // object <object_sha>
+        // type <object_type>
+        // tag <tag_name>
+        // tagger <name> <<email>> <timestamp> <timezone>
+        // <extra headers>
+        //
+        // <tag message>
+        let mut reader = Reader::new(content);
+
+        let mut object = None;
+        let mut kind = None;
+        let mut tag = None;
+        let mut tagger = None;
+
+        let extra_headers = parse_headers(&mut reader, |key, value, line| {
+            let text = || {
+                str::from_utf8(value.unwrap_or_default())
+                    .map_err(|_| format!("invalid {}", String::from_utf8_lossy(line)))
+            };
+            match key {
+                b"object" => {
+                    object = Some(
+                        ObjectId::from_hex(text()?)
+                            .ok_or_else(|| format!("invalid {}", String::from_utf8_lossy(line)))?,
+                    )
+                }
+                b"type" => kind = Some(text()?.to_string()),
+                b"tag" => tag = Some(text()?.to_string()),
+                b"tagger" => tagger = Some(Identity::parse(value.unwrap_or_default())?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+
+        Ok(Self {
+            object: object.ok_or_else(|| "tag is missing an object".to_string())?,
+            kind: kind.ok_or_else(|| "tag is missing a type".to_string())?,
+            tag: tag.ok_or_else(|| "tag is missing a name".to_string())?,
+            tagger,
+            extra_headers,
+            message: reader.pop_all().to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        bytes.extend_from_slice(b"object ");
+        bytes.extend_from_slice(self.object.to_string().as_bytes());
+        bytes.push(b'\n');
+
+        bytes.extend_from_slice(b"type ");
+        bytes.extend_from_slice(self.kind.as_bytes());
+        bytes.push(b'\n');
+
+        bytes.extend_from_slice(b"tag ");
+        bytes.extend_from_slice(self.tag.as_bytes());
+        bytes.push(b'\n');
+
+        if let Some(tagger) = &self.tagger {
+            bytes.extend_from_slice(b"tagger ");
+            bytes.extend_from_slice(&tagger.to_bytes());
+            bytes.push(b'\n');
+        }
+
+        write_extra_headers(&mut bytes, &self.extra_headers);
+
+        bytes.push(b'\n');
+        bytes.extend_from_slice(&self.message);
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tag::Tag;
+
+    #[test]
+    fn test_tag_round_trip() {
+        let raw = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+type commit\n\
+tag v1.0.0\n\
+tagger John Doe <john@example.com> 1234567890 +0000\n\
+\n\
+Release v1.0.0\n";
+
+        let tag = Tag::parse(raw).unwrap();
+        assert_eq!(
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+            tag.object.to_string()
+        );
+        assert_eq!("commit", tag.kind);
+        assert_eq!("v1.0.0", tag.tag);
+        assert_eq!(b"John Doe".to_vec(), tag.tagger.as_ref().unwrap().name);
+        assert_eq!(b"Release v1.0.0\n".to_vec(), tag.message);
+        assert_eq!(raw.to_vec(), tag.to_bytes());
+    }
+
+    #[test]
+    fn test_tag_round_trip_unknown_headers() {
+        let raw = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+type tree\n\
+tag signed\n\
+tagger J\xf6rg <j@example.com> 1234567890 +0100\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n\
+flag\n\
+\n\
+Signed\n";
+
+        let tag = Tag::parse(raw).unwrap();
+        assert_eq!(b"J\xf6rg".to_vec(), tag.tagger.as_ref().unwrap().name);
+        assert_eq!(
+            vec![
+                (
+                    b"gpgsig".to_vec(),
+                    Some(
+                        b"-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----"
+                            .to_vec()
+                    )
+                ),
+                (b"flag".to_vec(), None),
+            ],
+            tag.extra_headers
+        );
+        assert_eq!(raw.to_vec(), tag.to_bytes());
+    }
+}
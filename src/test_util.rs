@@ -0,0 +1,40 @@
+//! Fixtures shared by the unit tests, of the library and of the binary.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A path under the system temp directory, unique to `name` and the test process, removed with
+/// everything under it when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// The path does not exist yet: whatever a previous run left there is removed.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("toy_git_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,326 @@
+use std::io::{self, BufRead, Read};
+
+use crate::{
+    common::hex_len_prefixed_string,
+    error::{Error, Result},
+    object_id::{ObjectFormat, ObjectId},
+    refs::Ref,
+};
+
+/// A repository served over the smart HTTP protocol.
+pub struct Remote {
+    client: reqwest::blocking::Client,
+    url: String,
+}
+
+impl Remote {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::blocking::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Asks the remote for its refs, in the order it advertises them (HEAD first, if it has
+    /// one). HEAD comes with the branch it points at when the remote tells.
+    pub fn refs(&self) -> Result<Vec<Ref>> {
+        let refs_url = format!("{}{}", self.url, "/info/refs?service=git-upload-pack");
+        let response = self.client.get(refs_url).send()?.error_for_status()?;
+        parse_ref_advertisement(&response.bytes()?)
+    }
+
+    /// Requests a pack with the objects in `wants`, which must not be empty, and everything
+    /// reachable from them, and returns the pack data.
+    pub fn fetch_pack(
+        &self,
+        wants: &[ObjectId],
+    ) -> Result<SideBandReader<reqwest::blocking::Response>> {
+        // Sha1 is assumed unless another format is asked for.
+        let object_format = match wants[0].format() {
+            ObjectFormat::Sha1 => String::new(),
+            format => format!(" object-format={}", format.name()),
+        };
+        // Capabilities go on the first line only.
+        let mut want_payload = String::new();
+        for (i, want) in wants.iter().enumerate() {
+            let capabilities = match i {
+                0 => format!(
+                    " multi_ack_detailed thin-pack side-band-64k ofs-delta{}",
+                    object_format
+                ),
+                _ => String::new(),
+            };
+            want_payload.push_str(&hex_len_prefixed_string(&format!(
+                "want {}{}\n",
+                want, capabilities
+            )));
+        }
+        want_payload.push_str("00000009done\n");
+        let want_url = format!("{}{}", self.url, "/git-upload-pack");
+
+        let response = self
+            .client
+            .post(&want_url)
+            .header("Content-Type", "application/x-git-upload-pack-request")
+            .header("Accept", "application/x-git-upload-pack-result")
+            .body(want_payload)
+            .send()?
+            .error_for_status()?;
+
+        Ok(SideBandReader::new(response))
+    }
+}
+
+/// Parses the pkt-lines of a ref advertisement: `<id> <name>`, the capabilities following the
+/// first ref after a NUL, and annotated tags followed by `<id> <name>^{}` for what they point
+/// at. HEAD's branch comes from the `symref=HEAD:<ref>` capability.
+fn parse_ref_advertisement(body: &[u8]) -> Result<Vec<Ref>> {
+    let invalid = || Error::Protocol("invalid ref advertisement".to_string());
+
+    let mut refs: Vec<Ref> = vec![];
+    let mut head_target = None;
+    let mut rest = body;
+    while !rest.is_empty() {
+        let len = rest
+            .get(..4)
+            .and_then(|len| str::from_utf8(len).ok())
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .ok_or_else(invalid)?;
+        if len == 0 {
+            // Flush packet, after the service line and at the end.
+            rest = &rest[4..];
+            continue;
+        }
+        let line = rest.get(4..len).ok_or_else(invalid)?;
+        rest = &rest[len..];
+
+        let line = str::from_utf8(line).map_err(|_| invalid())?;
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        if line.starts_with("# service=") {
+            continue;
+        }
+
+        let (line, capabilities) = line.split_once('\0').unwrap_or((line, ""));
+        if let Some(target) = capabilities
+            .split(' ')
+            .find_map(|capability| capability.strip_prefix("symref=HEAD:"))
+        {
+            head_target = Some(target.to_string());
+        }
+
+        let (id, name) = line.split_once(' ').ok_or_else(invalid)?;
+        let id = ObjectId::from_hex(id).ok_or_else(invalid)?;
+        match name.strip_suffix("^{}") {
+            // An empty repository only advertises its capabilities.
+            Some("capabilities") => {}
+            Some(name) => {
+                let tag = refs
+                    .last_mut()
+                    .filter(|tag| tag.name == name)
+                    .ok_or_else(invalid)?;
+                tag.peeled = Some(id);
+            }
+            None => refs.push(Ref {
+                name: name.to_string(),
+                id,
+                peeled: None,
+                symref_target: None,
+            }),
+        }
+    }
+
+    if let Some(head) = refs.iter_mut().find(|head| head.name == "HEAD") {
+        head.symref_target = head_target;
+    }
+    Ok(refs)
+}
+
+/// Demultiplexes a `side-band-64k` upload-pack response: pack data (band 1) is handed out
+/// through `Read`/`BufRead`, progress messages (band 2) are logged.
+pub struct SideBandReader<R: Read> {
+    inner: R,
+    packet: Vec<u8>,
+    packet_ptr: usize,
+    done: bool,
+}
+
+impl<R: Read> SideBandReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            packet: Vec::new(),
+            packet_ptr: 0,
+            done: false,
+        }
+    }
+
+    /// Loads the next data packet, returns false at the flush packet or the end of the stream.
+    fn next_data_packet(&mut self) -> io::Result<bool> {
+        loop {
+            let mut len_bytes = [0u8; 4];
+            match self.inner.read_exact(&mut len_bytes) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err),
+            }
+
+            let len = str::from_utf8(&len_bytes)
+                .ok()
+                .and_then(|len_str| usize::from_str_radix(len_str, 16).ok())
+                .ok_or_else(|| {
+                    protocol_error(format!("invalid pkt-line length: {:?}", len_bytes))
+                })?;
+            if len == 0 {
+                return Ok(false);
+            }
+            if len <= 4 {
+                return Err(protocol_error(format!("invalid pkt-line length: {}", len)));
+            }
+
+            let mut line = vec![0u8; len - 4];
+            self.inner.read_exact(&mut line)?;
+
+            match line[0] {
+                1 => {
+                    // Data.
+                    line.remove(0);
+                    self.packet = line;
+                    self.packet_ptr = 0;
+                    return Ok(true);
+                }
+                2 => {
+                    // Progress messages.
+                    let progress_msg = String::from_utf8_lossy(&line[1..]);
+                    debug!("remote: {}", progress_msg.trim_end());
+                }
+                3 => {
+                    // Fatal error from the remote.
+                    let error_msg = String::from_utf8_lossy(&line[1..]);
+                    return Err(protocol_error(format!("remote: {}", error_msg.trim_end())));
+                }
+                other => {
+                    let msg = String::from_utf8_lossy(&line);
+                    warn!("Unknown line type {} -> message = {}", other, msg);
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a protocol error so it survives the `io::Read` interface, see `Error::from_stream`.
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::other(Error::Protocol(msg))
+}
+
+impl<R: Read> Read for SideBandReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for SideBandReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while !self.done && self.packet_ptr == self.packet.len() {
+            self.done = !self.next_data_packet()?;
+        }
+
+        Ok(&self.packet[self.packet_ptr..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.packet_ptr += amount;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use crate::{
+        common::hex_len_prefixed_string,
+        error::Error,
+        object_id::ObjectId,
+        transport::{SideBandReader, parse_ref_advertisement},
+    };
+
+    #[test]
+    fn test_parse_ref_advertisement() {
+        let mut body = hex_len_prefixed_string("# service=git-upload-pack\n");
+        body.push_str("0000");
+        for line in [
+            "8ab81adbcdc0ef3c1ad39a655b0e1c83f4b3dee3 HEAD\0multi_ack side-band-64k \
+             symref=HEAD:refs/heads/trunk object-format=sha1 agent=git/2.39.5\n",
+            "ebd6deafbda332b5153240cbe757c533bdaf96b3 refs/heads/feature\n",
+            "8ab81adbcdc0ef3c1ad39a655b0e1c83f4b3dee3 refs/heads/trunk\n",
+            "ebcf86b93dbf1113d5f272f1916d3e0bdc116360 refs/tags/v1\n",
+            "ebd6deafbda332b5153240cbe757c533bdaf96b3 refs/tags/v1^{}\n",
+        ] {
+            body.push_str(&hex_len_prefixed_string(line));
+        }
+        body.push_str("0000");
+
+        let refs = parse_ref_advertisement(body.as_bytes()).unwrap();
+        assert_eq!(
+            vec![
+                "HEAD",
+                "refs/heads/feature",
+                "refs/heads/trunk",
+                "refs/tags/v1"
+            ],
+            refs.iter().map(|r| r.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some("refs/heads/trunk"), refs[0].symref_target.as_deref());
+        assert_eq!(refs[2].id, refs[0].id);
+        assert_eq!(
+            ObjectId::from_hex("ebd6deafbda332b5153240cbe757c533bdaf96b3"),
+            refs[3].peeled
+        );
+        assert_eq!(None, refs[1].peeled);
+
+        // An empty repository.
+        let empty = hex_len_prefixed_string(
+            "0000000000000000000000000000000000000000 capabilities^{}\0side-band-64k\n",
+        ) + "0000";
+        assert!(
+            parse_ref_advertisement(empty.as_bytes())
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(parse_ref_advertisement(b"0010not a ref\n0000").is_err());
+        assert!(parse_ref_advertisement(b"00ff").is_err());
+    }
+
+    #[test]
+    fn test_side_band_reader() {
+        let mut response = hex_len_prefixed_string("NAK\n").into_bytes();
+        response.extend_from_slice(b"0009\x01PACK");
+        response.extend_from_slice(b"000a\x02hello");
+        response.extend_from_slice(b"0007\x01\x00\x01");
+        response.extend_from_slice(b"0000");
+
+        let mut data = vec![];
+        SideBandReader::new(&response[..])
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(b"PACK\x00\x01".to_vec(), data);
+    }
+
+    #[test]
+    fn test_side_band_reader_remote_error() {
+        let mut response = b"0009\x01PACK".to_vec();
+        response.extend_from_slice(b"000f\x03not found\n");
+
+        let err = SideBandReader::new(&response[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        match Error::from_stream(err, "reading pack") {
+            Error::Protocol(msg) => assert_eq!("remote: not found", msg),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+}